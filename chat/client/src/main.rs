@@ -1,60 +1,212 @@
-use std::io::{self, ErrorKind, Read, Write};
-use std::net::TcpStream;
-use std::sync::mpsc::{self, TryRecvError};
-use std::thread;
+use std::path::PathBuf;
 use std::time::Duration;
 
-const LOCAL: &str = "127.0.0.1:6000";
-const MSG_SIZE: usize = 32;
+use chat_core::files::Files;
+use chat_core::protocol::{ClientMessage, ServerMessage};
+use chat_core::{tls, Client};
+use clap::Parser;
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time::{self, Instant};
+use tokio_rustls::rustls::pki_types::ServerName;
 
-fn main() {
-    let mut client = TcpStream::connect(LOCAL).expect("Stream failed to connect");
-    client
-        .set_nonblocking(true)
-        .expect("failed to initiate non-blocking");
+/// The server is pinged this often, and given up on once nothing at all has
+/// come back from it for `SERVER_TIMEOUT`.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+const SERVER_TIMEOUT: Duration = Duration::from_secs(45);
 
-    let (tx, rx) = mpsc::channel::<String>();
+#[derive(Debug, Parser)]
+#[command(name = "client", about = "Chat client")]
+struct Cli {
+    /// Server to connect to, or unix:/path for a server's Unix socket
+    #[arg(long, env = "CHAT_HOST", default_value = "127.0.0.1")]
+    host: String,
 
-    thread::spawn(move || loop {
-        let mut buff = vec![0; MSG_SIZE];
+    /// Port the server listens on
+    #[arg(short, long, env = "CHAT_PORT", default_value_t = 6000)]
+    port: u16,
 
-        match client.read_exact(&mut buff) {
-            Ok(_) => {
-                let msg = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
-                println!("message recv {:?}", msg);
-            }
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-            Err(_) => {
-                println!("connection w/ server was severed");
-                break;
-            }
+    /// Nickname to use instead of asking for one
+    #[arg(short, long, env = "CHAT_NICK")]
+    nick: Option<String>,
+
+    /// Connect with TLS
+    #[arg(long)]
+    tls: bool,
+
+    /// PEM CA certificate to trust instead of the public roots, implies --tls
+    #[arg(long)]
+    ca: Option<PathBuf>,
+
+    /// Hex SHA-256 fingerprint the server certificate must match, implies --tls
+    #[arg(long)]
+    pin: Option<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    #[cfg(unix)]
+    if let Some(path) = cli.host.strip_prefix("unix:") {
+        if cli.tls || cli.ca.is_some() || cli.pin.is_some() {
+            eprintln!("TLS isn't used over a Unix socket");
+            std::process::exit(2);
         }
+        let client = UnixStream::connect(path)
+            .await
+            .expect("Stream failed to connect");
+        return run(client, cli.nick).await;
+    }
 
-        match rx.try_recv() {
-            Ok(msg) => {
-                let mut buff = msg.clone().into_bytes();
-                buff.resize(MSG_SIZE, 0);
-                client.write_all(&buff).expect("writing to socket failed");
-                println!("message sent {:?}", msg);
-            }
-            Err(TryRecvError::Empty) => (),
-            Err(TryRecvError::Disconnected) => break,
+    // IPv6 literals may come bracketed, as in URLs
+    let host = cli.host.trim_start_matches('[').trim_end_matches(']');
+    let client = TcpStream::connect((host, cli.port))
+        .await
+        .expect("Stream failed to connect");
+
+    if !cli.tls && cli.ca.is_none() && cli.pin.is_none() {
+        return run(client, cli.nick).await;
+    }
+
+    let connector =
+        tls::connector(cli.ca.as_deref(), cli.pin.as_deref()).expect("failed to set up TLS");
+    let server_name = ServerName::try_from(host.to_string()).expect("invalid server name");
+    let client = connector
+        .connect(server_name, client)
+        .await
+        .expect("TLS handshake failed");
+
+    run(client, cli.nick).await
+}
+
+async fn run<S: AsyncRead + AsyncWrite>(client: S, nick: Option<String>) {
+    let mut client = Client::new(client);
+
+    let mut stdin = BufReader::new(io::stdin()).lines();
+    let mut files = Files::default();
+
+    let nick = match nick {
+        Some(nick) => Some(nick),
+        None => {
+            println!("Choose a nickname:");
+            stdin.next_line().await.expect("reading from stdin failed")
         }
+    };
 
-        thread::sleep(Duration::from_millis(100));
-    });
+    if let Some(nick) = nick {
+        println!("Password (leave empty to join as a guest):");
+        let password = stdin
+            .next_line()
+            .await
+            .expect("reading from stdin failed")
+            .unwrap_or_default();
+
+        let handshake = match password.trim() {
+            "" => format!("/nick {}", nick.trim()),
+            password => format!("/login {} {}", nick.trim(), password),
+        };
+        client
+            .send(&ClientMessage::from_line(&handshake))
+            .await
+            .expect("nickname too long");
+    }
+
+    let mut pings = time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    let mut last_heard = Instant::now();
 
     println!("Write a message:");
     loop {
-        let mut buff = String::new();
-        io::stdin()
-            .read_line(&mut buff)
-            .expect("reading from stdin failed");
-        let msg = buff.trim().to_string();
-        if msg == ":quit" || tx.send(msg).is_err() {
-            break;
+        tokio::select! {
+            msg = client.recv() => {
+                let msg = match msg {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) | Err(_) => {
+                        println!("connection w/ server was severed");
+                        break;
+                    }
+                };
+                last_heard = Instant::now();
+
+                match msg {
+                    Ok(ServerMessage::Ping) => {
+                        let _ = client.send(&ClientMessage::Pong).await;
+                    }
+                    Ok(ServerMessage::Pong) => (),
+                    Ok(msg) => {
+                        // chunks and acks are reported as percentages
+                        if !matches!(
+                            msg,
+                            ServerMessage::Chunk { .. } | ServerMessage::Progress { .. }
+                        ) {
+                            println!("{}", msg);
+                        }
+                        for reply in files.handle(&msg) {
+                            let _ = client.send(&reply).await;
+                        }
+                    }
+                    Err(err) => println!("dropped message: {}", err),
+                }
+            }
+            _ = pings.tick() => {
+                if last_heard.elapsed() >= SERVER_TIMEOUT {
+                    println!("server stopped responding");
+                    break;
+                }
+                let _ = client.send(&ClientMessage::Ping).await;
+            }
+            line = stdin.next_line() => {
+                let msg = match line.expect("reading from stdin failed") {
+                    Some(line) => line.trim().to_string(),
+                    None => break,
+                };
+                if msg == ":quit" {
+                    break;
+                }
+
+                let msg = match file_command(&mut files, &msg) {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(err)) => {
+                        println!("! {}", err);
+                        continue;
+                    }
+                    None => ClientMessage::from_line(&msg),
+                };
+                if let Err(err) = client.send(&msg).await {
+                    println!("message not sent: {}", err);
+                }
+            }
         }
     }
 
     println!("goodbye");
 }
+
+/// The commands for file transfers, which the client handles itself rather
+/// than passing on to the server as they are.
+fn file_command(files: &mut Files, line: &str) -> Option<Result<ClientMessage, String>> {
+    let (name, args) = line
+        .strip_prefix('/')?
+        .split_once(' ')
+        .unwrap_or((&line[1..], ""));
+    let args = args.split_whitespace().collect::<Vec<_>>();
+    let id = || match args[..] {
+        [id] => id
+            .parse::<u64>()
+            .map_err(|_| format!("usage: /{} <transfer>", name)),
+        _ => Err(format!("usage: /{} <transfer>", name)),
+    };
+
+    Some(match name {
+        "send" => match args[..] {
+            [to, path] => files.send(to, path),
+            _ => Err(String::from("usage: /send <nick|#room> <path>")),
+        },
+        "accept" => id().and_then(|id| files.accept(id)),
+        "decline" => id().and_then(|id| files.decline(id)),
+        "cancel" => id().and_then(|id| files.cancel(id)),
+        _ => return None,
+    })
+}
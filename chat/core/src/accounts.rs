@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand_core::OsRng;
+use serde::Deserialize;
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Whether clients that haven't logged in to a registered account may talk.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GuestPolicy {
+    Allow,
+    Deny,
+}
+
+impl GuestPolicy {
+    pub fn parse(s: &str) -> Option<GuestPolicy> {
+        match s {
+            "allow" => Some(GuestPolicy::Allow),
+            "deny" => Some(GuestPolicy::Deny),
+            _ => None,
+        }
+    }
+}
+
+/// Registered nicknames and their argon2 password hashes, one `nick:hash` per
+/// line. The whole file is rewritten whenever an account changes.
+pub struct Accounts {
+    path: PathBuf,
+    // keyed by lowercased nickname, like the hub's nickname registry
+    hashes: BTreeMap<String, String>,
+}
+
+impl Accounts {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Accounts> {
+        let path = path.into();
+
+        let hashes = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(nick, hash)| (nick.to_string(), hash.to_string()))
+                .collect(),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(Accounts { path, hashes })
+    }
+
+    pub fn hash(&self, nick: &str) -> Option<&str> {
+        self.hashes.get(&nick.to_lowercase()).map(String::as_str)
+    }
+
+    pub fn is_registered(&self, nick: &str) -> bool {
+        self.hash(nick).is_some()
+    }
+
+    pub fn set(&mut self, nick: &str, hash: String) -> io::Result<()> {
+        self.hashes.insert(nick.to_lowercase(), hash);
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let contents = self
+            .hashes
+            .iter()
+            .map(|(nick, hash)| format!("{}:{}\n", nick, hash))
+            .collect::<String>();
+
+        // write then rename so a crash never leaves a half written file
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+/// Hashing is deliberately slow, so callers should keep it off the hub task.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(format!(
+            "passwords must be at least {} characters",
+            MIN_PASSWORD_LEN
+        ));
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| format!("failed to hash password: {}", err))
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::temp_dir;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash_password("correct horse").unwrap();
+
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+        assert!(hash_password("short").is_err());
+    }
+
+    #[test]
+    fn test_accounts_persist() {
+        let dir = temp_dir("accounts");
+        let path = dir.join("accounts");
+
+        let mut accounts = Accounts::open(&path).unwrap();
+        assert!(!accounts.is_registered("alice"));
+        accounts.set("Alice", String::from("$hash")).unwrap();
+
+        let accounts = Accounts::open(&path).unwrap();
+        assert_eq!(accounts.hash("ALICE"), Some("$hash"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
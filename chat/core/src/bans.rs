@@ -0,0 +1,317 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::commands::validate_nick;
+
+/// A single address, or a whole network in CIDR notation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Net {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Net {
+    /// Host bits are cleared, so `10.1.2.3/8` is the same as `10.0.0.0/8`.
+    pub fn parse(s: &str) -> Option<Net> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().ok()?)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().ok()?.to_canonical();
+
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return None;
+        }
+
+        let addr = match addr {
+            IpAddr::V4(ip) => IpAddr::from(Ipv4Addr::from(u32::from(ip) & mask(prefix, 32) as u32)),
+            IpAddr::V6(ip) => IpAddr::from(Ipv6Addr::from(u128::from(ip) & mask(prefix, 128))),
+        };
+        Some(Net { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener show up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(ip) & mask(self.prefix, 32) as u32 == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask(self.prefix, 128) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The top `prefix` bits of a `bits` wide address.
+fn mask(prefix: u8, bits: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        _ => (u128::MAX << (bits - prefix)) & (u128::MAX >> (128 - bits as u32)),
+    }
+}
+
+impl fmt::Display for Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.addr, self.prefix) {
+            (IpAddr::V4(_), 32) | (IpAddr::V6(_), 128) => write!(f, "{}", self.addr),
+            _ => write!(f, "{}/{}", self.addr, self.prefix),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Target {
+    /// Lowercased, since nicknames are case-insensitive.
+    Nick(String),
+    Net(Net),
+}
+
+impl Target {
+    pub fn parse(s: &str) -> Option<Target> {
+        match Net::parse(s) {
+            Some(net) => Some(Target::Net(net)),
+            None => validate_nick(s)
+                .ok()
+                .map(|_| Target::Nick(s.to_lowercase())),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Nick(nick) => write!(f, "{}", nick),
+            Target::Net(net) => write!(f, "{}", net),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Ban {
+    pub target: Target,
+    /// Seconds since the Unix epoch, `None` for a ban that never runs out.
+    pub expires: Option<u64>,
+    /// The operator who set it.
+    pub by: String,
+    pub reason: Option<String>,
+}
+
+impl Ban {
+    /// What the banned client is told.
+    pub fn message(&self) -> String {
+        match &self.reason {
+            Some(reason) => format!("you are banned: {}", reason),
+            None => String::from("you are banned"),
+        }
+    }
+
+    fn active(&self, now: u64) -> bool {
+        self.expires.map(|expires| expires > now).unwrap_or(true)
+    }
+
+    fn parse(line: &str) -> Option<Ban> {
+        let mut fields = line.splitn(4, ' ');
+        let target = Target::parse(fields.next()?)?;
+        let expires = match fields.next()? {
+            "-" => None,
+            expires => Some(expires.parse().ok()?),
+        };
+        let by = fields.next()?.to_string();
+        let reason = fields.next().map(String::from);
+
+        Some(Ban {
+            target,
+            expires,
+            by,
+            reason,
+        })
+    }
+}
+
+impl fmt::Display for Ban {
+    /// `target expires by [reason]`, the format of a line in the bans file.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.target)?;
+        match self.expires {
+            Some(expires) => write!(f, "{} ", expires)?,
+            None => write!(f, "- ")?,
+        }
+        write!(f, "{}", self.by)?;
+        if let Some(reason) = &self.reason {
+            write!(f, " {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Banned nicknames and networks, one ban per line. Like accounts, the whole
+/// file is rewritten on every change, which also drops expired bans.
+pub struct Bans {
+    path: PathBuf,
+    bans: Vec<Ban>,
+}
+
+impl Bans {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Bans> {
+        let path = path.into();
+
+        let bans = match fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().filter_map(Ban::parse).collect(),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+
+        Ok(Bans { path, bans })
+    }
+
+    /// The ban covering a connection from `ip`, if any.
+    pub fn address(&self, ip: IpAddr, now: u64) -> Option<&Ban> {
+        self.active(now).find(|ban| match &ban.target {
+            Target::Net(net) => net.contains(ip),
+            Target::Nick(_) => false,
+        })
+    }
+
+    /// The ban on `nick`, if any.
+    pub fn nick(&self, nick: &str, now: u64) -> Option<&Ban> {
+        let nick = nick.to_lowercase();
+        self.active(now)
+            .find(|ban| ban.target == Target::Nick(nick.clone()))
+    }
+
+    pub fn active(&self, now: u64) -> impl Iterator<Item = &Ban> {
+        self.bans.iter().filter(move |ban| ban.active(now))
+    }
+
+    /// Replaces any ban already on the same target.
+    pub fn add(&mut self, ban: Ban) -> io::Result<()> {
+        self.bans.retain(|other| other.target != ban.target);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Returns false if the target wasn't banned.
+    pub fn remove(&mut self, target: &Target) -> io::Result<bool> {
+        let before = self.bans.len();
+        self.bans.retain(|ban| &ban.target != target);
+        if self.bans.len() == before {
+            return Ok(false);
+        }
+
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let now = unix_now();
+        self.bans.retain(|ban| ban.active(now));
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let contents = self
+            .bans
+            .iter()
+            .map(|ban| format!("{}\n", ban))
+            .collect::<String>();
+
+        // write then rename so a crash never leaves a half written file
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::temp_dir;
+
+    #[test]
+    fn test_net() {
+        let net = Net::parse("10.1.2.3/8").unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(net.contains("10.200.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+
+        let host = Net::parse("2001:db8::1").unwrap();
+        assert_eq!(host.to_string(), "2001:db8::1");
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+        assert!(Net::parse("2001:db8::/32")
+            .unwrap()
+            .contains("2001:db8:ffff::1".parse().unwrap()));
+
+        assert!(Net::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("192.168.1.1".parse().unwrap()));
+        assert!(Net::parse("10.0.0.0/33").is_none());
+        assert!(Net::parse("alice").is_none());
+    }
+
+    #[test]
+    fn test_bans_persist() {
+        let dir = temp_dir("bans");
+        let path = dir.join("bans");
+        let now = unix_now();
+
+        let mut bans = Bans::open(&path).unwrap();
+        bans.add(Ban {
+            target: Target::parse("Mallory").unwrap(),
+            expires: None,
+            by: String::from("alice"),
+            reason: Some(String::from("spam, lots of it")),
+        })
+        .unwrap();
+        bans.add(Ban {
+            target: Target::parse("192.0.2.0/24").unwrap(),
+            expires: Some(now + 60),
+            by: String::from("alice"),
+            reason: None,
+        })
+        .unwrap();
+        bans.add(Ban {
+            target: Target::parse("198.51.100.7").unwrap(),
+            expires: Some(now - 1),
+            by: String::from("alice"),
+            reason: None,
+        })
+        .unwrap();
+
+        let mut bans = Bans::open(&path).unwrap();
+        assert_eq!(bans.active(now).count(), 2);
+        let ban = bans.nick("MALLORY", now).unwrap();
+        assert_eq!(ban.reason.as_deref(), Some("spam, lots of it"));
+        assert!(bans.address("192.0.2.99".parse().unwrap(), now).is_some());
+        assert!(bans
+            .address("192.0.2.99".parse().unwrap(), now + 60)
+            .is_none());
+        assert!(bans.address("198.51.100.7".parse().unwrap(), now).is_none());
+
+        assert!(bans.remove(&Target::parse("mallory").unwrap()).unwrap());
+        assert!(!bans.remove(&Target::parse("mallory").unwrap()).unwrap());
+        assert!(Bans::open(&path).unwrap().nick("mallory", now).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use protocol::{
+    decode_chunk, decode_message, encode_chunk, encode_frame, encode_message, ClientMessage,
+    FrameDecoder, ServerMessage, DEFAULT_MAX_FRAME_SIZE,
+};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// One connection to a chat server, over a plain socket or anything else
+/// that carries its frames, such as a TLS stream.
+pub struct Client<S> {
+    reader: ReadHalf<S>,
+    writer: WriteHalf<S>,
+    decoder: FrameDecoder,
+    buff: Vec<u8>,
+}
+
+impl Client<TcpStream> {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Client<TcpStream>> {
+        Ok(Client::new(TcpStream::connect(addr).await?))
+    }
+}
+
+#[cfg(unix)]
+impl Client<UnixStream> {
+    /// Connects to a server's `unix-socket`.
+    pub async fn connect_unix(path: impl AsRef<std::path::Path>) -> io::Result<Client<UnixStream>> {
+        Ok(Client::new(UnixStream::connect(path).await?))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Client<S> {
+    pub fn new(stream: S) -> Client<S> {
+        let (reader, writer) = io::split(stream);
+
+        Client {
+            reader,
+            writer,
+            decoder: FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE),
+            buff: vec![0; 4096],
+        }
+    }
+
+    /// The next message from the server, or `None` once it has closed the
+    /// connection. A frame that doesn't decode is an error of its own and
+    /// leaves the connection usable. Cancel safe.
+    ///
+    /// Pings are handed back like everything else; answering them is up to
+    /// the caller.
+    pub async fn recv(&mut self) -> io::Result<Option<Result<ServerMessage, String>>> {
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(frame)) => {
+                    let msg = match decode_chunk(&frame) {
+                        Some((id, data)) => Ok(ServerMessage::Chunk {
+                            id,
+                            data: data.to_vec(),
+                        }),
+                        None => decode_message(&frame).map_err(|err| err.to_string()),
+                    };
+                    return Ok(Some(msg));
+                }
+                Ok(None) => (),
+                Err(err) => return Ok(Some(Err(err.to_string()))),
+            }
+
+            let n = self.reader.read(&mut self.buff).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.decoder.extend(&self.buff[..n]);
+        }
+    }
+
+    /// Frames and writes one message. File chunks go out as chunk frames.
+    pub async fn send(&mut self, msg: &ClientMessage) -> io::Result<()> {
+        let payload = match msg {
+            ClientMessage::Chunk { id, data } => encode_chunk(*id, data),
+            msg => encode_message(msg),
+        };
+        let buff = encode_frame(&payload, DEFAULT_MAX_FRAME_SIZE)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+
+        self.writer.write_all(&buff).await
+    }
+}
@@ -0,0 +1,452 @@
+use std::time::Duration;
+
+/// A command sent as `ClientMessage::Command`, typed by users as `/name args`.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Command {
+    Nick(String),
+    Join(String),
+    Part(Option<String>),
+    List,
+    Who(Option<String>),
+    Names(Option<String>),
+    /// Shows the topic without `text`, sets it with.
+    Topic {
+        room: Option<String>,
+        text: Option<String>,
+    },
+    Msg {
+        to: String,
+        text: String,
+    },
+    History(Option<String>),
+    /// In the active room.
+    Edit {
+        message: MessageRef,
+        text: String,
+    },
+    Delete(MessageRef),
+    Login {
+        nick: String,
+        password: String,
+    },
+    Register(String),
+    Passwd {
+        old: String,
+        new: String,
+    },
+    // room operators, in the active room unless one is given
+    Kick {
+        nick: String,
+        room: Option<String>,
+        reason: Option<String>,
+    },
+    /// Until unmuted without a duration.
+    Mute {
+        nick: String,
+        room: Option<String>,
+        duration: Option<Duration>,
+    },
+    Unmute {
+        nick: String,
+        room: Option<String>,
+    },
+    Op {
+        nick: String,
+        room: Option<String>,
+    },
+    Deop {
+        nick: String,
+        room: Option<String>,
+    },
+    // server operators
+    /// `target` is a nickname, an address or a network. Forever without a
+    /// duration.
+    Ban {
+        target: String,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    },
+    Unban(String),
+    Bans,
+}
+
+/// One of the user's own messages, by its ID or as the last one they sent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageRef {
+    Id(u64),
+    Last,
+}
+
+impl MessageRef {
+    fn parse(s: &str) -> Option<MessageRef> {
+        match s {
+            "last" => Some(MessageRef::Last),
+            _ => s.parse().ok().map(MessageRef::Id),
+        }
+    }
+}
+
+impl Command {
+    /// Returns the parsed command or a message explaining why it could not be
+    /// parsed.
+    pub fn parse(name: &str, args: &str) -> Result<Command, String> {
+        let args = args.trim();
+        let words = args.split_whitespace().collect::<Vec<_>>();
+
+        match name {
+            "nick" => match words[..] {
+                [nick] => Ok(Command::Nick(nick.to_string())),
+                _ => Err(String::from("usage: /nick <name>")),
+            },
+            "join" => match words[..] {
+                [room] => Ok(Command::Join(room.to_string())),
+                _ => Err(String::from("usage: /join <#room>")),
+            },
+            "part" => match words[..] {
+                [] => Ok(Command::Part(None)),
+                [room] => Ok(Command::Part(Some(room.to_string()))),
+                _ => Err(String::from("usage: /part [#room]")),
+            },
+            "list" => match words[..] {
+                [] => Ok(Command::List),
+                _ => Err(String::from("usage: /list")),
+            },
+            "who" => match words[..] {
+                [] => Ok(Command::Who(None)),
+                [room] => Ok(Command::Who(Some(room.to_string()))),
+                _ => Err(String::from("usage: /who [#room]")),
+            },
+            "names" => match words[..] {
+                [] => Ok(Command::Names(None)),
+                [room] => Ok(Command::Names(Some(room.to_string()))),
+                _ => Err(String::from("usage: /names [#room]")),
+            },
+            "topic" => {
+                let (room, text) = match args.split_once(char::is_whitespace) {
+                    Some((room, text)) if room.starts_with('#') => (Some(room), text.trim()),
+                    None if args.starts_with('#') => (Some(args), ""),
+                    _ => (None, args),
+                };
+                Ok(Command::Topic {
+                    room: room.map(String::from),
+                    text: Some(text).filter(|text| !text.is_empty()).map(String::from),
+                })
+            }
+            "msg" => match args.split_once(char::is_whitespace) {
+                Some((to, text)) => Ok(Command::Msg {
+                    to: to.to_string(),
+                    text: text.trim().to_string(),
+                }),
+                None => Err(String::from("usage: /msg <nick> <text>")),
+            },
+            "history" => match words[..] {
+                [] => Ok(Command::History(None)),
+                [room] => Ok(Command::History(Some(room.to_string()))),
+                _ => Err(String::from("usage: /history [#room]")),
+            },
+            "edit" => {
+                let edit = args
+                    .split_once(char::is_whitespace)
+                    .and_then(|(message, text)| Some((MessageRef::parse(message)?, text)));
+                match edit {
+                    Some((message, text)) => Ok(Command::Edit {
+                        message,
+                        text: text.trim().to_string(),
+                    }),
+                    None => Err(String::from("usage: /edit <id|last> <text>")),
+                }
+            }
+            "delete" => match words[..] {
+                [message] => MessageRef::parse(message)
+                    .map(Command::Delete)
+                    .ok_or_else(|| String::from("usage: /delete <id|last>")),
+                _ => Err(String::from("usage: /delete <id|last>")),
+            },
+            "login" => match words[..] {
+                [nick, password] => Ok(Command::Login {
+                    nick: nick.to_string(),
+                    password: password.to_string(),
+                }),
+                _ => Err(String::from("usage: /login <nick> <password>")),
+            },
+            "register" => match words[..] {
+                [password] => Ok(Command::Register(password.to_string())),
+                _ => Err(String::from("usage: /register <password>")),
+            },
+            "passwd" => match words[..] {
+                [old, new] => Ok(Command::Passwd {
+                    old: old.to_string(),
+                    new: new.to_string(),
+                }),
+                _ => Err(String::from("usage: /passwd <old> <new>")),
+            },
+            "kick" => match words[..] {
+                [nick, ref rest @ ..] => {
+                    let (room, rest) = room_arg(rest);
+                    Ok(Command::Kick {
+                        nick: nick.to_string(),
+                        room,
+                        reason: Some(rest.join(" ")).filter(|reason| !reason.is_empty()),
+                    })
+                }
+                _ => Err(String::from("usage: /kick <nick> [#room] [reason]")),
+            },
+            "mute" => {
+                let usage = || String::from("usage: /mute <nick> [#room] [duration]");
+                match words[..] {
+                    [nick, ref rest @ ..] => {
+                        let (room, rest) = room_arg(rest);
+                        let duration = match rest {
+                            [] => None,
+                            [duration] if parse_any_duration(duration).is_some() => {
+                                Some(parse_duration(duration).ok_or_else(too_long)?)
+                            }
+                            _ => return Err(usage()),
+                        };
+                        Ok(Command::Mute {
+                            nick: nick.to_string(),
+                            room,
+                            duration,
+                        })
+                    }
+                    _ => Err(usage()),
+                }
+            }
+            "unmute" | "op" | "deop" => {
+                let (nick, room) = match words[..] {
+                    [nick] => (nick.to_string(), None),
+                    [nick, room] if room.starts_with('#') => {
+                        (nick.to_string(), Some(room.to_string()))
+                    }
+                    _ => return Err(format!("usage: /{} <nick> [#room]", name)),
+                };
+                Ok(match name {
+                    "unmute" => Command::Unmute { nick, room },
+                    "op" => Command::Op { nick, room },
+                    _ => Command::Deop { nick, room },
+                })
+            }
+            "ban" => match words[..] {
+                [target, ref rest @ ..] => {
+                    let (duration, rest) = match rest.split_first() {
+                        Some((first, rest)) if parse_any_duration(first).is_some() => {
+                            let duration = parse_duration(first).ok_or_else(too_long)?;
+                            (Some(duration), rest)
+                        }
+                        _ => (None, rest),
+                    };
+                    Ok(Command::Ban {
+                        target: target.to_string(),
+                        duration,
+                        reason: Some(rest.join(" ")).filter(|reason| !reason.is_empty()),
+                    })
+                }
+                _ => Err(String::from(
+                    "usage: /ban <nick|address|network> [duration] [reason]",
+                )),
+            },
+            "unban" => match words[..] {
+                [target] => Ok(Command::Unban(target.to_string())),
+                _ => Err(String::from("usage: /unban <nick|address|network>")),
+            },
+            "bans" => match words[..] {
+                [] => Ok(Command::Bans),
+                _ => Err(String::from("usage: /bans")),
+            },
+            _ => Err(format!("unknown command /{}", name)),
+        }
+    }
+
+    /// The arguments as it is safe to log them: those of commands carrying
+    /// passwords are left out.
+    pub fn redact<'a>(name: &str, args: &'a str) -> &'a str {
+        match name {
+            "login" | "register" | "passwd" => "",
+            _ => args,
+        }
+    }
+}
+
+fn too_long() -> String {
+    String::from("durations can be at most 365d, leave it out for forever")
+}
+
+/// Splits off a leading `#room`, if there is one.
+fn room_arg<'a>(words: &'a [&'a str]) -> (Option<String>, &'a [&'a str]) {
+    match words.split_first() {
+        Some((room, rest)) if room.starts_with('#') => (Some(room.to_string()), rest),
+        _ => (None, words),
+    }
+}
+
+/// The longest a mute or ban can be given for, short of forever.
+pub const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// `30s`, `10m`, `2h` or `7d`, up to `MAX_DURATION`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    parse_any_duration(s).filter(|&duration| duration <= MAX_DURATION)
+}
+
+fn parse_any_duration(s: &str) -> Option<Duration> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let count = s[..s.len() - 1].parse::<u64>().ok().filter(|&n| n > 0)?;
+
+    Some(Duration::from_secs(count.checked_mul(unit)?))
+}
+
+pub const MAX_NICK_LEN: usize = 16;
+
+pub fn validate_nick(nick: &str) -> Result<(), String> {
+    if nick.is_empty() || nick.chars().count() > MAX_NICK_LEN {
+        return Err(format!(
+            "nicknames must be between 1 and {} characters",
+            MAX_NICK_LEN
+        ));
+    }
+
+    if !nick
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(String::from(
+            "nicknames may only contain letters, digits, '_' and '-'",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Command::parse("nick", " alice "),
+            Ok(Command::Nick(String::from("alice")))
+        );
+        assert!(Command::parse("nick", "").is_err());
+        assert!(Command::parse("nick", "a b").is_err());
+        assert_eq!(
+            Command::parse("join", "#rust"),
+            Ok(Command::Join(String::from("#rust")))
+        );
+        assert_eq!(Command::parse("part", ""), Ok(Command::Part(None)));
+        assert_eq!(Command::parse("list", ""), Ok(Command::List));
+        assert_eq!(Command::parse("who", ""), Ok(Command::Who(None)));
+        assert_eq!(
+            Command::parse("msg", "bob  hi  there"),
+            Ok(Command::Msg {
+                to: String::from("bob"),
+                text: String::from("hi  there")
+            })
+        );
+        assert!(Command::parse("msg", "bob").is_err());
+        assert_eq!(
+            Command::parse("names", "#rust"),
+            Ok(Command::Names(Some(String::from("#rust"))))
+        );
+        assert_eq!(
+            Command::parse("topic", "#rust"),
+            Ok(Command::Topic {
+                room: Some(String::from("#rust")),
+                text: None
+            })
+        );
+        assert_eq!(
+            Command::parse("topic", "#rust  all things #rust "),
+            Ok(Command::Topic {
+                room: Some(String::from("#rust")),
+                text: Some(String::from("all things #rust"))
+            })
+        );
+        assert_eq!(
+            Command::parse("topic", "lunch at noon"),
+            Ok(Command::Topic {
+                room: None,
+                text: Some(String::from("lunch at noon"))
+            })
+        );
+        assert_eq!(
+            Command::parse("edit", "90061000000  hello  there"),
+            Ok(Command::Edit {
+                message: MessageRef::Id(90061000000),
+                text: String::from("hello  there")
+            })
+        );
+        assert_eq!(
+            Command::parse("delete", "last"),
+            Ok(Command::Delete(MessageRef::Last))
+        );
+        assert!(Command::parse("edit", "last").is_err());
+        assert!(Command::parse("edit", "first hello").is_err());
+        assert!(Command::parse("delete", "").is_err());
+        assert_eq!(
+            Command::parse("login", "alice hunter22"),
+            Ok(Command::Login {
+                nick: String::from("alice"),
+                password: String::from("hunter22")
+            })
+        );
+        assert_eq!(
+            Command::parse("kick", "bob #rust off topic, again"),
+            Ok(Command::Kick {
+                nick: String::from("bob"),
+                room: Some(String::from("#rust")),
+                reason: Some(String::from("off topic, again"))
+            })
+        );
+        assert_eq!(
+            Command::parse("mute", "bob 10m"),
+            Ok(Command::Mute {
+                nick: String::from("bob"),
+                room: None,
+                duration: Some(Duration::from_secs(600))
+            })
+        );
+        assert!(Command::parse("mute", "bob forever").is_err());
+        assert_eq!(
+            Command::parse("ban", "10.0.0.0/8 spam bots"),
+            Ok(Command::Ban {
+                target: String::from("10.0.0.0/8"),
+                duration: None,
+                reason: Some(String::from("spam bots"))
+            })
+        );
+        assert_eq!(Command::redact("login", "alice hunter22"), "");
+        assert_eq!(Command::redact("join", "#rust"), "#rust");
+        assert_eq!(
+            Command::parse("dance", ""),
+            Err(String::from("unknown command /dance"))
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("7d"), Some(Duration::from_secs(604800)));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("365d"), Some(MAX_DURATION));
+        assert_eq!(parse_duration("366d"), None);
+        assert_eq!(parse_duration("10000000000000000000s"), None);
+    }
+
+    #[test]
+    fn test_validate_nick() {
+        assert!(validate_nick("alice").is_ok());
+        assert!(validate_nick("bob_2-b").is_ok());
+        assert!(validate_nick("").is_err());
+        assert!(validate_nick("a_really_long_nickname").is_err());
+        assert!(validate_nick("no:colons").is_err());
+    }
+}
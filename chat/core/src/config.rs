@@ -0,0 +1,753 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Args, Parser};
+use protocol::DEFAULT_MAX_FRAME_SIZE;
+use serde::Deserialize;
+
+use crate::accounts::GuestPolicy;
+use crate::commands::validate_nick;
+use crate::queue::SlowPolicy;
+
+pub const DEFAULT_PORT: u16 = 6000;
+pub const DEFAULT_MAX_CLIENTS: usize = 1024;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4096;
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 5;
+pub const DEFAULT_PING_INTERVAL: u64 = 30;
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 90;
+pub const DEFAULT_FLOOD_RATE: f64 = 2.0;
+pub const DEFAULT_FLOOD_BURST: u32 = 10;
+pub const DEFAULT_FLOOD_MUTE: u64 = 60;
+pub const DEFAULT_MAX_VIOLATIONS: u32 = 5;
+pub const DEFAULT_QUEUE_SIZE: usize = 256;
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// Room for what the server wraps around a message's text when passing it on:
+/// the envelope, the sender, the room, the ID and the time.
+const MESSAGE_OVERHEAD: usize = 1024;
+
+/// Settings are read from, in order of precedence: command line flags,
+/// `CHAT_*` environment variables, the TOML config file and finally the
+/// built-in defaults.
+#[derive(Debug, Parser)]
+#[command(name = "server", about = "Chat server")]
+pub struct Cli {
+    /// TOML config file
+    #[arg(short, long, env = "CHAT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub settings: Settings,
+}
+
+/// Everything that can be set from the command line or the config file. The
+/// field names double as the config file keys, with `-` instead of `_`.
+#[derive(Args, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    /// Addresses to listen on, IPv4 or IPv6 (repeat or comma separate)
+    #[arg(short, long, env = "CHAT_BIND", value_delimiter = ',')]
+    pub bind: Vec<IpAddr>,
+
+    /// Port to listen on
+    #[arg(short, long, env = "CHAT_PORT")]
+    pub port: Option<u16>,
+
+    /// Also accept WebSocket clients on this port, on the same addresses
+    #[arg(long, env = "CHAT_WS_PORT")]
+    pub ws_port: Option<u16>,
+
+    /// Also accept IRC clients on this port, on the same addresses
+    #[arg(long, env = "CHAT_IRC_PORT")]
+    pub irc_port: Option<u16>,
+
+    /// Serve Prometheus metrics over HTTP on this port, on the same addresses
+    #[arg(long, env = "CHAT_METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// Also accept clients on this Unix socket, created on startup
+    #[arg(long, env = "CHAT_UNIX_SOCKET")]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Permissions for the Unix socket, in octal
+    #[arg(long, env = "CHAT_UNIX_SOCKET_MODE", value_parser = parse_mode)]
+    pub unix_socket_mode: Option<u32>,
+
+    /// Accept links from other servers on this port, on the same addresses
+    #[arg(long, env = "CHAT_LINK_PORT")]
+    pub link_port: Option<u16>,
+
+    /// Servers to link to, as host:port (repeat or comma separate)
+    #[arg(long, env = "CHAT_LINKS", value_delimiter = ',')]
+    pub links: Vec<String>,
+
+    /// This server's name among linked servers. Linking also takes a
+    /// password, from CHAT_LINK_PASSWORD or link-password in the file
+    #[arg(long, env = "CHAT_SERVER_NAME")]
+    pub server_name: Option<String>,
+
+    /// Password every linked server must give. Links are not encrypted, so
+    /// it crosses the network in the clear. There is no flag for it, which
+    /// would show it to anyone running ps: only CHAT_LINK_PASSWORD or the file
+    #[arg(skip = std::env::var("CHAT_LINK_PASSWORD").ok())]
+    pub link_password: Option<String>,
+
+    /// Connections beyond this are turned away
+    #[arg(long, env = "CHAT_MAX_CLIENTS")]
+    pub max_clients: Option<usize>,
+
+    /// Largest message, in bytes, a client may send, at most 64512
+    #[arg(long, env = "CHAT_MAX_MESSAGE_SIZE")]
+    pub max_message_size: Option<usize>,
+
+    /// Largest file, in bytes, a client may send
+    #[arg(long, env = "CHAT_MAX_FILE_SIZE")]
+    pub max_file_size: Option<u64>,
+
+    /// Message of the day shown to every client on connect
+    #[arg(long, env = "CHAT_MOTD")]
+    pub motd: Option<String>,
+
+    /// Also append the server log to this file
+    #[arg(long, env = "CHAT_LOG")]
+    pub log: Option<PathBuf>,
+
+    /// Directory holding the per-room message logs
+    #[arg(long, env = "CHAT_HISTORY_DIR")]
+    pub history_dir: Option<PathBuf>,
+
+    /// File holding registered accounts
+    #[arg(long, env = "CHAT_ACCOUNTS")]
+    pub accounts: Option<PathBuf>,
+
+    /// Registered accounts that are server operators once logged in
+    #[arg(long, env = "CHAT_OPERATORS", value_delimiter = ',')]
+    pub operators: Vec<String>,
+
+    /// File holding bans
+    #[arg(long, env = "CHAT_BANS")]
+    pub bans: Option<PathBuf>,
+
+    /// Told to every client when the server shuts down
+    #[arg(long, env = "CHAT_SHUTDOWN_REASON")]
+    pub shutdown_reason: Option<String>,
+
+    /// Seconds to wait for clients to disconnect on shutdown
+    #[arg(long, env = "CHAT_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Seconds between pings sent to each client
+    #[arg(long, env = "CHAT_PING_INTERVAL")]
+    pub ping_interval: Option<u64>,
+
+    /// Seconds of silence, pongs included, before a client is disconnected
+    #[arg(long, env = "CHAT_IDLE_TIMEOUT")]
+    pub idle_timeout: Option<u64>,
+
+    /// Messages a second a client may keep sending
+    #[arg(long, env = "CHAT_FLOOD_RATE")]
+    pub flood_rate: Option<f64>,
+
+    /// Messages a client may send in one go before the rate applies
+    #[arg(long, env = "CHAT_FLOOD_BURST")]
+    pub flood_burst: Option<u32>,
+
+    /// Seconds a client that keeps flooding is muted for
+    #[arg(long, env = "CHAT_FLOOD_MUTE")]
+    pub flood_mute: Option<u64>,
+
+    /// Malformed or oversized frames a client may send before it is dropped
+    #[arg(long, env = "CHAT_MAX_VIOLATIONS")]
+    pub max_violations: Option<u32>,
+
+    /// Messages waiting to be written to one client before it counts as slow
+    #[arg(long, env = "CHAT_QUEUE_SIZE")]
+    pub queue_size: Option<usize>,
+
+    /// What to do with a slow client: drop-oldest messages or disconnect it
+    #[arg(long, env = "CHAT_SLOW_CLIENTS", value_parser = parse_slow_clients)]
+    pub slow_clients: Option<SlowPolicy>,
+
+    /// Whether guests may chat: allow or deny
+    #[arg(long, env = "CHAT_GUESTS", value_parser = parse_guests)]
+    pub guests: Option<GuestPolicy>,
+
+    /// PEM certificate chain to serve TLS with
+    #[arg(long, env = "CHAT_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "CHAT_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve TLS with a generated self-signed certificate, for local
+    /// development. --tls-dev=false overrides the file
+    #[arg(
+        long,
+        env = "CHAT_TLS_DEV",
+        conflicts_with = "tls_cert",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub tls_dev: Option<bool>,
+}
+
+fn parse_guests(s: &str) -> Result<GuestPolicy, String> {
+    GuestPolicy::parse(s).ok_or_else(|| String::from("must be allow or deny"))
+}
+
+/// `660`, `0660` or `0o660`. The config file takes a TOML octal integer.
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(String::from("must be octal permissions, such as 660")),
+    }
+}
+
+fn parse_slow_clients(s: &str) -> Result<SlowPolicy, String> {
+    SlowPolicy::parse(s).ok_or_else(|| String::from("must be drop-oldest or disconnect"))
+}
+
+impl Settings {
+    /// Fills in anything not set here from `file`.
+    fn or(self, file: Settings) -> Settings {
+        Settings {
+            bind: if self.bind.is_empty() {
+                file.bind
+            } else {
+                self.bind
+            },
+            port: self.port.or(file.port),
+            ws_port: self.ws_port.or(file.ws_port),
+            irc_port: self.irc_port.or(file.irc_port),
+            metrics_port: self.metrics_port.or(file.metrics_port),
+            unix_socket: self.unix_socket.or(file.unix_socket),
+            unix_socket_mode: self.unix_socket_mode.or(file.unix_socket_mode),
+            link_port: self.link_port.or(file.link_port),
+            links: if self.links.is_empty() {
+                file.links
+            } else {
+                self.links
+            },
+            server_name: self.server_name.or(file.server_name),
+            link_password: self.link_password.or(file.link_password),
+            max_clients: self.max_clients.or(file.max_clients),
+            max_message_size: self.max_message_size.or(file.max_message_size),
+            max_file_size: self.max_file_size.or(file.max_file_size),
+            motd: self.motd.or(file.motd),
+            log: self.log.or(file.log),
+            history_dir: self.history_dir.or(file.history_dir),
+            accounts: self.accounts.or(file.accounts),
+            operators: if self.operators.is_empty() {
+                file.operators
+            } else {
+                self.operators
+            },
+            bans: self.bans.or(file.bans),
+            shutdown_reason: self.shutdown_reason.or(file.shutdown_reason),
+            shutdown_timeout: self.shutdown_timeout.or(file.shutdown_timeout),
+            ping_interval: self.ping_interval.or(file.ping_interval),
+            idle_timeout: self.idle_timeout.or(file.idle_timeout),
+            flood_rate: self.flood_rate.or(file.flood_rate),
+            flood_burst: self.flood_burst.or(file.flood_burst),
+            flood_mute: self.flood_mute.or(file.flood_mute),
+            max_violations: self.max_violations.or(file.max_violations),
+            queue_size: self.queue_size.or(file.queue_size),
+            slow_clients: self.slow_clients.or(file.slow_clients),
+            guests: self.guests.or(file.guests),
+            tls_cert: self.tls_cert.or(file.tls_cert),
+            tls_key: self.tls_key.or(file.tls_key),
+            tls_dev: self.tls_dev.or(file.tls_dev),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Tls {
+    Off,
+    Files { cert: PathBuf, key: PathBuf },
+    Dev { dir: PathBuf },
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind: Vec<SocketAddr>,
+    pub ws_bind: Vec<SocketAddr>,
+    pub irc_bind: Vec<SocketAddr>,
+    pub metrics_bind: Vec<SocketAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub unix_socket_mode: u32,
+    pub link_bind: Vec<SocketAddr>,
+    /// Servers to link to, as host:port.
+    pub links: Vec<String>,
+    /// Always set when the server links to others.
+    pub server_name: Option<String>,
+    pub link_password: Option<String>,
+    pub max_clients: usize,
+    pub max_message_size: usize,
+    pub max_file_size: u64,
+    pub motd: Option<String>,
+    pub log: Option<PathBuf>,
+    pub history_dir: PathBuf,
+    pub accounts: PathBuf,
+    /// Lowercased, like the hub's nickname registry.
+    pub operators: Vec<String>,
+    pub bans: PathBuf,
+    pub shutdown_reason: Option<String>,
+    pub shutdown_timeout: Duration,
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+    pub flood_rate: f64,
+    pub flood_burst: u32,
+    pub flood_mute: Duration,
+    pub max_violations: u32,
+    pub queue_size: usize,
+    pub slow_clients: SlowPolicy,
+    pub guests: GuestPolicy,
+    pub tls: Tls,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::try_from(Settings::default()).expect("default settings are valid")
+    }
+}
+
+impl TryFrom<Settings> for Config {
+    type Error = String;
+
+    fn try_from(settings: Settings) -> Result<Config, String> {
+        let port = settings.port.unwrap_or(DEFAULT_PORT);
+        let bind = match settings.bind.is_empty() {
+            true => vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            false => settings.bind,
+        };
+
+        let tls_dev = settings.tls_dev.unwrap_or(false);
+        let tls = match (settings.tls_cert, settings.tls_key, tls_dev) {
+            (Some(cert), Some(key), false) => Tls::Files { cert, key },
+            (None, None, true) => Tls::Dev {
+                dir: PathBuf::from("tls"),
+            },
+            (None, None, false) => Tls::Off,
+            (_, _, true) => return Err(String::from("tls-dev can't be used with tls-cert")),
+            _ => return Err(String::from("tls-cert and tls-key must be set together")),
+        };
+
+        let max_clients = settings.max_clients.unwrap_or(DEFAULT_MAX_CLIENTS);
+        if max_clients == 0 {
+            return Err(String::from("max-clients must be at least 1"));
+        }
+
+        let max_message_size = settings
+            .max_message_size
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+        if max_message_size == 0 {
+            return Err(String::from("max-message-size must be at least 1"));
+        }
+        // what the server relays has to fit in a frame too
+        if max_message_size > DEFAULT_MAX_FRAME_SIZE - MESSAGE_OVERHEAD {
+            return Err(format!(
+                "max-message-size can be at most {}",
+                DEFAULT_MAX_FRAME_SIZE - MESSAGE_OVERHEAD
+            ));
+        }
+
+        for nick in &settings.operators {
+            validate_nick(nick).map_err(|err| format!("operator {}: {}", nick, err))?;
+        }
+
+        let ping_interval = settings.ping_interval.unwrap_or(DEFAULT_PING_INTERVAL);
+        let idle_timeout = settings.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT);
+        if ping_interval == 0 {
+            return Err(String::from("ping-interval must be at least 1"));
+        }
+        // otherwise a healthy client never gets the chance to answer a ping
+        if idle_timeout <= ping_interval {
+            return Err(String::from(
+                "idle-timeout must be longer than ping-interval",
+            ));
+        }
+
+        let flood_rate = settings.flood_rate.unwrap_or(DEFAULT_FLOOD_RATE);
+        let flood_burst = settings.flood_burst.unwrap_or(DEFAULT_FLOOD_BURST);
+        if flood_rate.is_nan() || flood_rate <= 0.0 {
+            return Err(String::from("flood-rate must be more than 0"));
+        }
+        if flood_burst == 0 {
+            return Err(String::from("flood-burst must be at least 1"));
+        }
+
+        let max_violations = settings.max_violations.unwrap_or(DEFAULT_MAX_VIOLATIONS);
+        if max_violations == 0 {
+            return Err(String::from("max-violations must be at least 1"));
+        }
+
+        let queue_size = settings.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE);
+        if queue_size == 0 {
+            return Err(String::from("queue-size must be at least 1"));
+        }
+
+        let ws_bind = match settings.ws_port {
+            Some(ws_port) if ws_port == port => {
+                return Err(String::from("ws-port must differ from port"))
+            }
+            Some(ws_port) => bind
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, ws_port))
+                .collect(),
+            None => vec![],
+        };
+
+        let irc_bind = match settings.irc_port {
+            Some(irc_port) if irc_port == port || Some(irc_port) == settings.ws_port => {
+                return Err(String::from("irc-port must differ from port and ws-port"))
+            }
+            Some(irc_port) => bind
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, irc_port))
+                .collect(),
+            None => vec![],
+        };
+
+        let metrics_bind = match settings.metrics_port {
+            Some(metrics_port)
+                if metrics_port == port
+                    || Some(metrics_port) == settings.ws_port
+                    || Some(metrics_port) == settings.irc_port =>
+            {
+                return Err(String::from(
+                    "metrics-port must differ from port, ws-port and irc-port",
+                ))
+            }
+            Some(metrics_port) => bind
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, metrics_port))
+                .collect(),
+            None => vec![],
+        };
+
+        let unix_socket_mode = settings
+            .unix_socket_mode
+            .unwrap_or(DEFAULT_UNIX_SOCKET_MODE);
+        if unix_socket_mode > 0o777 {
+            return Err(String::from("unix-socket-mode must be at most 0o777"));
+        }
+
+        let link_bind = match settings.link_port {
+            Some(link_port)
+                if link_port == port
+                    || Some(link_port) == settings.ws_port
+                    || Some(link_port) == settings.irc_port
+                    || Some(link_port) == settings.metrics_port =>
+            {
+                return Err(String::from(
+                    "link-port must differ from port, ws-port, irc-port and metrics-port",
+                ))
+            }
+            Some(link_port) => bind
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, link_port))
+                .collect(),
+            None => vec![],
+        };
+
+        let linked = settings.link_port.is_some() || !settings.links.is_empty();
+        if linked && (settings.server_name.is_none() || settings.link_password.is_none()) {
+            return Err(String::from(
+                "server-name and link-password must be set to link servers",
+            ));
+        }
+        if let Some(name) = &settings.server_name {
+            validate_nick(name).map_err(|err| format!("server-name: {}", err))?;
+        }
+
+        Ok(Config {
+            bind: bind
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect(),
+            ws_bind,
+            irc_bind,
+            metrics_bind,
+            unix_socket: settings.unix_socket,
+            unix_socket_mode,
+            link_bind,
+            links: settings.links,
+            server_name: settings.server_name,
+            link_password: settings.link_password,
+            max_clients,
+            max_message_size,
+            max_file_size: settings.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            motd: settings.motd,
+            log: settings.log,
+            history_dir: settings
+                .history_dir
+                .unwrap_or_else(|| PathBuf::from("history")),
+            accounts: settings
+                .accounts
+                .unwrap_or_else(|| PathBuf::from("accounts")),
+            operators: settings
+                .operators
+                .iter()
+                .map(|nick| nick.to_lowercase())
+                .collect(),
+            bans: settings.bans.unwrap_or_else(|| PathBuf::from("bans")),
+            shutdown_reason: settings.shutdown_reason,
+            shutdown_timeout: Duration::from_secs(
+                settings
+                    .shutdown_timeout
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            ),
+            ping_interval: Duration::from_secs(ping_interval),
+            idle_timeout: Duration::from_secs(idle_timeout),
+            flood_rate,
+            flood_burst,
+            flood_mute: Duration::from_secs(settings.flood_mute.unwrap_or(DEFAULT_FLOOD_MUTE)),
+            max_violations,
+            queue_size,
+            slow_clients: settings.slow_clients.unwrap_or(SlowPolicy::DropOldest),
+            guests: settings.guests.unwrap_or(GuestPolicy::Allow),
+            tls,
+        })
+    }
+}
+
+impl Config {
+    /// Reads the command line, environment and config file.
+    pub fn load() -> Result<Config, String> {
+        Config::from_cli(Cli::parse())
+    }
+
+    fn from_cli(cli: Cli) -> Result<Config, String> {
+        let file = match &cli.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+                toml::from_str(&contents)
+                    .map_err(|err| format!("failed to parse {}: {}", path.display(), err))?
+            }
+            None => Settings::default(),
+        };
+
+        Config::try_from(cli.settings.or(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::default();
+
+        assert_eq!(config.bind, vec!["127.0.0.1:6000".parse().unwrap()]);
+        assert_eq!(config.max_message_size, DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(config.max_file_size, DEFAULT_MAX_FILE_SIZE);
+        assert_eq!(config.guests, GuestPolicy::Allow);
+        assert_eq!(config.tls, Tls::Off);
+        assert_eq!(config.max_violations, DEFAULT_MAX_VIOLATIONS);
+        assert_eq!(config.queue_size, DEFAULT_QUEUE_SIZE);
+        assert_eq!(config.slow_clients, SlowPolicy::DropOldest);
+        assert_eq!(config.unix_socket, None);
+        assert_eq!(config.unix_socket_mode, DEFAULT_UNIX_SOCKET_MODE);
+    }
+
+    #[test]
+    fn test_file_settings() {
+        let file: Settings = toml::from_str(
+            r#"
+            bind = ["0.0.0.0", "::"]
+            port = 7000
+            max-clients = 10
+            motd = "be nice"
+            guests = "deny"
+            slow-clients = "disconnect"
+            unix-socket = "chat.sock"
+            unix-socket-mode = 0o600
+            operators = ["Alice"]
+            "#,
+        )
+        .unwrap();
+        let config = Config::try_from(file).unwrap();
+
+        assert_eq!(
+            config.bind,
+            vec![
+                "0.0.0.0:7000".parse().unwrap(),
+                "[::]:7000".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.ws_bind, vec![]);
+        assert_eq!(config.irc_bind, vec![]);
+        assert_eq!(config.metrics_bind, vec![]);
+        assert_eq!(config.max_clients, 10);
+        assert_eq!(config.motd.as_deref(), Some("be nice"));
+        assert_eq!(config.guests, GuestPolicy::Deny);
+        assert_eq!(config.slow_clients, SlowPolicy::Disconnect);
+        assert_eq!(config.unix_socket, Some(PathBuf::from("chat.sock")));
+        assert_eq!(config.unix_socket_mode, 0o600);
+        assert_eq!(config.operators, vec!["alice"]);
+
+        assert!(toml::from_str::<Settings>("colour = \"blue\"").is_err());
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let file: Settings =
+            toml::from_str("port = 7000\nmotd = \"from file\"\nlink-password = \"secret\"")
+                .unwrap();
+        let cli = Cli::try_parse_from([
+            "server",
+            "--port",
+            "7001",
+            "--bind",
+            "::1,127.0.0.1",
+            "--ws-port",
+            "7002",
+            "--irc-port",
+            "7003",
+            "--metrics-port",
+            "7004",
+            "--link-port",
+            "7005",
+            "--links",
+            "peer.example:7005",
+            "--server-name",
+            "berlin",
+        ])
+        .unwrap();
+        let config = Config::try_from(cli.settings.or(file)).unwrap();
+
+        assert_eq!(
+            config.bind,
+            vec![
+                "[::1]:7001".parse().unwrap(),
+                "127.0.0.1:7001".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config.ws_bind,
+            vec![
+                "[::1]:7002".parse().unwrap(),
+                "127.0.0.1:7002".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config.irc_bind,
+            vec![
+                "[::1]:7003".parse().unwrap(),
+                "127.0.0.1:7003".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config.metrics_bind,
+            vec![
+                "[::1]:7004".parse().unwrap(),
+                "127.0.0.1:7004".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config.link_bind,
+            vec![
+                "[::1]:7005".parse().unwrap(),
+                "127.0.0.1:7005".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.links, vec!["peer.example:7005"]);
+        assert_eq!(config.server_name.as_deref(), Some("berlin"));
+        assert_eq!(config.motd.as_deref(), Some("from file"));
+
+        let file: Settings = toml::from_str(
+            "ws-port = 7002
+metrics-port = 7002",
+        )
+        .unwrap();
+        assert!(Config::try_from(file).is_err());
+    }
+
+    #[test]
+    fn test_unix_socket_mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        assert!(parse_mode("rw-rw----").is_err());
+        assert!(parse_mode("1777").is_err());
+
+        let file: Settings = toml::from_str("unix-socket-mode = 0o4755").unwrap();
+        assert!(Config::try_from(file).is_err());
+    }
+
+    #[test]
+    fn test_link_settings() {
+        assert!(Cli::try_parse_from(["server", "--link-password", "secret"]).is_err());
+
+        let file: Settings = toml::from_str("link-port = 7005").unwrap();
+        assert!(Config::try_from(file).is_err());
+
+        let file: Settings = toml::from_str(
+            "links = [\"peer.example:7005\"]\nserver-name = \"two words\"\nlink-password = \"x\"",
+        )
+        .unwrap();
+        assert!(Config::try_from(file).is_err());
+    }
+
+    #[test]
+    fn test_tls_settings() {
+        assert!(Cli::try_parse_from(["server", "--tls-cert", "cert.pem"]).is_err());
+
+        let file: Settings = toml::from_str("tls-dev = true\ntls-cert = \"cert.pem\"").unwrap();
+        assert!(Config::try_from(file).is_err());
+
+        // the file's tls-dev stands unless a flag says otherwise
+        let tls = |args: &[&str]| {
+            let file: Settings = toml::from_str("tls-dev = true").unwrap();
+            let cli = Cli::try_parse_from(args).unwrap();
+            Config::try_from(cli.settings.or(file)).unwrap().tls
+        };
+        let dev = Tls::Dev {
+            dir: PathBuf::from("tls"),
+        };
+        assert_eq!(tls(&["server"]), dev);
+        assert_eq!(tls(&["server", "--tls-dev"]), dev);
+        assert_eq!(tls(&["server", "--tls-dev=false"]), Tls::Off);
+    }
+
+    #[test]
+    fn test_heartbeat_settings() {
+        let config = Config::default();
+        assert_eq!(config.ping_interval, Duration::from_secs(30));
+        assert_eq!(config.idle_timeout, Duration::from_secs(90));
+
+        let file: Settings = toml::from_str("ping-interval = 60\nidle-timeout = 60").unwrap();
+        assert!(Config::try_from(file).is_err());
+    }
+
+    #[test]
+    fn test_flood_settings() {
+        let file: Settings = toml::from_str("flood-rate = 0.5\nflood-burst = 3").unwrap();
+        let config = Config::try_from(file).unwrap();
+        assert_eq!(config.flood_rate, 0.5);
+        assert_eq!(config.flood_burst, 3);
+        assert_eq!(config.flood_mute, Duration::from_secs(DEFAULT_FLOOD_MUTE));
+
+        let file: Settings = toml::from_str("flood-rate = 0.0").unwrap();
+        assert!(Config::try_from(file).is_err());
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        let file: Settings = toml::from_str("max-message-size = 16384").unwrap();
+        assert_eq!(Config::try_from(file).unwrap().max_message_size, 16384);
+
+        for size in [0, DEFAULT_MAX_FRAME_SIZE] {
+            let file: Settings = toml::from_str(&format!("max-message-size = {}", size)).unwrap();
+            assert!(Config::try_from(file).is_err());
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use protocol::chunk::CHUNK_HEADER_SIZE;
+use protocol::{
+    decode_chunk, decode_message, encode_chunk, encode_frame, encode_message, is_chunk,
+    ClientMessage, ErrorCode, FrameDecoder, FrameError, ServerMessage, DEFAULT_MAX_FRAME_SIZE,
+    MAX_CHUNK_SIZE,
+};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+use crate::flood::{FloodGuard, Verdict};
+use crate::hub::{ClientId, Event};
+use crate::log;
+use crate::metrics::{self, METRICS};
+use crate::queue;
+
+/// How long the writer has to flush once a client is done with.
+const WRITER_GRACE: Duration = Duration::from_secs(2);
+
+/// Where a client's messages come from: length prefixed frames on a plain
+/// socket, or WebSocket messages.
+pub trait Incoming: Send {
+    /// The payload of the next message, or `None` once the client has closed
+    /// the connection. A message the client should not have sent is an error
+    /// of its own and leaves the connection usable. Must be cancel safe.
+    fn next(
+        &mut self,
+    ) -> impl Future<Output = io::Result<Option<Result<Vec<u8>, FrameError>>>> + Send;
+
+    /// The most a message's payload may be, unless the transport holds its
+    /// clients to `max_message_size` some other way.
+    fn max_message_size(&self, config: &Config) -> usize {
+        config.max_message_size
+    }
+}
+
+/// Where the messages for a client go.
+pub trait Outgoing: Send + 'static {
+    fn send(&mut self, payload: Vec<u8>) -> impl Future<Output = io::Result<()>> + Send;
+
+    fn close(&mut self) -> impl Future<Output = ()> + Send;
+}
+
+pub struct Frames<S> {
+    reader: ReadHalf<S>,
+    decoder: FrameDecoder,
+    buff: Vec<u8>,
+}
+
+impl<S> Frames<S> {
+    pub fn new(reader: ReadHalf<S>, max_frame_size: usize) -> Frames<S> {
+        Frames {
+            reader,
+            decoder: FrameDecoder::new(max_frame_size),
+            buff: vec![0; 4096],
+        }
+    }
+}
+
+impl<S: AsyncRead + Send> Incoming for Frames<S> {
+    async fn next(&mut self) -> io::Result<Option<Result<Vec<u8>, FrameError>>> {
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(frame)) => return Ok(Some(Ok(frame))),
+                Ok(None) => (),
+                Err(err) => return Ok(Some(Err(err))),
+            }
+
+            let n = self.reader.read(&mut self.buff).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            metrics::add(&METRICS.bytes_in, n);
+            self.decoder.extend(&self.buff[..n]);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Send + 'static> Outgoing for WriteHalf<S> {
+    async fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        let buff = encode_frame(&payload, DEFAULT_MAX_FRAME_SIZE)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        metrics::add(&METRICS.bytes_out, buff.len());
+        self.write_all(&buff).await
+    }
+
+    async fn close(&mut self) {
+        let _ = self.shutdown().await;
+    }
+}
+
+/// Who a client is, as far as logs and bans go.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Peer {
+    Net(SocketAddr),
+    /// On the Unix socket, known by the user (and process) the system says
+    /// is on the other end.
+    Unix {
+        uid: u32,
+        pid: Option<i32>,
+    },
+}
+
+impl Peer {
+    /// Only clients from the network have an address that bans can match.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Net(addr) => Some(addr.ip()),
+            Peer::Unix { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Net(addr) => write!(f, "{}", addr),
+            Peer::Unix {
+                uid,
+                pid: Some(pid),
+            } => write!(f, "unix:uid={},pid={}", uid, pid),
+            Peer::Unix { uid, pid: None } => write!(f, "unix:uid={}", uid),
+        }
+    }
+}
+
+/// Serves a client over a plain (or TLS) socket.
+pub async fn handle<S>(
+    socket: S,
+    peer: Peer,
+    id: ClientId,
+    events: UnboundedSender<Event>,
+    config: &Config,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = io::split(socket);
+    let frames = Frames::new(reader, max_payload(config));
+
+    serve(frames, writer, peer, id, events, config).await
+}
+
+/// The most a transport should read in one go: the larger of a message and a
+/// full file chunk. Messages are held to `max_message_size` by `serve`.
+pub fn max_payload(config: &Config) -> usize {
+    config
+        .max_message_size
+        .max(CHUNK_HEADER_SIZE + MAX_CHUNK_SIZE)
+}
+
+/// Chunk frames for file data, JSON for everything else.
+fn encode(msg: &ServerMessage) -> Vec<u8> {
+    match msg {
+        ServerMessage::Chunk { id, data } => encode_chunk(*id, data),
+        msg => encode_message(msg),
+    }
+}
+
+fn decode(payload: &[u8], max: usize) -> Result<ClientMessage, (ErrorCode, String)> {
+    if is_chunk(payload) {
+        return match decode_chunk(payload) {
+            Some((_, data)) if data.len() > MAX_CHUNK_SIZE => Err((
+                ErrorCode::TooLarge,
+                format!("chunks may be at most {} bytes", MAX_CHUNK_SIZE),
+            )),
+            Some((id, data)) => Ok(ClientMessage::Chunk {
+                id,
+                data: data.to_vec(),
+            }),
+            None => Err((ErrorCode::Malformed, String::from("truncated chunk frame"))),
+        };
+    }
+
+    if payload.len() > max {
+        let err = FrameError::TooLarge {
+            len: payload.len(),
+            max,
+        };
+        return Err((ErrorCode::TooLarge, err.to_string()));
+    }
+
+    decode_message(payload).map_err(|err| (err.code(), err.to_string()))
+}
+
+/// Drives a single client: messages coming from it are handed to the hub,
+/// and whatever the hub queues for this client is sent back out.
+/// `max_message_size` only limits what the client sends; replies from the
+/// server may be larger.
+///
+/// The client is pinged every `ping_interval`, and a client that sends
+/// nothing at all, not even a pong, for `idle_timeout` is dropped. That is
+/// the only way to notice a peer that vanished without closing the socket.
+///
+/// Messages beyond the flood limits never reach the hub at all, so a client
+/// flooding the server costs everyone else nothing. Neither do frames that
+/// don't decode: each one is a protocol violation answered with an error, and
+/// after `max_violations` of them the client is dropped.
+///
+/// File chunks are exempt from the flood limits too, since a transfer is
+/// already paced by the hub's progress acks, one chunk at a time. Only the
+/// hub knows which transfers a client may send, so it counts the violations
+/// of chunks for any other.
+pub async fn serve<I, O>(
+    mut incoming: I,
+    mut outgoing: O,
+    peer: Peer,
+    id: ClientId,
+    events: UnboundedSender<Event>,
+    config: &Config,
+) where
+    I: Incoming,
+    O: Outgoing,
+{
+    let (tx, mut rx) = queue::channel(config.queue_size, config.slow_clients);
+
+    if events.send(Event::Connected { id, peer, tx }).is_err() {
+        return;
+    }
+
+    // for what this task tells the client itself, without going via the hub
+    let (local, mut local_rx) = mpsc::unbounded_channel::<ServerMessage>();
+
+    // once the hub drops our sender, whatever is still queued gets written
+    // and the connection is closed. A client that let its queue overflow
+    // under the disconnect policy is told why and closed straight away.
+    let ping_interval = config.ping_interval;
+    let mut writer_task = tokio::spawn(async move {
+        let mut pings = time::interval_at(Instant::now() + ping_interval, ping_interval);
+
+        loop {
+            // local first, so the reason for dropping a client goes out
+            // before the hub lets go of it
+            let msg = tokio::select! {
+                biased;
+                Some(msg) = local_rx.recv() => msg,
+                msg = rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None if rx.too_slow() => {
+                        let text = String::from("disconnected for falling too far behind");
+                        let msg = ServerMessage::Error { code: ErrorCode::Refused, text };
+                        let _ = outgoing.send(encode(&msg)).await;
+                        break;
+                    }
+                    None => break,
+                },
+                _ = pings.tick() => ServerMessage::Ping,
+            };
+
+            match outgoing.send(encode(&msg)).await {
+                Ok(()) => (),
+                // a message too large to send is lost, but the connection
+                // is fine
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    metrics::add(&METRICS.failed_writes, 1);
+                    log!("{}: dropping a reply: {}", peer, err);
+                }
+                Err(_) => {
+                    metrics::add(&METRICS.failed_writes, 1);
+                    return;
+                }
+            }
+        }
+
+        outgoing.close().await;
+    });
+
+    let idle = time::sleep(config.idle_timeout);
+    tokio::pin!(idle);
+    let mut flood = FloodGuard::new(
+        config.flood_rate,
+        config.flood_burst,
+        config.flood_mute,
+        Instant::now(),
+    );
+    let mut violations = 0;
+
+    let reason = loop {
+        let next = tokio::select! {
+            next = incoming.next() => next,
+            _ = &mut idle => break "timed out",
+            _ = &mut writer_task => break "disconnected",
+        };
+
+        let decoded = match next {
+            Ok(Some(Ok(payload))) => decode(&payload, incoming.max_message_size(config)),
+            Ok(Some(Err(err))) => Err((ErrorCode::TooLarge, err.to_string())),
+            Ok(None) => break "quit",
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut => break "timed out",
+            Err(_) => break "connection lost",
+        };
+        idle.as_mut().reset(Instant::now() + config.idle_timeout);
+
+        let msg = match decoded {
+            Ok(msg) => msg,
+            Err((code, text)) => {
+                violations += 1;
+                log!(
+                    "{}: protocol violation {} of {}: {}",
+                    peer,
+                    violations,
+                    config.max_violations,
+                    text
+                );
+                let _ = local.send(ServerMessage::Error { code, text });
+
+                if violations >= config.max_violations {
+                    let text = String::from("disconnected for too many protocol violations");
+                    let _ = local.send(ServerMessage::Error { code, text });
+                    break "protocol violations";
+                }
+                continue;
+            }
+        };
+
+        // the heartbeat has to get through however much else is sent
+        let verdict = match msg {
+            ClientMessage::Ping | ClientMessage::Pong | ClientMessage::Chunk { .. } => {
+                Verdict::Allow
+            }
+            _ => flood.check(Instant::now()),
+        };
+        let notice = match verdict {
+            Verdict::Allow => None,
+            Verdict::Drop => continue,
+            Verdict::Warn => {
+                log!("{}: flooding, dropping messages", peer);
+                Some(String::from(
+                    "you are sending too fast, messages are being dropped",
+                ))
+            }
+            Verdict::Mute(time) => {
+                log!("{}: muted for flooding", peer);
+                Some(format!(
+                    "you are muted for {}s for flooding",
+                    time.as_secs()
+                ))
+            }
+            Verdict::Muted(left) => {
+                Some(format!("you are muted for another {}s", left.as_secs() + 1))
+            }
+            Verdict::Disconnect => {
+                log!("{}: disconnecting for flooding", peer);
+                let text = String::from("disconnected for flooding");
+                let _ = local.send(ServerMessage::Error {
+                    code: ErrorCode::Flooding,
+                    text,
+                });
+                break "flooding";
+            }
+        };
+        if let Some(text) = notice {
+            let _ = local.send(ServerMessage::Error {
+                code: ErrorCode::Flooding,
+                text,
+            });
+            continue;
+        }
+
+        let _ = events.send(Event::Message { id, msg });
+    };
+
+    let _ = events.send(Event::Disconnected {
+        id,
+        reason: reason.to_string(),
+    });
+
+    // the hub lets go of the client now, and the writer gets a moment to
+    // flush what is left. A peer that stopped reading could leave it stuck
+    // on a full send buffer for good.
+    if !writer_task.is_finished() && time::timeout(WRITER_GRACE, &mut writer_task).await.is_err() {
+        writer_task.abort();
+    }
+}
@@ -0,0 +1,404 @@
+/**
+ * What the hub knows about linked servers: the links that are up, who on
+ * each other server is in which room, and which relayed messages it has
+ * already seen.
+ *
+ * Servers pass what they learn on to every link but the one it came in on,
+ * so links may form any shape, loops included. Chat messages, edits and
+ * deletions carry an ID and are dropped when seen again. Member lists carry a
+ * number from their server's ever growing `seq` instead, and one no newer
+ * than what is already held is dropped, which also keeps a server's restart
+ * from being mistaken for old news.
+ *
+ * Another server's members are filed under the link they last came in on.
+ * If that link goes down they go with it, and the other links are asked for
+ * what they know, in case the server can still be reached that way.
+**/
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use protocol::LinkMessage;
+use tokio::sync::mpsc::UnboundedSender;
+
+pub type LinkId = u64;
+
+/// How many relayed message IDs are remembered for spotting repeats.
+const MAX_SEEN: usize = 10_000;
+
+struct Link {
+    name: String,
+    tx: UnboundedSender<LinkMessage>,
+}
+
+/// One server's members of one room, as of `seq`. Kept once the room is
+/// empty, so that an older list can't bring them back.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Members {
+    seq: u64,
+    nicks: BTreeSet<String>,
+}
+
+struct Origin {
+    // the link this server's members last came in on
+    route: LinkId,
+    rooms: BTreeMap<String, Members>,
+}
+
+/// Who joined and who left a room, from a member list that replaced another.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Change {
+    pub joined: Vec<String>,
+    pub left: Vec<String>,
+}
+
+pub struct Federation {
+    name: String,
+    links: HashMap<LinkId, Link>,
+    seq: u64,
+    // this server's own members, as last sent to the links
+    local: BTreeMap<String, Members>,
+    remote: HashMap<String, Origin>,
+    seen: HashSet<String>,
+    seen_order: VecDeque<String>,
+}
+
+impl Federation {
+    pub fn new(name: String) -> Federation {
+        // microseconds since the epoch, so a restarted server carries on
+        // above what its peers last heard from it
+        let seq = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        Federation {
+            name,
+            links: HashMap::new(),
+            seq,
+            local: BTreeMap::new(),
+            remote: HashMap::new(),
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Takes on a link whose handshake is done, and tells it everything
+    /// known so far.
+    pub fn link_up(
+        &mut self,
+        link: LinkId,
+        name: String,
+        tx: UnboundedSender<LinkMessage>,
+    ) -> Result<(), String> {
+        // two links to the same server are fine, they just carry everything
+        // twice
+        if name == self.name {
+            return Err(String::from("it has the same name as this server"));
+        }
+
+        for msg in self.snapshot() {
+            let _ = tx.send(msg);
+        }
+        self.links.insert(link, Link { name, tx });
+        Ok(())
+    }
+
+    /// Forgets a link, returning its name and the servers that were reached
+    /// through it. Those still need to be `forget`ten.
+    pub fn link_down(&mut self, link: LinkId) -> Option<(String, Vec<String>)> {
+        let Link { name, .. } = self.links.remove(&link)?;
+        let lost = self.routed_via(link);
+        Some((name, lost))
+    }
+
+    /// The servers whose members last came in on `link`.
+    pub fn routed_via(&self, link: LinkId) -> Vec<String> {
+        let mut servers = self
+            .remote
+            .iter()
+            .filter(|(_, origin)| origin.route == link)
+            .map(|(server, _)| server.clone())
+            .collect::<Vec<_>>();
+        servers.sort();
+        servers
+    }
+
+    /// Drops everything known about another server, returning who it had in
+    /// which room.
+    pub fn forget(&mut self, server: &str) -> Vec<(String, Vec<String>)> {
+        let Some(origin) = self.remote.remove(server) else {
+            return vec![];
+        };
+
+        origin
+            .rooms
+            .into_iter()
+            .filter(|(_, members)| !members.nicks.is_empty())
+            .map(|(room, members)| (room, members.nicks.into_iter().collect()))
+            .collect()
+    }
+
+    /// Sends to one link.
+    pub fn send(&self, link: LinkId, msg: LinkMessage) {
+        if let Some(link) = self.links.get(&link) {
+            let _ = link.tx.send(msg);
+        }
+    }
+
+    /// Sends to every link but `except`, the one a message came in on.
+    pub fn broadcast(&self, except: Option<LinkId>, msg: &LinkMessage) {
+        for (&id, link) in &self.links {
+            if Some(id) != except {
+                let _ = link.tx.send(msg.clone());
+            }
+        }
+    }
+
+    /// The ID a message from this server is relayed under, from the one
+    /// `History` gave it.
+    pub fn message_id(&self, id: u64) -> String {
+        format!("{}:{}", self.name, id)
+    }
+
+    /// Records a relayed message ID, returning false if it was seen before.
+    pub fn first_sighting(&mut self, id: &str) -> bool {
+        if !self.seen.insert(id.to_string()) {
+            return false;
+        }
+
+        self.seen_order.push_back(id.to_string());
+        if self.seen_order.len() > MAX_SEEN {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Sends out the member lists of this server's rooms that differ from
+    /// what was last sent.
+    pub fn set_local(&mut self, rooms: BTreeMap<String, BTreeSet<String>>) {
+        let emptied = self
+            .local
+            .iter()
+            .filter(|(room, members)| !members.nicks.is_empty() && !rooms.contains_key(*room))
+            .map(|(room, _)| (room.clone(), BTreeSet::new()))
+            .collect::<Vec<_>>();
+
+        for (room, nicks) in rooms.into_iter().chain(emptied) {
+            if self.local.get(&room).map(|members| &members.nicks) == Some(&nicks) {
+                continue;
+            }
+
+            self.seq += 1;
+            let members = Members {
+                seq: self.seq,
+                nicks,
+            };
+            let msg = members_message(&self.name, &room, &members);
+            self.local.insert(room, members);
+            self.broadcast(None, &msg);
+        }
+    }
+
+    /// Takes in a member list that came in on `link`. Returns what changed,
+    /// or `None` if it was old news and shouldn't be passed on.
+    pub fn apply(
+        &mut self,
+        link: LinkId,
+        server: String,
+        seq: u64,
+        room: String,
+        nicks: Vec<String>,
+    ) -> Option<Change> {
+        if server == self.name {
+            return None;
+        }
+
+        let origin = self.remote.entry(server).or_insert_with(|| Origin {
+            route: link,
+            rooms: BTreeMap::new(),
+        });
+        let old = origin.rooms.get(&room).cloned().unwrap_or_default();
+        if seq <= old.seq {
+            return None;
+        }
+
+        origin.route = link;
+        let nicks = nicks.into_iter().collect::<BTreeSet<_>>();
+        let change = Change {
+            joined: nicks.difference(&old.nicks).cloned().collect(),
+            left: old.nicks.difference(&nicks).cloned().collect(),
+        };
+        origin.rooms.insert(room, Members { seq, nicks });
+        Some(change)
+    }
+
+    /// Everyone on other servers in `room`, as `nick@server`.
+    pub fn members(&self, room: &str) -> Vec<String> {
+        let mut nicks = self
+            .remote
+            .iter()
+            .filter_map(|(server, origin)| Some((server, origin.rooms.get(room)?)))
+            .flat_map(|(server, members)| {
+                members
+                    .nicks
+                    .iter()
+                    .map(move |nick| remote_nick(nick, server))
+            })
+            .collect::<Vec<_>>();
+        nicks.sort_by_key(|nick| nick.to_lowercase());
+        nicks
+    }
+
+    /// Every member list known, this server's own included.
+    pub fn snapshot(&self) -> Vec<LinkMessage> {
+        let own = self
+            .local
+            .iter()
+            .map(|(room, members)| members_message(&self.name, room, members));
+        let relayed = self.remote.iter().flat_map(|(server, origin)| {
+            origin
+                .rooms
+                .iter()
+                .map(move |(room, members)| members_message(server, room, members))
+        });
+
+        own.chain(relayed).collect()
+    }
+
+    /// Lets go of every link, which closes them.
+    pub fn shutdown(&mut self) {
+        self.links.clear();
+    }
+}
+
+/// How a user on another server is shown here.
+pub fn remote_nick(nick: &str, server: &str) -> String {
+    format!("{}@{}", nick, server)
+}
+
+fn members_message(server: &str, room: &str, members: &Members) -> LinkMessage {
+    LinkMessage::Members {
+        server: server.to_string(),
+        seq: members.seq,
+        room: room.to_string(),
+        nicks: members.nicks.iter().cloned().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn nicks(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut federation = Federation::new(String::from("a"));
+        let room = String::from("#general");
+        let b = String::from("b");
+
+        let change = federation.apply(1, b.clone(), 5, room.clone(), nicks(&["bob", "carol"]));
+        assert_eq!(
+            change,
+            Some(Change {
+                joined: nicks(&["bob", "carol"]),
+                left: vec![],
+            })
+        );
+
+        // the same list again, or an older one, is old news
+        assert_eq!(
+            federation.apply(2, b.clone(), 5, room.clone(), nicks(&["bob"])),
+            None
+        );
+        assert_eq!(
+            federation.apply(2, b.clone(), 4, room.clone(), vec![]),
+            None
+        );
+        // and a list of our own, come back round a loop, is ignored
+        assert_eq!(
+            federation.apply(2, String::from("a"), 9, room.clone(), vec![]),
+            None
+        );
+
+        let change = federation.apply(2, b.clone(), 6, room.clone(), nicks(&["bob", "dave"]));
+        assert_eq!(
+            change,
+            Some(Change {
+                joined: nicks(&["dave"]),
+                left: nicks(&["carol"]),
+            })
+        );
+        assert_eq!(federation.members(&room), nicks(&["bob@b", "dave@b"]));
+
+        // b's members came in on link 2 last
+        assert!(federation.routed_via(1).is_empty());
+        assert_eq!(federation.routed_via(2), vec![b.clone()]);
+        assert_eq!(
+            federation.forget(&b),
+            vec![(room.clone(), nicks(&["bob", "dave"]))]
+        );
+        assert!(federation.members(&room).is_empty());
+    }
+
+    #[test]
+    fn test_set_local() {
+        let mut federation = Federation::new(String::from("a"));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        federation.link_up(1, String::from("b"), tx).unwrap();
+
+        let rooms = BTreeMap::from([(
+            String::from("#general"),
+            BTreeSet::from([String::from("alice")]),
+        )]);
+        federation.set_local(rooms.clone());
+        let Ok(LinkMessage::Members {
+            seq: first, nicks, ..
+        }) = rx.try_recv()
+        else {
+            panic!("expected a member list");
+        };
+        assert_eq!(nicks, vec!["alice"]);
+
+        // nothing is sent when nothing changed
+        federation.set_local(rooms);
+        assert!(rx.try_recv().is_err());
+
+        // an emptied room is sent as such, with a newer seq
+        federation.set_local(BTreeMap::new());
+        let Ok(LinkMessage::Members { seq, nicks, .. }) = rx.try_recv() else {
+            panic!("expected a member list");
+        };
+        assert!(seq > first);
+        assert!(nicks.is_empty());
+    }
+
+    #[test]
+    fn test_links() {
+        let mut federation = Federation::new(String::from("a"));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(federation
+            .link_up(1, String::from("a"), tx.clone())
+            .is_err());
+        federation.link_up(1, String::from("b"), tx).unwrap();
+
+        assert!(federation.first_sighting("b:1"));
+        assert!(!federation.first_sighting("b:1"));
+
+        federation.apply(1, String::from("c"), 1, String::from("#general"), vec![]);
+        assert_eq!(
+            federation.link_down(1),
+            Some((String::from("b"), vec![String::from("c")]))
+        );
+        assert_eq!(federation.link_down(1), None);
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use protocol::{ClientMessage, ServerMessage, MAX_CHUNK_SIZE};
+use sha2::{Digest, Sha256};
+
+/// Chunks sent ahead of the server's acks. Enough to keep the link busy,
+/// few enough that chat never waits behind much file data.
+const WINDOW: u64 = 4;
+
+struct Upload {
+    name: String,
+    data: Vec<u8>,
+    sent: usize,
+    shown: u64,
+}
+
+struct Offer {
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+struct Download {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    sha256: String,
+    hasher: Sha256,
+    received: u64,
+    shown: u64,
+}
+
+/// The files this client is sending or receiving. The server tells both ends
+/// what happens to a transfer, and everything here follows from that.
+#[derive(Default)]
+pub struct Files {
+    // read in and offered, but not yet given an id by the server
+    unoffered: Vec<Upload>,
+    uploads: HashMap<u64, Upload>,
+    offers: HashMap<u64, Offer>,
+    downloads: HashMap<u64, Download>,
+}
+
+impl Files {
+    /// Reads in the file at `path` and offers it to `to`.
+    pub fn send(&mut self, to: &str, path: &str) -> Result<ClientMessage, String> {
+        let path = PathBuf::from(path);
+        let data =
+            fs::read(&path).map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| format!("{} is not a file", path.display()))?;
+
+        let msg = ClientMessage::Offer {
+            to: to.to_string(),
+            name: name.clone(),
+            size: data.len() as u64,
+            sha256: hex(&Sha256::digest(&data)),
+        };
+        self.unoffered.push(Upload {
+            name,
+            data,
+            sent: 0,
+            shown: 0,
+        });
+
+        Ok(msg)
+    }
+
+    pub fn accept(&self, id: u64) -> Result<ClientMessage, String> {
+        match self.offers.contains_key(&id) {
+            true => Ok(ClientMessage::Accept { id }),
+            false => Err(format!("nothing was offered as transfer {}", id)),
+        }
+    }
+
+    pub fn decline(&mut self, id: u64) -> Result<ClientMessage, String> {
+        if self.offers.remove(&id).is_none() && !self.downloads.contains_key(&id) {
+            return Err(format!("nothing was offered as transfer {}", id));
+        }
+        Ok(ClientMessage::Decline { id })
+    }
+
+    pub fn cancel(&self, id: u64) -> Result<ClientMessage, String> {
+        match self.uploads.contains_key(&id) {
+            true => Ok(ClientMessage::Cancel { id }),
+            false => Err(format!("you aren't sending transfer {}", id)),
+        }
+    }
+
+    /// Follows a transfer along from what the server says about it. Returns
+    /// the chunks to send next, if any.
+    pub fn handle(&mut self, msg: &ServerMessage) -> Vec<ClientMessage> {
+        match msg {
+            ServerMessage::Offered { id, name, size, .. } => {
+                let found = self
+                    .unoffered
+                    .iter()
+                    .position(|upload| upload.name == *name && upload.data.len() as u64 == *size);
+                if let Some(i) = found {
+                    self.uploads.insert(*id, self.unoffered.remove(i));
+                }
+                vec![]
+            }
+            ServerMessage::Offer {
+                id,
+                name,
+                size,
+                sha256,
+                ..
+            } => {
+                let offer = Offer {
+                    name: name.clone(),
+                    size: *size,
+                    sha256: sha256.clone(),
+                };
+                self.offers.insert(*id, offer);
+                vec![]
+            }
+            ServerMessage::Accepted { id, .. } => {
+                if let Some(offer) = self.offers.remove(id) {
+                    self.start_download(*id, offer);
+                    return vec![];
+                }
+                // the first accept starts the transfer, any later ones are
+                // refused by the server
+                match self.uploads.get_mut(id) {
+                    Some(upload) if upload.sent == 0 => (0..WINDOW)
+                        .filter_map(|_| next_chunk(*id, upload))
+                        .collect(),
+                    _ => vec![],
+                }
+            }
+            ServerMessage::Progress { id, received, size } => {
+                let Some(upload) = self.uploads.get_mut(id) else {
+                    return vec![];
+                };
+                upload.shown = show_progress(*id, *received, *size, upload.shown);
+                next_chunk(*id, upload).into_iter().collect()
+            }
+            ServerMessage::Chunk { id, data } => {
+                if let Err(err) = self.receive(*id, data) {
+                    println!("! transfer {} failed: {}", id, err);
+                    self.abandon(*id);
+                    return vec![ClientMessage::Decline { id: *id }];
+                }
+                vec![]
+            }
+            ServerMessage::Done { id } => {
+                self.uploads.remove(id);
+                if let Some(download) = self.downloads.remove(id) {
+                    finish_download(download);
+                }
+                vec![]
+            }
+            ServerMessage::Failed { id, .. } => {
+                self.uploads.remove(id);
+                self.offers.remove(id);
+                self.abandon(*id);
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn start_download(&mut self, id: u64, offer: Offer) {
+        match create_unique(&offer.name) {
+            Ok((path, file)) => {
+                println!("* saving transfer {} to {}", id, path.display());
+                let download = Download {
+                    path,
+                    file,
+                    size: offer.size,
+                    sha256: offer.sha256,
+                    hasher: Sha256::new(),
+                    received: 0,
+                    shown: 0,
+                };
+                self.downloads.insert(id, download);
+            }
+            Err(err) => println!("! can't save transfer {}: {}", id, err),
+        }
+    }
+
+    fn receive(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        let Some(download) = self.downloads.get_mut(&id) else {
+            return Ok(());
+        };
+
+        download.file.write_all(data)?;
+        download.hasher.update(data);
+        download.received += data.len() as u64;
+        download.shown = show_progress(id, download.received, download.size, download.shown);
+        Ok(())
+    }
+
+    /// Throws away whatever of a download has arrived.
+    fn abandon(&mut self, id: u64) {
+        if let Some(download) = self.downloads.remove(&id) {
+            let _ = fs::remove_file(download.path);
+        }
+    }
+}
+
+fn next_chunk(id: u64, upload: &mut Upload) -> Option<ClientMessage> {
+    if upload.sent >= upload.data.len() {
+        return None;
+    }
+
+    let end = (upload.sent + MAX_CHUNK_SIZE).min(upload.data.len());
+    let data = upload.data[upload.sent..end].to_vec();
+    upload.sent = end;
+    Some(ClientMessage::Chunk { id, data })
+}
+
+/// Prints every 10% of the way. Returns the last step shown.
+fn show_progress(id: u64, received: u64, size: u64, shown: u64) -> u64 {
+    let step = received * 10 / size.max(1);
+    if step > shown && step < 10 {
+        println!("* transfer {}: {}%", id, step * 10);
+    }
+    step.max(shown)
+}
+
+fn finish_download(mut download: Download) {
+    let digest = hex(&download.hasher.finalize_reset());
+    if download.received != download.size || digest != download.sha256 {
+        println!(
+            "! {} doesn't match what was offered, removed it",
+            download.path.display()
+        );
+        let _ = fs::remove_file(download.path);
+        return;
+    }
+
+    println!("* saved {}", download.path.display());
+}
+
+/// Opens a new file for `name` in the current directory, adding a number
+/// rather than overwriting anything.
+fn create_unique(name: &str) -> io::Result<(PathBuf, File)> {
+    let name = safe_name(name);
+
+    for n in 0.. {
+        let path = match n {
+            0 => PathBuf::from(&name),
+            n => PathBuf::from(format!("{}.{}", name, n)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!()
+}
+
+/// The sender picks the name, so it's kept to a plain file name here.
+fn safe_name(name: &str) -> String {
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+        .chars()
+        .filter(|&c| !c.is_control() && c != '\\')
+        .collect::<String>();
+
+    match name.trim_start_matches('.') {
+        "" => String::from("download"),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Running out of tokens is a strike. The third strike mutes the client and
+/// the fourth, muted or not, disconnects it.
+pub const MUTE_AT: u32 = 3;
+pub const DISCONNECT_AT: u32 = 4;
+
+/// Strikes are forgotten once a client has gone this long without one.
+pub const STRIKE_MEMORY: Duration = Duration::from_secs(10 * 60);
+
+/// Holds up to `burst` tokens and refills at `rate` tokens a second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+
+    pub fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Over the limit, but the client has already been told.
+    Drop,
+    /// Over the limit for the first time in a while.
+    Warn,
+    /// Over the limit once too often, nothing gets through for this long.
+    Mute(Duration),
+    /// Within the limit but still muted for this long.
+    Muted(Duration),
+    Disconnect,
+}
+
+/// Decides what happens to each message a client sends.
+#[derive(Debug)]
+pub struct FloodGuard {
+    bucket: TokenBucket,
+    mute: Duration,
+    muted_until: Option<Instant>,
+    // dropping messages since the last strike, which only counts once
+    flooding: bool,
+    strikes: u32,
+    last_strike: Option<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(rate: f64, burst: u32, mute: Duration, now: Instant) -> FloodGuard {
+        FloodGuard {
+            bucket: TokenBucket::new(rate, burst, now),
+            mute,
+            muted_until: None,
+            flooding: false,
+            strikes: 0,
+            last_strike: None,
+        }
+    }
+
+    pub fn check(&mut self, now: Instant) -> Verdict {
+        if self.bucket.take(now) {
+            self.flooding = false;
+
+            return match self.muted_until {
+                Some(until) if until > now => Verdict::Muted(until - now),
+                _ => Verdict::Allow,
+            };
+        }
+
+        if self.flooding {
+            return Verdict::Drop;
+        }
+        self.flooding = true;
+
+        if let Some(last) = self.last_strike {
+            if now.saturating_duration_since(last) > STRIKE_MEMORY {
+                self.strikes = 0;
+            }
+        }
+        self.strikes += 1;
+        self.last_strike = Some(now);
+
+        match self.strikes {
+            strikes if strikes >= DISCONNECT_AT => Verdict::Disconnect,
+            MUTE_AT => {
+                self.muted_until = Some(now + self.mute);
+                Verdict::Mute(self.mute)
+            }
+            _ => Verdict::Warn,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 3, start);
+
+        assert!((0..3).all(|_| bucket.take(start)));
+        assert!(!bucket.take(start));
+
+        // half a second buys one more message at two a second
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+
+        // and the bucket never holds more than the burst
+        let much_later = later + Duration::from_secs(60);
+        assert!((0..3).all(|_| bucket.take(much_later)));
+        assert!(!bucket.take(much_later));
+    }
+
+    /// Sends messages until one is refused, then waits long enough to be
+    /// allowed again, returning the first verdict that wasn't `Allow`.
+    fn flood(guard: &mut FloodGuard, now: &mut Instant) -> Verdict {
+        let verdict = loop {
+            match guard.check(*now) {
+                Verdict::Allow => (),
+                verdict => break verdict,
+            }
+        };
+        assert_eq!(guard.check(*now), Verdict::Drop);
+
+        *now += Duration::from_secs(1);
+        verdict
+    }
+
+    #[test]
+    fn test_escalation() {
+        let mut now = Instant::now();
+        let mute = Duration::from_secs(30);
+        let mut guard = FloodGuard::new(1.0, 5, mute, now);
+
+        assert_eq!(flood(&mut guard, &mut now), Verdict::Warn);
+        assert_eq!(flood(&mut guard, &mut now), Verdict::Warn);
+        assert_eq!(flood(&mut guard, &mut now), Verdict::Mute(mute));
+        assert_eq!(
+            guard.check(now),
+            Verdict::Muted(mute - Duration::from_secs(1))
+        );
+
+        now += mute;
+        assert_eq!(guard.check(now), Verdict::Allow);
+        assert_eq!(flood(&mut guard, &mut now), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn test_strikes_are_forgotten() {
+        let mut now = Instant::now();
+        let mut guard = FloodGuard::new(1.0, 5, Duration::from_secs(30), now);
+
+        assert_eq!(flood(&mut guard, &mut now), Verdict::Warn);
+        assert_eq!(flood(&mut guard, &mut now), Verdict::Warn);
+
+        now += STRIKE_MEMORY + Duration::from_secs(1);
+        assert_eq!(flood(&mut guard, &mut now), Verdict::Warn);
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bans::unix_now;
+
+/// One chat message as stored in a room's log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// Unique on this server, see `History::next_id`.
+    pub id: u64,
+    pub timestamp: u64,
+    pub nick: String,
+    pub text: String,
+    /// The ID a message relayed from another server goes by on the links.
+    pub origin: Option<String>,
+    /// When the text was last edited.
+    pub edited: Option<u64>,
+}
+
+impl Entry {
+    pub fn new(id: u64, nick: &str, text: &str) -> Entry {
+        Entry {
+            id,
+            timestamp: unix_now(),
+            nick: nick.to_string(),
+            text: text.to_string(),
+            origin: None,
+            edited: None,
+        }
+    }
+}
+
+/// A line of a room's log. Edits and deletions are appended like messages
+/// and applied as the log is read back.
+#[derive(Debug, Eq, PartialEq)]
+enum Record {
+    Message(Entry),
+    Edit { id: u64, time: u64, text: String },
+    Delete { id: u64, time: u64 },
+}
+
+impl Record {
+    fn to_line(&self) -> String {
+        match self {
+            Record::Message(entry) => format!(
+                "m\t{}\t{}\t{}\t{}\t{}\n",
+                entry.id,
+                entry.timestamp,
+                escape(entry.origin.as_deref().unwrap_or("")),
+                escape(&entry.nick),
+                escape(&entry.text)
+            ),
+            Record::Edit { id, time, text } => format!("e\t{}\t{}\t{}\n", id, time, escape(text)),
+            Record::Delete { id, time } => format!("d\t{}\t{}\n", id, time),
+        }
+    }
+
+    fn from_line(line: &str) -> Option<Record> {
+        let (kind, rest) = line.split_once('\t')?;
+
+        match kind {
+            "m" => {
+                let mut fields = rest.splitn(5, '\t');
+                Some(Record::Message(Entry {
+                    id: fields.next()?.parse().ok()?,
+                    timestamp: fields.next()?.parse().ok()?,
+                    origin: Some(unescape(fields.next()?)).filter(|origin| !origin.is_empty()),
+                    nick: unescape(fields.next()?),
+                    text: unescape(fields.next()?),
+                    edited: None,
+                }))
+            }
+            "e" => {
+                let mut fields = rest.splitn(3, '\t');
+                Some(Record::Edit {
+                    id: fields.next()?.parse().ok()?,
+                    time: fields.next()?.parse().ok()?,
+                    text: unescape(fields.next()?),
+                })
+            }
+            "d" => {
+                let mut fields = rest.splitn(2, '\t');
+                Some(Record::Delete {
+                    id: fields.next()?.parse().ok()?,
+                    time: fields.next()?.parse().ok()?,
+                })
+            }
+            // logs from before messages had IDs hold `timestamp nick text`
+            // lines, which are given one as they're read
+            timestamp => {
+                let mut fields = rest.splitn(2, '\t');
+                Some(Record::Message(Entry {
+                    id: 0,
+                    timestamp: timestamp.parse().ok()?,
+                    nick: unescape(fields.next()?),
+                    text: unescape(fields.next()?),
+                    origin: None,
+                    edited: None,
+                }))
+            }
+        }
+    }
+}
+
+/// Tabs and newlines separate fields and records, so they are escaped.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+
+    out
+}
+
+struct RoomLog {
+    entries: Vec<Entry>,
+    file: File,
+}
+
+impl RoomLog {
+    fn position(&self, id: u64) -> Option<usize> {
+        position(&self.entries, id)
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        self.file.write_all(record.to_line().as_bytes())
+    }
+}
+
+/// Append-only message logs, one file per room under `dir`. A room's log is
+/// read into memory the first time the room is touched.
+pub struct History {
+    dir: PathBuf,
+    rooms: HashMap<String, RoomLog>,
+    last_id: u64,
+}
+
+impl History {
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<History> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(History {
+            dir,
+            rooms: HashMap::new(),
+            last_id: 0,
+        })
+    }
+
+    fn log(&mut self, room: &str) -> io::Result<&mut RoomLog> {
+        if !self.rooms.contains_key(room) {
+            let path = self
+                .dir
+                .join(format!("{}.log", room.trim_start_matches('#')));
+
+            let records = match File::open(&path) {
+                Ok(file) => BufReader::new(file)
+                    .lines()
+                    .map_while(Result::ok)
+                    .filter_map(|line| Record::from_line(&line))
+                    .collect(),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => vec![],
+                Err(err) => return Err(err),
+            };
+            let entries = replay(records);
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+            if let Some(last) = entries.last() {
+                self.last_id = self.last_id.max(last.id);
+            }
+            self.rooms
+                .insert(room.to_string(), RoomLog { entries, file });
+        }
+
+        Ok(self.rooms.get_mut(room).expect("room log was just loaded"))
+    }
+
+    /// An ID for a new message, above every one handed out before. It is the
+    /// time in microseconds, so a restarted server carries on above the IDs
+    /// it gave out last time, at least as long as its clock doesn't go back.
+    pub fn next_id(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        self.last_id = now.max(self.last_id + 1);
+        self.last_id
+    }
+
+    pub fn append(&mut self, room: &str, entry: Entry) -> io::Result<()> {
+        let log = self.log(room)?;
+        log.write(&Record::Message(entry.clone()))?;
+        log.entries.push(entry);
+
+        Ok(())
+    }
+
+    pub fn get(&mut self, room: &str, id: u64) -> io::Result<Option<Entry>> {
+        let log = self.log(room)?;
+        Ok(log.position(id).map(|i| log.entries[i].clone()))
+    }
+
+    /// The message relayed from another server as `origin`.
+    pub fn by_origin(&mut self, room: &str, origin: &str) -> io::Result<Option<Entry>> {
+        let log = self.log(room)?;
+        let entry = log
+            .entries
+            .iter()
+            .rev()
+            .find(|entry| entry.origin.as_deref() == Some(origin));
+        Ok(entry.cloned())
+    }
+
+    /// Replaces a message's text, returning false if there is no such message.
+    pub fn edit(&mut self, room: &str, id: u64, text: &str) -> io::Result<bool> {
+        let log = self.log(room)?;
+        let Some(i) = log.position(id) else {
+            return Ok(false);
+        };
+
+        let time = unix_now();
+        log.write(&Record::Edit {
+            id,
+            time,
+            text: text.to_string(),
+        })?;
+        let entry = &mut log.entries[i];
+        entry.text = text.to_string();
+        entry.edited = Some(time);
+
+        Ok(true)
+    }
+
+    /// Removes a message, returning false if there is no such message.
+    pub fn delete(&mut self, room: &str, id: u64) -> io::Result<bool> {
+        let log = self.log(room)?;
+        let Some(i) = log.position(id) else {
+            return Ok(false);
+        };
+
+        log.write(&Record::Delete {
+            id,
+            time: unix_now(),
+        })?;
+        log.entries.remove(i);
+
+        Ok(true)
+    }
+
+    /// Up to `count` entries, oldest first: the most recent ones, or those
+    /// just older than message `before`. Going by ID rather than position
+    /// keeps pages from shifting as messages are sent or deleted between them.
+    pub fn page(
+        &mut self,
+        room: &str,
+        before: Option<u64>,
+        count: usize,
+    ) -> io::Result<Vec<Entry>> {
+        let before = before.unwrap_or(u64::MAX);
+        let older = self
+            .log(room)?
+            .entries
+            .iter()
+            .filter(|entry| entry.id < before)
+            .collect::<Vec<_>>();
+        let start = older.len().saturating_sub(count);
+
+        Ok(older[start..].iter().map(|&entry| entry.clone()).collect())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for log in self.rooms.values_mut() {
+            log.file.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The messages a log's records leave standing, in the order they were sent.
+fn replay(records: Vec<Record>) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+
+    for record in records {
+        match record {
+            Record::Message(mut entry) => {
+                // old lines get the ID a message sent that second would have
+                // had, kept in order by the ones before
+                if entry.id == 0 {
+                    let after = entries.last().map_or(0, |last| last.id + 1);
+                    entry.id = (entry.timestamp * 1_000_000).max(after);
+                }
+                entries.push(entry);
+            }
+            Record::Edit { id, time, text } => {
+                if let Some(i) = position(&entries, id) {
+                    entries[i].text = text;
+                    entries[i].edited = Some(time);
+                }
+            }
+            Record::Delete { id, .. } => {
+                if let Some(i) = position(&entries, id) {
+                    entries.remove(i);
+                }
+            }
+        }
+    }
+
+    entries
+}
+
+/// Searched from the end, since it's recent messages that get edited.
+fn position(entries: &[Entry], id: u64) -> Option<usize> {
+    entries.iter().rposition(|entry| entry.id == id)
+}
+
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "chat-{}-{}-{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_round_trip() {
+        let records = [
+            Record::Message(Entry {
+                id: 90061000001,
+                timestamp: 90061,
+                nick: String::from("alice"),
+                text: String::from("tab\there\nnew line \\n not a newline"),
+                origin: Some(String::from("berlin:42")),
+                edited: None,
+            }),
+            Record::Edit {
+                id: 90061000001,
+                time: 90062,
+                text: String::from("\ttab"),
+            },
+            Record::Delete {
+                id: 90061000001,
+                time: 90063,
+            },
+        ];
+
+        for record in records {
+            assert_eq!(Record::from_line(record.to_line().trim_end()), Some(record));
+        }
+    }
+
+    #[test]
+    fn test_survives_reopen() {
+        let dir = temp_dir("history");
+
+        let mut history = History::open(&dir).unwrap();
+        for i in 0..5 {
+            let id = history.next_id();
+            history
+                .append("#rust", Entry::new(id, "alice", &format!("msg {}", i)))
+                .unwrap();
+        }
+        drop(history);
+
+        let mut history = History::open(&dir).unwrap();
+        let texts = |entries: Vec<Entry>| entries.into_iter().map(|e| e.text).collect::<Vec<_>>();
+
+        let page = history.page("#rust", None, 2).unwrap();
+        assert_eq!(texts(page.clone()), vec!["msg 3", "msg 4"]);
+
+        // pages stay put as messages come and go in between
+        let id = history.next_id();
+        history
+            .append("#rust", Entry::new(id, "bob", "msg 5"))
+            .unwrap();
+        history.delete("#rust", page[1].id).unwrap();
+        let page = history.page("#rust", Some(page[0].id), 2).unwrap();
+        assert_eq!(texts(page.clone()), vec!["msg 1", "msg 2"]);
+
+        let page = history.page("#rust", Some(page[0].id), 2).unwrap();
+        assert_eq!(texts(page.clone()), vec!["msg 0"]);
+        assert!(history
+            .page("#rust", Some(page[0].id), 2)
+            .unwrap()
+            .is_empty());
+        assert!(history.page("#empty", None, 2).unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_edit_and_delete() {
+        let dir = temp_dir("history");
+        fs::create_dir_all(&dir).unwrap();
+        // a log from before messages had IDs
+        fs::write(dir.join("rust.log"), "90061\talice\tone\n90061\tbob\ttwo\n").unwrap();
+
+        let mut history = History::open(&dir).unwrap();
+        let old = history.page("#rust", None, 10).unwrap();
+        assert_eq!(old[0].id, 90061000000);
+        assert_eq!(old[1].id, 90061000001);
+
+        let id = history.next_id();
+        assert!(id > old[1].id);
+        assert!(history.next_id() > id);
+        history
+            .append("#rust", Entry::new(id, "alice", "thre"))
+            .unwrap();
+
+        assert!(history.edit("#rust", id, "three").unwrap());
+        assert!(history.delete("#rust", old[1].id).unwrap());
+        assert!(!history.delete("#rust", old[1].id).unwrap());
+        assert!(!history.edit("#rust", 1, "nothing").unwrap());
+        drop(history);
+
+        let mut history = History::open(&dir).unwrap();
+        let entries = history.page("#rust", None, 10).unwrap();
+        let texts = entries.iter().map(|e| e.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["one", "three"]);
+        assert_eq!(entries[0].edited, None);
+        assert!(entries[1].edited.is_some());
+        assert_eq!(history.get("#rust", id).unwrap(), Some(entries[1].clone()));
+        assert!(history.next_id() > id);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
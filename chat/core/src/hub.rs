@@ -0,0 +1,2909 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use protocol::{ClientMessage, ErrorCode, LinkMessage, ServerMessage};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::accounts::{hash_password, verify_password, Accounts, GuestPolicy};
+use crate::bans::{unix_now, Ban, Bans, Target};
+use crate::commands::{validate_nick, Command, MessageRef};
+use crate::config::Config;
+use crate::connection::Peer;
+use crate::federation::{remote_nick, Federation, LinkId};
+use crate::history::{Entry, History};
+use crate::log;
+use crate::metrics::{self, METRICS};
+use crate::queue::{self, SendError};
+use crate::rooms::{normalize_room, Rooms, DEFAULT_ROOM};
+use crate::transfers::{validate_offer, Transfer, Transfers, MAX_TRANSFERS};
+
+pub type ClientId = u64;
+
+/// How many messages a client is sent when joining a room, and how many more
+/// each `/history` goes back.
+pub const HISTORY_PAGE: usize = 20;
+
+const GUESTS_DENIED: &str = "guests may not chat here, /register or /login first";
+
+/// Everything the connection tasks report back to the hub. The hub is the only
+/// place that knows about every client, so all routing decisions happen here.
+#[derive(Debug)]
+pub enum Event {
+    Connected {
+        id: ClientId,
+        peer: Peer,
+        tx: queue::Sender,
+    },
+    Message {
+        id: ClientId,
+        msg: ClientMessage,
+    },
+    Disconnected {
+        id: ClientId,
+        reason: String,
+    },
+    /// The outcome of password hashing done off the hub task.
+    Auth {
+        id: ClientId,
+        result: Result<Auth, String>,
+    },
+    /// Another server has said hello, see `links`.
+    LinkUp {
+        link: LinkId,
+        name: String,
+        tx: UnboundedSender<LinkMessage>,
+    },
+    Link {
+        link: LinkId,
+        msg: LinkMessage,
+    },
+    LinkDown {
+        link: LinkId,
+        reason: String,
+    },
+    /// Tell everyone, flush history and let go of every client. The hub
+    /// stops once this has been handled.
+    Shutdown {
+        reason: Option<String>,
+    },
+}
+
+#[derive(Debug)]
+pub enum Auth {
+    Login { nick: String },
+    Register { nick: String, hash: String },
+    Passwd { nick: String, hash: String },
+}
+
+struct Client {
+    peer: Peer,
+    nick: Option<String>,
+    // logged in to the registered account matching `nick`
+    account: bool,
+    // the room plain chat text goes to
+    active: Option<String>,
+    // room -> the oldest message this client has been shown, for /history
+    // to go back from
+    oldest: HashMap<String, u64>,
+    // last time the client sent anything, for /who
+    last_active: Instant,
+    // message ID -> room, for what the client said since connecting
+    sent: BTreeMap<u64, String>,
+    // file chunks sent for transfers the client may not send, which the
+    // connection can't tell from good ones
+    violations: u32,
+    tx: queue::Sender,
+}
+
+pub struct Hub {
+    clients: HashMap<ClientId, Client>,
+    // keyed by lowercased nickname so "Alice" and "alice" collide
+    nicks: HashMap<String, ClientId>,
+    rooms: Rooms,
+    transfers: Transfers,
+    max_file_size: u64,
+    max_violations: u32,
+    // relayed chat text is held to what a client here may send
+    max_message_size: usize,
+    history: History,
+    accounts: Accounts,
+    // shared with the accept loops, which turn banned addresses away
+    bans: Arc<Mutex<Bans>>,
+    // lowercased nicknames of the server operators' accounts
+    operators: Vec<String>,
+    guests: GuestPolicy,
+    motd: Option<String>,
+    // only when linked to other servers
+    federation: Option<Federation>,
+    // lets slow work done elsewhere report back as an event
+    events: UnboundedSender<Event>,
+}
+
+impl Hub {
+    pub fn new(
+        config: &Config,
+        history: History,
+        accounts: Accounts,
+        bans: Arc<Mutex<Bans>>,
+        events: UnboundedSender<Event>,
+    ) -> Hub {
+        Hub {
+            clients: HashMap::new(),
+            nicks: HashMap::new(),
+            rooms: Rooms::default(),
+            transfers: Transfers::default(),
+            max_file_size: config.max_file_size,
+            max_violations: config.max_violations,
+            max_message_size: config.max_message_size,
+            history,
+            accounts,
+            bans,
+            operators: config.operators.clone(),
+            guests: config.guests,
+            motd: config.motd.clone(),
+            federation: match config.link_bind.is_empty() && config.links.is_empty() {
+                true => None,
+                false => config.server_name.clone().map(Federation::new),
+            },
+            events,
+        }
+    }
+
+    pub fn handle(&mut self, event: Event) {
+        // chat text, file data and news from other servers leave this
+        // server's rooms as they were, anything else may not
+        let members_changed = !matches!(
+            event,
+            Event::Message {
+                msg: ClientMessage::Chat { .. }
+                    | ClientMessage::Edit { .. }
+                    | ClientMessage::Delete { .. }
+                    | ClientMessage::Chunk { .. }
+                    | ClientMessage::Ping
+                    | ClientMessage::Pong,
+                ..
+            } | Event::Link { .. }
+        );
+
+        match event {
+            Event::Connected { id, peer, tx } => {
+                self.clients.insert(
+                    id,
+                    Client {
+                        peer,
+                        nick: None,
+                        account: false,
+                        active: Some(DEFAULT_ROOM.to_string()),
+                        oldest: HashMap::new(),
+                        last_active: Instant::now(),
+                        sent: BTreeMap::new(),
+                        violations: 0,
+                        tx,
+                    },
+                );
+                self.rooms.join(DEFAULT_ROOM, id);
+                self.notice(id, "welcome! pick a nickname with /nick <name>");
+                if let Some(motd) = &self.motd {
+                    self.notice(id, motd);
+                }
+                self.replay(id, DEFAULT_ROOM);
+            }
+            Event::Message { id, msg } => self.message(id, msg),
+            Event::Disconnected { id, reason } => self.disconnect(id, &reason),
+            Event::Auth { id, result } => self.auth_done(id, result),
+            Event::LinkUp { link, name, tx } => self.link_up(link, name, tx),
+            Event::Link { link, msg } => self.link_message(link, msg),
+            Event::LinkDown { link, reason } => self.link_down(link, &reason),
+            Event::Shutdown { reason } => self.shutdown(reason),
+        }
+
+        if members_changed {
+            self.share_members();
+        }
+
+        metrics::set(&METRICS.clients, self.clients.len());
+        metrics::set(&METRICS.rooms, self.rooms.count());
+    }
+
+    /// Whether the client's nickname is muted in `room`.
+    fn is_muted(&self, room: &str, id: ClientId) -> bool {
+        match self.nick(id) {
+            Some(nick) => self.rooms.is_muted(room, &nick, Instant::now()),
+            None => false,
+        }
+    }
+
+    /// Whether the client is allowed to send chat text or private messages.
+    fn may_chat(&self, id: ClientId) -> bool {
+        match self.clients.get(&id) {
+            Some(client) => client.account || self.guests == GuestPolicy::Allow,
+            None => false,
+        }
+    }
+
+    fn message(&mut self, id: ClientId, msg: ClientMessage) {
+        metrics::add(&METRICS.messages_received, 1);
+
+        let (room, text) = match msg {
+            ClientMessage::Chat { room, text } => (room, text),
+            ClientMessage::Command { name, args } => return self.command(id, &name, &args),
+            ClientMessage::Edit {
+                room,
+                id: message,
+                text,
+            } => return self.edit(id, room, MessageRef::Id(message), text),
+            ClientMessage::Delete { room, id: message } => {
+                return self.delete(id, room, MessageRef::Id(message))
+            }
+            ClientMessage::Offer {
+                to,
+                name,
+                size,
+                sha256,
+            } => return self.offer(id, &to, name, size, &sha256),
+            ClientMessage::Accept { id: transfer } => return self.accept(id, transfer),
+            ClientMessage::Decline { id: transfer } => return self.decline(id, transfer),
+            ClientMessage::Cancel { id: transfer } => return self.cancel(id, transfer),
+            ClientMessage::Chunk { id: transfer, data } => return self.chunk(id, transfer, data),
+            ClientMessage::Ping => return self.send(id, ServerMessage::Pong),
+            ClientMessage::Pong => return,
+        };
+
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        client.last_active = Instant::now();
+        log!("{}: {:?}", client.peer, text);
+
+        let Some((nick, room)) = self.speaking_in(id, room) else {
+            return;
+        };
+
+        let message = self.history.next_id();
+        let entry = Entry::new(message, &nick, &text);
+        let time = entry.timestamp;
+        if let Err(err) = self.history.append(&room, entry) {
+            log!("failed to write history for {}: {}", room, err);
+        }
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.sent.insert(message, room.clone());
+        }
+
+        if let Some(federation) = &self.federation {
+            let msg = LinkMessage::Chat {
+                id: federation.message_id(message),
+                server: federation.name().to_string(),
+                room: room.clone(),
+                from: nick.clone(),
+                text: text.clone(),
+                time,
+            };
+            federation.broadcast(None, &msg);
+        }
+
+        let msg = ServerMessage::Chat {
+            id: message,
+            room: room.clone(),
+            from: nick,
+            text,
+            time,
+            replayed: false,
+            edited: false,
+        };
+        self.send_room(&room, &msg);
+    }
+
+    /// The client's nickname and the room it's saying something in, `room`
+    /// or else its active one, if it may. Otherwise tells it why not.
+    fn speaking_in(&self, id: ClientId, room: Option<String>) -> Option<(String, String)> {
+        let client = self.clients.get(&id)?;
+
+        let Some(nick) = client.nick.clone() else {
+            self.error(id, "pick a nickname with /nick <name> before chatting");
+            return None;
+        };
+        let room = match room.map(|room| normalize_room(&room)) {
+            Some(Ok(room)) if self.rooms.rooms_of(id).contains(&room) => room,
+            Some(Ok(room)) => {
+                self.error(id, format!("you are not in {}", room));
+                return None;
+            }
+            Some(Err(err)) => {
+                self.error(id, err);
+                return None;
+            }
+            None => match &client.active {
+                Some(room) => room.clone(),
+                None => {
+                    self.error(id, "you are not in any room, /join one first");
+                    return None;
+                }
+            },
+        };
+        if !self.may_chat(id) {
+            self.error(id, GUESTS_DENIED);
+            return None;
+        }
+        if self.is_muted(&room, id) {
+            self.error(id, format!("you are muted in {}", room));
+            return None;
+        }
+
+        Some((nick, room))
+    }
+
+    /// Looks up a message for its author to edit or delete, returning the
+    /// author's nickname, the room and the message's ID.
+    fn own_message(
+        &mut self,
+        id: ClientId,
+        room: Option<String>,
+        message: MessageRef,
+    ) -> Option<(String, String, u64)> {
+        let (nick, room) = self.speaking_in(id, room)?;
+        let client = self.clients.get(&id)?;
+
+        let message = match message {
+            MessageRef::Id(message) => message,
+            MessageRef::Last => {
+                let last = client.sent.iter().rev().find(|(_, sent)| **sent == room);
+                match last {
+                    Some((&message, _)) => message,
+                    None => {
+                        self.error(id, format!("you haven't said anything in {}", room));
+                        return None;
+                    }
+                }
+            }
+        };
+        // a guest's nickname may have been someone else's before, so guests
+        // only get to change what they said since connecting
+        let sent_here = client.sent.contains_key(&message);
+        let account = client.account;
+
+        let entry = match self.history.get(&room, message) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                self.error(id, format!("there is no message {} in {}", message, room));
+                return None;
+            }
+            Err(err) => {
+                log!("failed to read history for {}: {}", room, err);
+                self.error(id, "history is unavailable, try again later");
+                return None;
+            }
+        };
+        let own = entry.origin.is_none()
+            && entry.nick.to_lowercase() == nick.to_lowercase()
+            && (account || sent_here);
+        if !own {
+            self.error(id, "you can only change your own messages");
+            return None;
+        }
+
+        Some((nick, room, message))
+    }
+
+    fn edit(&mut self, id: ClientId, room: Option<String>, message: MessageRef, text: String) {
+        let Some((nick, room, message)) = self.own_message(id, room, message) else {
+            return;
+        };
+
+        if let Err(err) = self.history.edit(&room, message, &text) {
+            log!("failed to write history for {}: {}", room, err);
+        }
+
+        if let Some(federation) = &self.federation {
+            let msg = LinkMessage::Edit {
+                id: federation.message_id(self.history.next_id()),
+                server: federation.name().to_string(),
+                room: room.clone(),
+                message: federation.message_id(message),
+                text: text.clone(),
+            };
+            federation.broadcast(None, &msg);
+        }
+
+        let msg = ServerMessage::Edited {
+            room: room.clone(),
+            id: message,
+            from: nick,
+            text,
+        };
+        self.send_room(&room, &msg);
+    }
+
+    fn delete(&mut self, id: ClientId, room: Option<String>, message: MessageRef) {
+        let Some((nick, room, message)) = self.own_message(id, room, message) else {
+            return;
+        };
+
+        if let Err(err) = self.history.delete(&room, message) {
+            log!("failed to write history for {}: {}", room, err);
+        }
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.sent.remove(&message);
+        }
+
+        if let Some(federation) = &self.federation {
+            let msg = LinkMessage::Delete {
+                id: federation.message_id(self.history.next_id()),
+                server: federation.name().to_string(),
+                room: room.clone(),
+                message: federation.message_id(message),
+            };
+            federation.broadcast(None, &msg);
+        }
+
+        let msg = ServerMessage::Deleted {
+            room: room.clone(),
+            id: message,
+            from: nick,
+        };
+        self.send_room(&room, &msg);
+    }
+
+    fn command(&mut self, id: ClientId, name: &str, args: &str) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        client.last_active = Instant::now();
+        log!(
+            "{}: /{} {:?}",
+            client.peer,
+            name,
+            Command::redact(name, args)
+        );
+
+        let cmd = match Command::parse(name, args) {
+            Ok(cmd) => cmd,
+            Err(err) => return self.error(id, err),
+        };
+        METRICS.command(name);
+
+        match cmd {
+            Command::Nick(nick) => self.set_nick(id, nick),
+            Command::Join(room) => self.join(id, &room),
+            Command::Part(room) => self.part(id, room),
+            Command::List => self.list(id),
+            Command::Who(room) => self.who(id, room),
+            Command::Names(room) => self.names(id, room),
+            Command::Topic { room, text } => self.topic(id, room, text),
+            Command::Msg { to, text } => self.private_message(id, &to, &text),
+            Command::History(room) => self.more_history(id, room),
+            Command::Edit { message, text } => self.edit(id, None, message, text),
+            Command::Delete(message) => self.delete(id, None, message),
+            Command::Login { nick, password } => self.login(id, nick, password),
+            Command::Register(password) => self.register(id, password),
+            Command::Passwd { old, new } => self.passwd(id, old, new),
+            Command::Kick { nick, room, reason } => self.kick(id, &nick, room, reason),
+            Command::Mute {
+                nick,
+                room,
+                duration,
+            } => self.mute(id, &nick, room, duration),
+            Command::Unmute { nick, room } => self.unmute(id, &nick, room),
+            Command::Op { nick, room } => self.op(id, &nick, room, true),
+            Command::Deop { nick, room } => self.op(id, &nick, room, false),
+            Command::Ban {
+                target,
+                duration,
+                reason,
+            } => self.ban(id, &target, duration, reason),
+            Command::Unban(target) => self.unban(id, &target),
+            Command::Bans => self.list_bans(id),
+        }
+    }
+
+    fn set_nick(&mut self, id: ClientId, nick: String) {
+        if let Err(err) = validate_nick(&nick) {
+            return self.error_code(id, ErrorCode::BadNick, err);
+        }
+
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+        let renaming_self = client
+            .nick
+            .as_ref()
+            .map(|current| current.to_lowercase() == nick.to_lowercase())
+            .unwrap_or(false);
+
+        if client.account && !renaming_self {
+            return self.error(id, "you are logged in, reconnect to use another nickname");
+        }
+        if !client.account && self.accounts.is_registered(&nick) {
+            return self.error_code(
+                id,
+                ErrorCode::NickInUse,
+                format!("{} is registered, use /login {} <password>", nick, nick),
+            );
+        }
+
+        self.assign_nick(id, nick);
+    }
+
+    /// Gives the client `nick` if nobody else holds it, announcing the change
+    /// to everyone who shares a room with the client. Returns false if taken.
+    fn assign_nick(&mut self, id: ClientId, nick: String) -> bool {
+        let banned = self.bans.lock().unwrap().nick(&nick, unix_now()).is_some();
+        if banned {
+            let text = format!("nickname {} is banned", nick);
+            self.error_code(id, ErrorCode::BadNick, text);
+            return false;
+        }
+
+        let key = nick.to_lowercase();
+        match self.nicks.get(&key) {
+            Some(&owner) if owner == id => (),
+            Some(_) => {
+                let text = format!("nickname {} is already taken", nick);
+                self.error_code(id, ErrorCode::NickInUse, text);
+                return false;
+            }
+            None => (),
+        }
+
+        let Some(client) = self.clients.get_mut(&id) else {
+            return false;
+        };
+        let old = client.nick.replace(nick.clone());
+
+        match old {
+            Some(old) => {
+                self.nicks.remove(&old.to_lowercase());
+                self.nicks.insert(key, id);
+                self.rooms.rename(&old, &nick);
+                let peers = self.rooms.peers(id);
+                let msg = ServerMessage::Nick {
+                    old: Some(old),
+                    new: nick,
+                };
+                self.send_all(peers, &msg);
+            }
+            None => {
+                self.nicks.insert(key, id);
+                let msg = ServerMessage::Nick {
+                    old: None,
+                    new: nick.clone(),
+                };
+                self.send(id, msg);
+
+                // until now nobody could be told who this was
+                for room in self.rooms.rooms_of(id) {
+                    let msg = ServerMessage::Join {
+                        room: room.clone(),
+                        nick: nick.clone(),
+                    };
+                    self.announce(&room, id, &msg);
+                }
+            }
+        }
+
+        true
+    }
+
+    fn login(&mut self, id: ClientId, nick: String, password: String) {
+        if let Err(err) = validate_nick(&nick) {
+            return self.error(id, err);
+        }
+        if self.clients.get(&id).map(|c| c.account).unwrap_or(true) {
+            return self.error(id, "you are already logged in");
+        }
+
+        let Some(hash) = self.accounts.hash(&nick).map(String::from) else {
+            return self.error(id, format!("no account named {}, /register it first", nick));
+        };
+
+        self.spawn_auth(id, move || {
+            if verify_password(&password, &hash) {
+                Ok(Auth::Login { nick })
+            } else {
+                Err(format!("wrong password for {}", nick))
+            }
+        });
+    }
+
+    fn register(&mut self, id: ClientId, password: String) {
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+        let Some(nick) = client.nick.clone() else {
+            return self.error(
+                id,
+                "pick a nickname with /nick <name> before registering it",
+            );
+        };
+        if client.account || self.accounts.is_registered(&nick) {
+            return self.error(id, format!("{} is already registered", nick));
+        }
+
+        self.spawn_auth(id, move || {
+            let hash = hash_password(&password)?;
+            Ok(Auth::Register { nick, hash })
+        });
+    }
+
+    fn passwd(&mut self, id: ClientId, old: String, new: String) {
+        let Some(nick) = self
+            .clients
+            .get(&id)
+            .filter(|client| client.account)
+            .and_then(|client| client.nick.clone())
+        else {
+            return self.error(id, "you are not logged in");
+        };
+        let Some(hash) = self.accounts.hash(&nick).map(String::from) else {
+            return self.error(id, "you are not logged in");
+        };
+
+        self.spawn_auth(id, move || {
+            if !verify_password(&old, &hash) {
+                return Err(String::from("wrong password"));
+            }
+
+            let hash = hash_password(&new)?;
+            Ok(Auth::Passwd { nick, hash })
+        });
+    }
+
+    /// Password hashing takes long enough to stall every other client, so it
+    /// runs on the blocking pool and comes back as an `Event::Auth`.
+    fn spawn_auth<F>(&self, id: ClientId, job: F)
+    where
+        F: FnOnce() -> Result<Auth, String> + Send + 'static,
+    {
+        let events = self.events.clone();
+        tokio::task::spawn_blocking(move || {
+            let _ = events.send(Event::Auth { id, result: job() });
+        });
+    }
+
+    fn auth_done(&mut self, id: ClientId, result: Result<Auth, String>) {
+        if !self.clients.contains_key(&id) {
+            return;
+        }
+
+        match result {
+            Err(err) => self.error(id, err),
+            Ok(Auth::Login { nick }) => {
+                if let Some(&owner) = self.nicks.get(&nick.to_lowercase()) {
+                    if owner != id {
+                        return self.error(id, format!("{} is already logged in", nick));
+                    }
+                }
+
+                if self.assign_nick(id, nick.clone()) {
+                    if let Some(client) = self.clients.get_mut(&id) {
+                        client.account = true;
+                    }
+                    self.reply(id, "login", format!("logged in as {}", nick));
+                }
+            }
+            Ok(Auth::Register { nick, hash }) => {
+                let current = self.clients.get(&id).and_then(|c| c.nick.clone());
+                if current.as_ref() != Some(&nick) || self.accounts.is_registered(&nick) {
+                    return self.error(id, format!("could not register {}", nick));
+                }
+
+                if let Err(err) = self.accounts.set(&nick, hash) {
+                    log!("failed to save accounts: {}", err);
+                    return self.error(id, "could not save your account, try again later");
+                }
+                if let Some(client) = self.clients.get_mut(&id) {
+                    client.account = true;
+                }
+                self.reply(
+                    id,
+                    "register",
+                    format!("registered {}, you are logged in", nick),
+                );
+            }
+            Ok(Auth::Passwd { nick, hash }) => {
+                if let Err(err) = self.accounts.set(&nick, hash) {
+                    log!("failed to save accounts: {}", err);
+                    return self.error(id, "could not save your account, try again later");
+                }
+                self.reply(id, "passwd", "password changed");
+            }
+        }
+    }
+
+    fn join(&mut self, id: ClientId, room: &str) {
+        let room = match normalize_room(room) {
+            Ok(room) => room,
+            Err(err) => return self.error(id, err),
+        };
+
+        // whoever creates a room runs it, but the default room belongs to
+        // everyone and is left to the server operators
+        let created = self.rooms.members(&room).is_empty();
+        let joined = self.rooms.join(&room, id);
+        if created && room != DEFAULT_ROOM {
+            self.rooms.set_op(&room, id, true);
+        }
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.active = Some(room.clone());
+        }
+
+        if joined {
+            self.room_reply(id, "join", &room, format!("joined {}", room));
+            if let Some(nick) = self.nick(id) {
+                let msg = ServerMessage::Join {
+                    room: room.clone(),
+                    nick,
+                };
+                self.announce(&room, id, &msg);
+            }
+            self.replay(id, &room);
+        } else {
+            self.room_reply(id, "join", &room, format!("now talking in {}", room));
+        }
+    }
+
+    /// Sends the latest page of a room's history, as on joining it.
+    fn replay(&mut self, id: ClientId, room: &str) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.oldest.remove(room);
+        }
+
+        self.send_history(id, room);
+    }
+
+    fn more_history(&mut self, id: ClientId, room: Option<String>) {
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+
+        let room = match room.map(|room| normalize_room(&room)) {
+            Some(Ok(room)) => room,
+            Some(Err(err)) => return self.error(id, err),
+            None => match &client.active {
+                Some(room) => room.clone(),
+                None => return self.error(id, "you are not in any room"),
+            },
+        };
+
+        if !self.rooms.rooms_of(id).contains(&room) {
+            return self.error(id, format!("you are not in {}", room));
+        }
+
+        if self.send_history(id, &room) == 0 {
+            let text = format!("no older messages in {}", room);
+            self.room_reply(id, "history", &room, text);
+        }
+    }
+
+    /// Sends the page of history just older than what the client has already
+    /// seen, returning how many messages were sent.
+    fn send_history(&mut self, id: ClientId, room: &str) -> usize {
+        let Some(client) = self.clients.get(&id) else {
+            return 0;
+        };
+        let before = client.oldest.get(room).copied();
+
+        let entries = match self.history.page(room, before, HISTORY_PAGE) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log!("failed to read history for {}: {}", room, err);
+                return 0;
+            }
+        };
+        let count = entries.len();
+        // with nothing to show, everything since came in live, so there is
+        // nothing older for /history either
+        let oldest = entries.first().map_or(0, |entry| entry.id);
+
+        for entry in entries {
+            self.send(
+                id,
+                ServerMessage::Chat {
+                    id: entry.id,
+                    room: room.to_string(),
+                    from: entry.nick,
+                    text: entry.text,
+                    time: entry.timestamp,
+                    replayed: true,
+                    edited: entry.edited.is_some(),
+                },
+            );
+        }
+
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.oldest.insert(room.to_string(), oldest);
+        }
+
+        count
+    }
+
+    fn part(&mut self, id: ClientId, room: Option<String>) {
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+
+        let room = match room.map(|room| normalize_room(&room)) {
+            Some(Ok(room)) => room,
+            Some(Err(err)) => return self.error(id, err),
+            None => match &client.active {
+                Some(room) => room.clone(),
+                None => return self.error(id, "you are not in any room"),
+            },
+        };
+
+        if !self.rooms.part(&room, id) {
+            return self.error(id, format!("you are not in {}", room));
+        }
+        self.room_reply(id, "part", &room, format!("left {}", room));
+        if let Some(nick) = self.nick(id) {
+            let msg = ServerMessage::Leave {
+                room: room.clone(),
+                nick,
+                reason: None,
+            };
+            self.announce(&room, id, &msg);
+        }
+
+        self.left(id, &room);
+    }
+
+    /// Moves the client on to another of its rooms if it was talking in the
+    /// one it just left.
+    fn left(&mut self, id: ClientId, room: &str) {
+        let active = self.rooms.rooms_of(id).into_iter().next();
+        if let Some(client) = self.clients.get_mut(&id) {
+            if client.active.as_deref() == Some(room) {
+                client.active = active;
+            }
+        }
+    }
+
+    fn list(&self, id: ClientId) {
+        let rooms = self.rooms.list();
+        if rooms.is_empty() {
+            return self.reply(id, "list", "there are no rooms");
+        }
+
+        let mut text = String::from("rooms:");
+        for (room, count) in rooms {
+            text.push_str(&format!("\n  {} ({})", room, count));
+        }
+
+        self.reply(id, "list", text);
+    }
+
+    fn private_message(&mut self, id: ClientId, to: &str, text: &str) {
+        let Some(nick) = self.clients.get(&id).and_then(|client| client.nick.clone()) else {
+            return self.error(id, "pick a nickname with /nick <name> before chatting");
+        };
+        if !self.may_chat(id) {
+            return self.error(id, GUESTS_DENIED);
+        }
+
+        let Some(&recipient) = self.nicks.get(&to.to_lowercase()) else {
+            return self.error(id, format!("no such user {}", to));
+        };
+
+        let msg = ServerMessage::Private {
+            from: nick,
+            to: self.nick(recipient).unwrap_or_else(|| to.to_string()),
+            text: text.to_string(),
+        };
+        let delivered = match self.clients.get(&recipient) {
+            Some(client) => deliver(client, msg.clone()),
+            None => Err(SendError::Closed),
+        };
+
+        match delivered {
+            Ok(()) => self.send(id, msg),
+            Err(err) => {
+                self.disconnect(recipient, gone_reason(err));
+                self.error(id, format!("{} is offline", to));
+            }
+        }
+    }
+
+    /// Offers a file to a user, or to everyone else in a room the sender is
+    /// in. Nothing is sent until someone accepts.
+    fn offer(&mut self, id: ClientId, to: &str, name: String, size: u64, sha256: &str) {
+        let Some(nick) = self.nick(id) else {
+            return self.error(id, "pick a nickname with /nick <name> before sending files");
+        };
+        if !self.may_chat(id) {
+            return self.error(id, GUESTS_DENIED);
+        }
+        if let Err(err) = validate_offer(&name, size, sha256, self.max_file_size) {
+            return self.error(id, err);
+        }
+        if self.transfers.sent_by(id).len() >= MAX_TRANSFERS {
+            return self.error(
+                id,
+                format!("you may only offer {} files at a time", MAX_TRANSFERS),
+            );
+        }
+
+        let (to, recipients) = if to.starts_with('#') {
+            let room = match normalize_room(to) {
+                Ok(room) => room,
+                Err(err) => return self.error(id, err),
+            };
+            if !self.rooms.rooms_of(id).contains(&room) {
+                return self.error(id, format!("you are not in {}", room));
+            }
+            if self.is_muted(&room, id) {
+                return self.error(id, format!("you are muted in {}", room));
+            }
+            let members = self
+                .rooms
+                .members(&room)
+                .into_iter()
+                .filter(|&member| member != id && self.nick(member).is_some())
+                .collect::<BTreeSet<_>>();
+            if members.is_empty() {
+                return self.error(id, format!("nobody else is in {}", room));
+            }
+            (room, members)
+        } else {
+            match self.nicks.get(&to.to_lowercase()) {
+                Some(&recipient) if recipient == id => {
+                    return self.error(id, "you can't send files to yourself")
+                }
+                Some(&recipient) => (
+                    self.nick(recipient).unwrap_or_else(|| to.to_string()),
+                    BTreeSet::from([recipient]),
+                ),
+                None => return self.error(id, format!("no such user {}", to)),
+            }
+        };
+
+        log!("{}: offering {:?} ({} bytes) to {}", nick, name, size, to);
+        let transfer = Transfer::new(id, name.clone(), size, sha256, recipients.clone());
+        let transfer = self.transfers.add(transfer);
+
+        let msg = ServerMessage::Offer {
+            id: transfer,
+            from: nick,
+            to: to.clone(),
+            name: name.clone(),
+            size,
+            sha256: sha256.to_lowercase(),
+        };
+        self.send_all(recipients, &msg);
+        let msg = ServerMessage::Offered {
+            id: transfer,
+            to,
+            name,
+            size,
+        };
+        self.send(id, msg);
+    }
+
+    /// Once the first chunk is on its way it's too late to accept: the
+    /// earlier part of the file is never sent twice.
+    fn accept(&mut self, id: ClientId, transfer: u64) {
+        let Some(nick) = self.nick(id) else {
+            return;
+        };
+        let Some(t) = self
+            .transfers
+            .get_mut(transfer)
+            .filter(|t| t.pending.contains(&id))
+        else {
+            return self.error(id, format!("no transfer {} is on offer to you", transfer));
+        };
+        if t.started() {
+            return self.error(id, format!("transfer {} has already started", transfer));
+        }
+
+        t.pending.remove(&id);
+        t.accepted.insert(id);
+        let from = t.from;
+
+        let msg = ServerMessage::Accepted { id: transfer, nick };
+        self.send_all([from, id], &msg);
+    }
+
+    /// Turns an offer down, or stops receiving a transfer under way.
+    fn decline(&mut self, id: ClientId, transfer: u64) {
+        let Some(nick) = self.nick(id) else {
+            return;
+        };
+        let Some(from) = self
+            .transfers
+            .get(transfer)
+            .filter(|t| t.recipients().any(|recipient| recipient == id))
+            .map(|t| t.from)
+        else {
+            return self.error(id, format!("no transfer {} is on offer to you", transfer));
+        };
+
+        let msg = ServerMessage::Declined { id: transfer, nick };
+        self.send_all([from, id], &msg);
+        self.drop_recipient(transfer, id);
+    }
+
+    fn cancel(&mut self, id: ClientId, transfer: u64) {
+        match self.transfers.get(transfer) {
+            Some(t) if t.from == id => {
+                self.fail_transfer(transfer, String::from("cancelled by the sender"))
+            }
+            _ => self.error(id, format!("you aren't sending transfer {}", transfer)),
+        }
+    }
+
+    /// Passes a chunk on to everyone who accepted, and acks it to the sender
+    /// with a `Progress`, which is the sender's cue to send another. Each
+    /// chunk is a single small event, so a transfer never holds up anything
+    /// else the hub has to do.
+    fn chunk(&mut self, id: ClientId, transfer: u64, data: Vec<u8>) {
+        let Some(t) = self.transfers.get_mut(transfer).filter(|t| t.from == id) else {
+            return self.violation(id, format!("you aren't sending transfer {}", transfer));
+        };
+        if t.accepted.is_empty() {
+            return self.violation(id, format!("nobody has accepted transfer {} yet", transfer));
+        }
+
+        // anyone still making up their mind has missed the start
+        let missed = std::mem::take(&mut t.pending);
+        let done = match t.receive(&data) {
+            Ok(done) => done,
+            Err(reason) => return self.fail_transfer(transfer, reason),
+        };
+        let (received, size) = (t.received, t.size);
+        let accepted = t.accepted.clone();
+
+        let msg = ServerMessage::Failed {
+            id: transfer,
+            reason: String::from("it started without you"),
+        };
+        self.send_all(missed, &msg);
+
+        let msg = ServerMessage::Chunk { id: transfer, data };
+        self.send_all(accepted.iter().copied(), &msg);
+        let msg = ServerMessage::Progress {
+            id: transfer,
+            received,
+            size,
+        };
+        self.send(id, msg);
+
+        if done {
+            if let Some(t) = self.transfers.remove(transfer) {
+                log!("transfer {} of {:?} is complete", transfer, t.name);
+            }
+            let msg = ServerMessage::Done { id: transfer };
+            self.send_all(accepted.into_iter().chain([id]), &msg);
+        }
+    }
+
+    /// Takes a recipient out of a transfer, which fails if that leaves nobody
+    /// to send it to.
+    fn drop_recipient(&mut self, transfer: u64, id: ClientId) {
+        let Some(t) = self.transfers.get_mut(transfer) else {
+            return;
+        };
+        t.pending.remove(&id);
+        t.accepted.remove(&id);
+
+        if t.recipients().next().is_none() {
+            self.fail_transfer(transfer, String::from("nobody is left to receive it"));
+        }
+    }
+
+    /// Ends a transfer, telling the sender and everyone still in it why.
+    fn fail_transfer(&mut self, transfer: u64, reason: String) {
+        let Some(t) = self.transfers.remove(transfer) else {
+            return;
+        };
+        log!("transfer {} of {:?} failed: {}", transfer, t.name, reason);
+
+        let msg = ServerMessage::Failed {
+            id: transfer,
+            reason,
+        };
+        let ids = t.recipients().chain([t.from]).collect::<Vec<_>>();
+        self.send_all(ids, &msg);
+    }
+
+    fn shutdown(&mut self, reason: Option<String>) {
+        let text = match reason {
+            Some(reason) => format!("server is shutting down: {}", reason),
+            None => String::from("server is shutting down"),
+        };
+        let ids = self.clients.keys().copied().collect::<Vec<_>>();
+        self.send_all(ids, &ServerMessage::Notice { text });
+
+        if let Err(err) = self.history.flush() {
+            log!("failed to flush history: {}", err);
+        }
+
+        // dropping the senders lets each connection write out what's queued
+        // and then close
+        self.clients.clear();
+        self.nicks.clear();
+        if let Some(federation) = &mut self.federation {
+            federation.shutdown();
+        }
+    }
+
+    fn link_up(&mut self, link: LinkId, name: String, tx: UnboundedSender<LinkMessage>) {
+        let Some(federation) = &mut self.federation else {
+            return;
+        };
+
+        match federation.link_up(link, name.clone(), tx) {
+            Ok(()) => log!("linked to {}", name),
+            Err(err) => log!("refusing link to {}: {}", name, err),
+        }
+    }
+
+    fn link_message(&mut self, link: LinkId, msg: LinkMessage) {
+        let Some(federation) = &mut self.federation else {
+            return;
+        };
+
+        match msg {
+            LinkMessage::Members {
+                server,
+                seq,
+                room,
+                nicks,
+            } => {
+                let Some(room) = self.accepts(&server, &room, None, None) else {
+                    return log!("dropping members from {} for {}", server, room);
+                };
+                if nicks.iter().any(|nick| validate_nick(nick).is_err()) {
+                    return log!("dropping members from {} for {}", server, room);
+                }
+                let Some(federation) = &mut self.federation else {
+                    return;
+                };
+                let Some(change) =
+                    federation.apply(link, server.clone(), seq, room.clone(), nicks.clone())
+                else {
+                    return;
+                };
+                let msg = LinkMessage::Members {
+                    server: server.clone(),
+                    seq,
+                    room: room.clone(),
+                    nicks,
+                };
+                federation.broadcast(Some(link), &msg);
+
+                for nick in change.left {
+                    let msg = ServerMessage::Leave {
+                        room: room.clone(),
+                        nick: remote_nick(&nick, &server),
+                        reason: None,
+                    };
+                    self.send_room(&room, &msg);
+                }
+                for nick in change.joined {
+                    let msg = ServerMessage::Join {
+                        room: room.clone(),
+                        nick: remote_nick(&nick, &server),
+                    };
+                    self.send_room(&room, &msg);
+                }
+            }
+            LinkMessage::Chat {
+                id,
+                server,
+                room,
+                from,
+                text,
+                time,
+            } => {
+                if server == federation.name() || !federation.first_sighting(&id) {
+                    return;
+                }
+                let Some(room) = self.accepts(&server, &room, Some(&from), Some(&text)) else {
+                    return log!("dropping message from {} for {}", server, room);
+                };
+                let Some(federation) = &mut self.federation else {
+                    return;
+                };
+                let msg = LinkMessage::Chat {
+                    id: id.clone(),
+                    server: server.clone(),
+                    room: room.clone(),
+                    from: from.clone(),
+                    text: text.clone(),
+                    time,
+                };
+                federation.broadcast(Some(link), &msg);
+
+                let from = remote_nick(&from, &server);
+                let entry = Entry {
+                    id: self.history.next_id(),
+                    timestamp: time,
+                    nick: from.clone(),
+                    text: text.clone(),
+                    origin: Some(id),
+                    edited: None,
+                };
+                let msg = ServerMessage::Chat {
+                    id: entry.id,
+                    room: room.clone(),
+                    from,
+                    text,
+                    time,
+                    replayed: false,
+                    edited: false,
+                };
+                if let Err(err) = self.history.append(&room, entry) {
+                    log!("failed to write history for {}: {}", room, err);
+                }
+                self.send_room(&room, &msg);
+            }
+            LinkMessage::Edit {
+                id,
+                server,
+                room,
+                message,
+                text,
+            } => {
+                if server == federation.name() || !federation.first_sighting(&id) {
+                    return;
+                }
+                let Some(room) = self.accepts(&server, &room, None, Some(&text)) else {
+                    return log!("dropping edit from {} for {}", server, room);
+                };
+                // only the server a message came from may change it
+                if message.split_once(':').map(|(origin, _)| origin) != Some(server.as_str()) {
+                    return log!("dropping edit of {} from {}", message, server);
+                }
+                let Some(federation) = &mut self.federation else {
+                    return;
+                };
+                let msg = LinkMessage::Edit {
+                    id,
+                    server: server.clone(),
+                    room: room.clone(),
+                    message: message.clone(),
+                    text: text.clone(),
+                };
+                federation.broadcast(Some(link), &msg);
+
+                let Some((room, entry)) = self.relayed_message(room, &message) else {
+                    return;
+                };
+                if let Err(err) = self.history.edit(&room, entry.id, &text) {
+                    log!("failed to write history for {}: {}", room, err);
+                }
+                let msg = ServerMessage::Edited {
+                    room: room.clone(),
+                    id: entry.id,
+                    from: entry.nick,
+                    text,
+                };
+                self.send_room(&room, &msg);
+            }
+            LinkMessage::Delete {
+                id,
+                server,
+                room,
+                message,
+            } => {
+                if server == federation.name() || !federation.first_sighting(&id) {
+                    return;
+                }
+                let Some(room) = self.accepts(&server, &room, None, None) else {
+                    return log!("dropping delete from {} for {}", server, room);
+                };
+                // only the server a message came from may change it
+                if message.split_once(':').map(|(origin, _)| origin) != Some(server.as_str()) {
+                    return log!("dropping delete of {} from {}", message, server);
+                }
+                let Some(federation) = &mut self.federation else {
+                    return;
+                };
+                let msg = LinkMessage::Delete {
+                    id,
+                    server: server.clone(),
+                    room: room.clone(),
+                    message: message.clone(),
+                };
+                federation.broadcast(Some(link), &msg);
+
+                let Some((room, entry)) = self.relayed_message(room, &message) else {
+                    return;
+                };
+                if let Err(err) = self.history.delete(&room, entry.id) {
+                    log!("failed to write history for {}: {}", room, err);
+                }
+                let msg = ServerMessage::Deleted {
+                    room: room.clone(),
+                    id: entry.id,
+                    from: entry.nick,
+                };
+                self.send_room(&room, &msg);
+            }
+            LinkMessage::Lost { server } => {
+                // only news if that's the way this server was reached
+                if !federation.routed_via(link).contains(&server) {
+                    return;
+                }
+                federation.broadcast(
+                    Some(link),
+                    &LinkMessage::Lost {
+                        server: server.clone(),
+                    },
+                );
+                self.forget_server(&server);
+                self.resync();
+            }
+            LinkMessage::Sync => {
+                for msg in federation.snapshot() {
+                    federation.send(link, msg);
+                }
+            }
+            LinkMessage::Hello { .. } | LinkMessage::Ping => (),
+        }
+    }
+
+    /// The message relayed from `server` as `message`, as stored here. Only
+    /// its own server may change a message, on behalf of its author.
+    /// What another server relays is checked as if one of its users had
+    /// sent it here, before it is taken in or passed on: room names end up
+    /// naming history files. Returns the room, normalized.
+    fn accepts(
+        &self,
+        server: &str,
+        room: &str,
+        from: Option<&str>,
+        text: Option<&str>,
+    ) -> Option<String> {
+        validate_nick(server).ok()?;
+        if let Some(from) = from {
+            validate_nick(from).ok()?;
+        }
+        if let Some(text) = text {
+            if text.is_empty() || text.len() > self.max_message_size {
+                return None;
+            }
+        }
+        normalize_room(room).ok()
+    }
+
+    fn relayed_message(&mut self, room: String, message: &str) -> Option<(String, Entry)> {
+        match self.history.by_origin(&room, message) {
+            Ok(entry) => Some((room, entry?)),
+            Err(err) => {
+                log!("failed to read history for {}: {}", room, err);
+                None
+            }
+        }
+    }
+
+    fn link_down(&mut self, link: LinkId, reason: &str) {
+        let Some(federation) = &mut self.federation else {
+            return;
+        };
+        let Some((name, lost)) = federation.link_down(link) else {
+            return;
+        };
+        log!("link to {} closed ({})", name, reason);
+
+        for server in &lost {
+            federation.broadcast(
+                None,
+                &LinkMessage::Lost {
+                    server: server.clone(),
+                },
+            );
+        }
+        for server in &lost {
+            self.forget_server(server);
+        }
+        if !lost.is_empty() {
+            self.resync();
+        }
+    }
+
+    /// Tells everyone who shared a room with a server's members that they
+    /// are gone.
+    fn forget_server(&mut self, server: &str) {
+        let Some(federation) = &mut self.federation else {
+            return;
+        };
+
+        for (room, nicks) in federation.forget(server) {
+            for nick in nicks {
+                let msg = ServerMessage::Leave {
+                    room: room.clone(),
+                    nick: remote_nick(&nick, server),
+                    reason: Some(String::from("link lost")),
+                };
+                self.send_room(&room, &msg);
+            }
+        }
+    }
+
+    /// Asks every link for what it knows, to find any server that was
+    /// forgotten but can still be reached some other way.
+    fn resync(&self) {
+        if let Some(federation) = &self.federation {
+            federation.broadcast(None, &LinkMessage::Sync);
+        }
+    }
+
+    /// Tells linked servers about any change in who is in which room here.
+    fn share_members(&mut self) {
+        let Some(federation) = &mut self.federation else {
+            return;
+        };
+
+        let mut rooms = BTreeMap::new();
+        for (room, _) in self.rooms.list() {
+            let nicks = self
+                .rooms
+                .members(&room)
+                .iter()
+                .filter_map(|member| self.clients.get(member)?.nick.clone())
+                .collect::<BTreeSet<_>>();
+            if !nicks.is_empty() {
+                rooms.insert(room, nicks);
+            }
+        }
+
+        federation.set_local(rooms);
+    }
+
+    fn who(&self, id: ClientId, room: Option<String>) {
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+
+        let room = match room.map(|room| normalize_room(&room)) {
+            Some(Ok(room)) => room,
+            Some(Err(err)) => return self.error(id, err),
+            None => match &client.active {
+                Some(room) => room.clone(),
+                None => return self.error(id, "you are not in any room"),
+            },
+        };
+
+        let members = self.rooms.members(&room);
+        let remote = self.remote_members(&room);
+        if members.is_empty() && remote.is_empty() {
+            return self.error(id, format!("there is no room called {}", room));
+        }
+
+        let mut users = members
+            .iter()
+            .filter_map(|member| self.clients.get(member))
+            .filter_map(|member| Some((member.nick.as_ref()?, member.last_active.elapsed())))
+            .collect::<Vec<_>>();
+        users.sort_by_key(|(nick, _)| nick.to_lowercase());
+        let unnamed = members.len() - users.len();
+
+        let mut text = format!("{} users in {}:", users.len() + remote.len(), room);
+        for (nick, idle) in users {
+            text.push_str(&format!("\n  {} (idle {})", nick, format_duration(idle)));
+        }
+        for nick in remote {
+            text.push_str(&format!("\n  {}", nick));
+        }
+
+        if unnamed > 0 {
+            text.push_str(&format!("\n  and {} without a nickname", unnamed));
+        }
+
+        self.reply(id, "who", text);
+    }
+
+    fn names(&self, id: ClientId, room: Option<String>) {
+        let Some(room) = self.room_or_active(id, room) else {
+            return;
+        };
+
+        let members = self.rooms.members(&room);
+        let remote = self.remote_members(&room);
+        if members.is_empty() && remote.is_empty() {
+            return self.error(id, format!("there is no room called {}", room));
+        }
+
+        let mut nicks = members
+            .iter()
+            .filter_map(|member| self.nick(*member))
+            .chain(remote)
+            .collect::<Vec<_>>();
+        nicks.sort_by_key(|nick| nick.to_lowercase());
+
+        self.send(id, ServerMessage::Names { room, nicks });
+    }
+
+    /// Without `text`, tells the client what the topic is. With it, sets the
+    /// topic and tells everyone in the room who did.
+    fn topic(&mut self, id: ClientId, room: Option<String>, text: Option<String>) {
+        let Some(room) = self.room_or_active(id, room) else {
+            return;
+        };
+
+        let Some(text) = text else {
+            if self.rooms.members(&room).is_empty() {
+                return self.error(id, format!("there is no room called {}", room));
+            }
+            let msg = ServerMessage::Topic {
+                topic: self.rooms.topic(&room).map(String::from),
+                room,
+                nick: None,
+            };
+            return self.send(id, msg);
+        };
+
+        let Some(nick) = self.nick(id) else {
+            return self.error(id, "pick a nickname with /nick <name> first");
+        };
+        if !self.rooms.rooms_of(id).contains(&room) {
+            return self.error(id, format!("you are not in {}", room));
+        }
+        if !self.may_chat(id) {
+            return self.error(id, GUESTS_DENIED);
+        }
+        if self.is_muted(&room, id) {
+            return self.error(id, format!("you are muted in {}", room));
+        }
+
+        self.rooms.set_topic(&room, &text);
+        let msg = ServerMessage::Topic {
+            room: room.clone(),
+            topic: Some(text),
+            nick: Some(nick),
+        };
+        self.send_room(&room, &msg);
+    }
+
+    fn kick(&mut self, id: ClientId, nick: &str, room: Option<String>, reason: Option<String>) {
+        let Some((room, target, nick, by)) = self.moderate(id, nick, room) else {
+            return;
+        };
+        log!("{} kicked {} from {}", by, nick, room);
+
+        let msg = ServerMessage::Kick {
+            room: room.clone(),
+            nick,
+            by,
+            reason,
+        };
+        self.send_room(&room, &msg);
+        self.rooms.part(&room, target);
+        self.left(target, &room);
+    }
+
+    fn mute(&mut self, id: ClientId, nick: &str, room: Option<String>, duration: Option<Duration>) {
+        let Some((room, target, nick, by)) = self.moderate(id, nick, room) else {
+            return;
+        };
+        log!("{} muted {} in {}", by, nick, room);
+
+        self.rooms.mute(
+            &room,
+            &nick,
+            // past what an `Instant` can hold is as good as forever
+            duration.and_then(|duration| Instant::now().checked_add(duration)),
+        );
+        let time = duration
+            .map(|duration| format!(" for {}", format_duration(duration)))
+            .unwrap_or_default();
+        self.room_reply(
+            id,
+            "mute",
+            &room,
+            format!("muted {} in {}{}", nick, room, time),
+        );
+        self.notice(
+            target,
+            format!("you were muted in {} by {}{}", room, by, time),
+        );
+    }
+
+    fn unmute(&mut self, id: ClientId, nick: &str, room: Option<String>) {
+        let Some((room, target, nick, by)) = self.moderate(id, nick, room) else {
+            return;
+        };
+
+        if !self.rooms.unmute(&room, &nick) {
+            return self.error(id, format!("{} is not muted in {}", nick, room));
+        }
+        self.room_reply(id, "unmute", &room, format!("unmuted {} in {}", nick, room));
+        self.notice(target, format!("you were unmuted in {} by {}", room, by));
+    }
+
+    fn op(&mut self, id: ClientId, nick: &str, room: Option<String>, op: bool) {
+        let Some((room, target, nick, by)) = self.moderate(id, nick, room) else {
+            return;
+        };
+
+        let (command, news, unchanged, notice) = match op {
+            true => ("op", "now", "already", "made you an operator"),
+            false => ("deop", "no longer", "not", "took away your operator status"),
+        };
+        if !self.rooms.set_op(&room, target, op) {
+            let text = format!("{} is {} an operator in {}", nick, unchanged, room);
+            return self.error(id, text);
+        }
+        let text = format!("{} is {} an operator in {}", nick, news, room);
+        log!("{}: {}", by, text);
+        self.room_reply(id, command, &room, text);
+        self.notice(target, format!("{} {} in {}", by, notice, room));
+    }
+
+    /// The room an operator command is about, the member it is aimed at with
+    /// their nickname, and the operator's nickname. Tells the client what's
+    /// wrong and returns `None` if it may not do this.
+    fn moderate(
+        &self,
+        id: ClientId,
+        nick: &str,
+        room: Option<String>,
+    ) -> Option<(String, ClientId, String, String)> {
+        let room = self.room_or_active(id, room)?;
+        let Some(by) = self.nick(id) else {
+            self.error(id, "pick a nickname with /nick <name> first");
+            return None;
+        };
+        if !self.is_server_op(id) && !self.rooms.is_op(&room, id) {
+            self.error(id, format!("you are not an operator in {}", room));
+            return None;
+        }
+
+        let target = match self.nicks.get(&nick.to_lowercase()) {
+            Some(&target) if self.rooms.members(&room).contains(&target) => target,
+            Some(_) => {
+                self.error(id, format!("{} is not in {}", nick, room));
+                return None;
+            }
+            None => {
+                self.error(id, format!("no such user {}", nick));
+                return None;
+            }
+        };
+        if self.is_server_op(target) && !self.is_server_op(id) {
+            self.error(id, format!("{} is a server operator", nick));
+            return None;
+        }
+
+        Some((room, target, self.nick(target)?, by))
+    }
+
+    fn ban(
+        &mut self,
+        id: ClientId,
+        target: &str,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    ) {
+        let Some(by) = self.server_op(id) else {
+            return;
+        };
+        let Some(target) = Target::parse(target) else {
+            return self.error(
+                id,
+                format!("{} is not a nickname, address or network", target),
+            );
+        };
+
+        let banned = self
+            .clients
+            .iter()
+            .filter(|(_, client)| match &target {
+                Target::Nick(nick) => {
+                    client.nick.as_ref().map(|n| n.to_lowercase()).as_ref() == Some(nick)
+                }
+                Target::Net(net) => client.peer.ip().is_some_and(|ip| net.contains(ip)),
+            })
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        if banned.contains(&id) {
+            return self.error(id, format!("banning {} would ban you too", target));
+        }
+
+        let ban = Ban {
+            target,
+            expires: duration.map(|duration| unix_now().saturating_add(duration.as_secs())),
+            by: by.clone(),
+            reason,
+        };
+        let text = ban.message();
+        let time = duration
+            .map(|duration| format!(" for {}", format_duration(duration)))
+            .unwrap_or_default();
+        let reply = format!("banned {}{}", ban.target, time);
+
+        if let Err(err) = self.bans.lock().unwrap().add(ban) {
+            log!("failed to save bans: {}", err);
+            return self.error(id, "could not save the ban, try again later");
+        }
+        log!("{}: {}", by, reply);
+        self.reply(id, "ban", reply);
+
+        for banned in banned {
+            let msg = ServerMessage::Error {
+                code: ErrorCode::Banned,
+                text: text.clone(),
+            };
+            self.send(banned, msg);
+            self.disconnect(banned, "banned");
+        }
+    }
+
+    fn unban(&mut self, id: ClientId, target: &str) {
+        let Some(by) = self.server_op(id) else {
+            return;
+        };
+        let Some(target) = Target::parse(target) else {
+            return self.error(
+                id,
+                format!("{} is not a nickname, address or network", target),
+            );
+        };
+
+        let removed = self.bans.lock().unwrap().remove(&target);
+        match removed {
+            Ok(true) => {
+                log!("{}: unbanned {}", by, target);
+                self.reply(id, "unban", format!("unbanned {}", target));
+            }
+            Ok(false) => self.error(id, format!("{} is not banned", target)),
+            Err(err) => {
+                log!("failed to save bans: {}", err);
+                self.error(id, "could not save the bans, try again later");
+            }
+        }
+    }
+
+    fn list_bans(&self, id: ClientId) {
+        if self.server_op(id).is_none() {
+            return;
+        }
+
+        let now = unix_now();
+        let bans = self.bans.lock().unwrap();
+        let mut text = String::new();
+        for ban in bans.active(now) {
+            text.push_str(&format!("\n  {} by {}", ban.target, ban.by));
+            if let Some(expires) = ban.expires {
+                let left = Duration::from_secs(expires - now);
+                text.push_str(&format!(", {} left", format_duration(left)));
+            }
+            if let Some(reason) = &ban.reason {
+                text.push_str(&format!(": {}", reason));
+            }
+        }
+        drop(bans);
+
+        match text.is_empty() {
+            true => self.reply(id, "bans", "there are no bans"),
+            false => self.reply(id, "bans", format!("bans:{}", text)),
+        }
+    }
+
+    /// Server operators are the operator accounts, once logged in.
+    fn is_server_op(&self, id: ClientId) -> bool {
+        match self.clients.get(&id) {
+            Some(Client {
+                account: true,
+                nick: Some(nick),
+                ..
+            }) => self.operators.contains(&nick.to_lowercase()),
+            _ => false,
+        }
+    }
+
+    /// The client's nickname if it is a server operator. Tells it otherwise.
+    fn server_op(&self, id: ClientId) -> Option<String> {
+        if !self.is_server_op(id) {
+            self.error(id, "only server operators can do that");
+            return None;
+        }
+
+        self.nick(id)
+    }
+
+    /// The room named, or the client's active room if none was. Tells the
+    /// client what's wrong and returns `None` if neither will do.
+    fn room_or_active(&self, id: ClientId, room: Option<String>) -> Option<String> {
+        let client = self.clients.get(&id)?;
+
+        match room.map(|room| normalize_room(&room)) {
+            Some(Ok(room)) => Some(room),
+            Some(Err(err)) => {
+                self.error(id, err);
+                None
+            }
+            None => {
+                if client.active.is_none() {
+                    self.error(id, "you are not in any room");
+                }
+                client.active.clone()
+            }
+        }
+    }
+
+    /// Chunks skip the flood limits, so one that can't be taken counts as a
+    /// protocol violation, and after `max_violations` the client is dropped.
+    fn violation(&mut self, id: ClientId, text: String) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        client.violations += 1;
+        log!(
+            "{}: protocol violation {} of {}: {}",
+            client.peer,
+            client.violations,
+            self.max_violations,
+            text
+        );
+        let disconnect = client.violations >= self.max_violations;
+
+        self.error(id, text);
+        if disconnect {
+            self.error(id, "disconnected for too many protocol violations");
+            self.disconnect(id, "protocol violations");
+        }
+    }
+
+    fn disconnect(&mut self, id: ClientId, reason: &str) {
+        let Some(client) = self.clients.remove(&id) else {
+            return;
+        };
+        log!("closing connection with: {} ({})", client.peer, reason);
+
+        for transfer in self.transfers.sent_by(id) {
+            self.fail_transfer(transfer, String::from("the sender went away"));
+        }
+        for transfer in self.transfers.sent_to(id) {
+            self.drop_recipient(transfer, id);
+        }
+
+        let rooms = self.rooms.part_all(id);
+        if let Some(nick) = client.nick {
+            self.nicks.remove(&nick.to_lowercase());
+
+            for room in rooms {
+                let msg = ServerMessage::Leave {
+                    room: room.clone(),
+                    nick: nick.clone(),
+                    reason: Some(reason.to_string()),
+                };
+                self.announce(&room, id, &msg);
+            }
+        }
+    }
+
+    /// Members of `room` on linked servers.
+    fn remote_members(&self, room: &str) -> Vec<String> {
+        match &self.federation {
+            Some(federation) => federation.members(room),
+            None => vec![],
+        }
+    }
+
+    fn nick(&self, id: ClientId) -> Option<String> {
+        self.clients.get(&id).and_then(|client| client.nick.clone())
+    }
+
+    /// Sends to everyone in the room except the client the news is about.
+    fn announce(&mut self, room: &str, about: ClientId, msg: &ServerMessage) {
+        let members = self
+            .rooms
+            .members(room)
+            .into_iter()
+            .filter(|&member| member != about);
+        self.send_all(members, msg);
+    }
+
+    fn notice(&self, id: ClientId, text: impl Into<String>) {
+        self.send(id, ServerMessage::Notice { text: text.into() });
+    }
+
+    fn reply(&self, id: ClientId, command: &str, text: impl Into<String>) {
+        let msg = ServerMessage::Reply {
+            command: command.to_string(),
+            room: None,
+            text: text.into(),
+        };
+        self.send(id, msg);
+    }
+
+    /// A reply to a command that was about a particular room.
+    fn room_reply(&self, id: ClientId, command: &str, room: &str, text: impl Into<String>) {
+        let msg = ServerMessage::Reply {
+            command: command.to_string(),
+            room: Some(room.to_string()),
+            text: text.into(),
+        };
+        self.send(id, msg);
+    }
+
+    fn error(&self, id: ClientId, text: impl Into<String>) {
+        self.error_code(id, ErrorCode::Refused, text);
+    }
+
+    fn error_code(&self, id: ClientId, code: ErrorCode, text: impl Into<String>) {
+        let msg = ServerMessage::Error {
+            code,
+            text: text.into(),
+        };
+        self.send(id, msg);
+    }
+
+    fn send(&self, id: ClientId, msg: ServerMessage) {
+        if let Some(client) = self.clients.get(&id) {
+            let _ = deliver(client, msg);
+        }
+    }
+
+    fn send_room(&mut self, room: &str, msg: &ServerMessage) {
+        let members = self.rooms.members(room);
+        self.send_all(members, msg);
+    }
+
+    fn send_all(&mut self, ids: impl IntoIterator<Item = ClientId>, msg: &ServerMessage) {
+        // a failed send means the connection task has already gone away, or
+        // that the client has fallen too far behind to keep
+        let gone = ids
+            .into_iter()
+            .filter_map(|id| {
+                let client = self.clients.get(&id)?;
+                deliver(client, msg.clone()).err().map(|err| (id, err))
+            })
+            .collect::<Vec<_>>();
+
+        for (id, err) in gone {
+            self.disconnect(id, gone_reason(err));
+        }
+    }
+}
+
+/// Queues a message for a client's connection task. Fails once that task has
+/// gone away, or once the client's queue overflows under the disconnect
+/// policy.
+fn deliver(client: &Client, msg: ServerMessage) -> Result<(), SendError> {
+    let sent = client.tx.send(msg);
+    match sent {
+        Ok(()) => metrics::add(&METRICS.messages_sent, 1),
+        Err(_) => metrics::add(&METRICS.failed_writes, 1),
+    }
+    sent
+}
+
+/// The reason other clients are given for a client that `deliver` failed on.
+fn gone_reason(err: SendError) -> &'static str {
+    match err {
+        SendError::Closed => "connection lost",
+        SendError::TooSlow => "too slow",
+    }
+}
+
+/// The largest whole unit only: `42s`, `5m`, `3h` or `2d`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+pub async fn run(mut rx: UnboundedReceiver<Event>, mut hub: Hub) {
+    while let Some(event) = rx.recv().await {
+        let shutdown = matches!(event, Event::Shutdown { .. });
+        hub.handle(event);
+
+        if shutdown {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_MAX_VIOLATIONS;
+    use crate::history::temp_dir;
+    use crate::queue::SlowPolicy;
+    use std::net::SocketAddr;
+    use std::ops::{Deref, DerefMut};
+    use std::path::{Path, PathBuf};
+    use tokio::sync::mpsc;
+
+    const QUEUE_SIZE: usize = 1000;
+
+    /// A hub whose files go when it does.
+    struct TestHub {
+        hub: Hub,
+        dir: PathBuf,
+    }
+
+    impl Deref for TestHub {
+        type Target = Hub;
+
+        fn deref(&self) -> &Hub {
+            &self.hub
+        }
+    }
+
+    impl DerefMut for TestHub {
+        fn deref_mut(&mut self) -> &mut Hub {
+            &mut self.hub
+        }
+    }
+
+    impl Drop for TestHub {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn new_hub() -> TestHub {
+        hub_with(Config::default()).0
+    }
+
+    fn hub_with(config: Config) -> (TestHub, UnboundedReceiver<Event>) {
+        let dir = temp_dir("hub");
+        let (hub, rx) = open_hub(&dir, &config);
+        (TestHub { hub, dir }, rx)
+    }
+
+    /// History, accounts and bans all live in `dir`.
+    fn open_hub(dir: &Path, config: &Config) -> (Hub, UnboundedReceiver<Event>) {
+        let history = History::open(dir.join("history")).unwrap();
+        let accounts = Accounts::open(dir.join("accounts")).unwrap();
+        let bans = Bans::open(dir.join("bans")).unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let bans = Arc::new(Mutex::new(bans));
+        (Hub::new(config, history, accounts, bans, tx), rx)
+    }
+
+    /// Feeds the result of a spawned password hash back into the hub.
+    async fn pump(hub: &mut Hub, events: &mut UnboundedReceiver<Event>) {
+        hub.handle(events.recv().await.unwrap());
+    }
+
+    fn connect(hub: &mut Hub, id: ClientId) -> queue::Receiver {
+        let (tx, mut rx) = queue::channel(QUEUE_SIZE, SlowPolicy::DropOldest);
+        let peer = Peer::Net(SocketAddr::from(([127, 0, 0, 1], 6000 + id as u16)));
+        hub.handle(Event::Connected { id, peer, tx });
+        rx.try_recv().unwrap();
+        rx
+    }
+
+    fn say(hub: &mut Hub, id: ClientId, msg: &str) {
+        hub.handle(Event::Message {
+            id,
+            msg: ClientMessage::from_line(msg),
+        });
+    }
+
+    /// Everything queued for a client, rendered as the terminal client would.
+    fn drain(rx: &mut queue::Receiver) -> Vec<String> {
+        let mut msgs = vec![];
+        while let Some(msg) = rx.try_recv() {
+            msgs.push(msg.to_string());
+        }
+        msgs
+    }
+
+    #[test]
+    fn test_chat_requires_nick() {
+        let mut hub = new_hub();
+        let mut rx = connect(&mut hub, 1);
+
+        say(&mut hub, 1, "hello");
+        assert_eq!(
+            drain(&mut rx),
+            vec!["! pick a nickname with /nick <name> before chatting"]
+        );
+    }
+
+    #[test]
+    fn test_messages_carry_nick() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let mut bob = connect(&mut hub, 2);
+
+        say(&mut hub, 1, "/nick alice");
+        say(&mut hub, 2, "/nick bob");
+        drain(&mut alice);
+        drain(&mut bob);
+
+        say(&mut hub, 1, "hi bob");
+        assert_eq!(drain(&mut alice), vec!["[#general] alice: hi bob"]);
+        assert_eq!(drain(&mut bob), vec!["[#general] alice: hi bob"]);
+
+        say(&mut hub, 2, "/nick robert");
+        assert_eq!(drain(&mut alice), vec!["* bob is now known as robert"]);
+    }
+
+    #[test]
+    fn test_message_kinds() {
+        let mut hub = new_hub();
+        let mut rx = connect(&mut hub, 1);
+        say(&mut hub, 1, "/nick alice");
+        say(&mut hub, 1, "/join #rust");
+        drain(&mut rx);
+
+        hub.handle(Event::Message {
+            id: 1,
+            msg: ClientMessage::Chat {
+                room: Some(String::from("#general")),
+                text: String::from("/not a command"),
+            },
+        });
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            ServerMessage::Chat {
+                room,
+                text,
+                replayed: false,
+                ..
+            } if room == "#general" && text == "/not a command"
+        ));
+
+        hub.handle(Event::Message {
+            id: 1,
+            msg: ClientMessage::Ping,
+        });
+        assert_eq!(rx.try_recv().unwrap(), ServerMessage::Pong);
+
+        say(&mut hub, 1, "/list");
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            ServerMessage::Reply { command, .. } if command == "list"
+        ));
+
+        say(&mut hub, 1, "/dance");
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            ServerMessage::Error {
+                code: ErrorCode::Refused,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_nick_collision() {
+        let mut hub = new_hub();
+        let _alice = connect(&mut hub, 1);
+        let mut imposter = connect(&mut hub, 2);
+
+        say(&mut hub, 1, "/nick alice");
+        say(&mut hub, 2, "/nick ALICE");
+        assert_eq!(
+            drain(&mut imposter),
+            vec![
+                "* alice joined #general",
+                "! nickname ALICE is already taken"
+            ]
+        );
+
+        hub.handle(Event::Disconnected {
+            id: 1,
+            reason: String::from("quit"),
+        });
+        say(&mut hub, 2, "/nick ALICE");
+        assert_eq!(
+            drain(&mut imposter),
+            vec![
+                "* alice left #general (quit)",
+                "* you are now known as ALICE"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_private_message() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let mut bob = connect(&mut hub, 2);
+        let mut carol = connect(&mut hub, 3);
+        say(&mut hub, 1, "/nick alice");
+        say(&mut hub, 2, "/nick bob");
+        say(&mut hub, 3, "/nick carol");
+        drain(&mut alice);
+        drain(&mut bob);
+        drain(&mut carol);
+
+        say(&mut hub, 1, "/msg Bob psst");
+        assert_eq!(drain(&mut alice), vec!["[pm] alice -> bob: psst"]);
+        assert_eq!(drain(&mut bob), vec!["[pm] alice -> bob: psst"]);
+        assert!(drain(&mut carol).is_empty());
+
+        say(&mut hub, 1, "/msg dave hello?");
+        assert_eq!(drain(&mut alice), vec!["! no such user dave"]);
+
+        drop(bob);
+        say(&mut hub, 1, "/msg bob still there?");
+        assert_eq!(
+            drain(&mut alice),
+            vec!["* bob left #general (connection lost)", "! bob is offline"]
+        );
+    }
+
+    #[test]
+    fn test_slow_client() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let (tx, mut bob) = queue::channel(3, SlowPolicy::Disconnect);
+        let peer = Peer::Net(SocketAddr::from(([127, 0, 0, 1], 6002)));
+        hub.handle(Event::Connected { id: 2, peer, tx });
+        say(&mut hub, 1, "/nick alice");
+        say(&mut hub, 2, "/nick bob");
+        drain(&mut alice);
+        drain(&mut bob);
+
+        // bob reads nothing while alice keeps talking
+        for i in 0..4 {
+            say(&mut hub, 1, &format!("msg {}", i));
+        }
+        let seen = drain(&mut alice);
+        assert_eq!(seen.len(), 5);
+        assert_eq!(seen[4], "* bob left #general (too slow)");
+        assert!(bob.too_slow());
+        assert!(drain(&mut bob).is_empty());
+    }
+
+    #[test]
+    fn test_history_replay() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        say(&mut hub, 1, "/nick alice");
+        for i in 0..HISTORY_PAGE + 5 {
+            say(&mut hub, 1, &format!("msg {}", i));
+        }
+        drain(&mut alice);
+
+        // a fresh hub over the same directory, as after a restart
+        hub.hub = open_hub(&hub.dir, &Config::default()).0;
+        let (tx, mut bob) = queue::channel(QUEUE_SIZE, SlowPolicy::DropOldest);
+        let peer = Peer::Net(SocketAddr::from(([127, 0, 0, 1], 6002)));
+        hub.handle(Event::Connected { id: 2, peer, tx });
+
+        let replayed = drain(&mut bob);
+        assert_eq!(replayed.len(), HISTORY_PAGE + 1);
+        assert!(replayed[1].ends_with("alice: msg 5"));
+        assert!(replayed[HISTORY_PAGE].ends_with("alice: msg 24"));
+
+        // messages sent in the meantime don't shift the next page
+        say(&mut hub, 2, "/nick bob");
+        for i in 0..3 {
+            say(&mut hub, 2, &format!("new {}", i));
+        }
+        drain(&mut bob);
+
+        say(&mut hub, 2, "/history");
+        let older = drain(&mut bob);
+        assert_eq!(older.len(), 5);
+        assert!(older[0].ends_with("alice: msg 0"));
+        assert!(older[4].ends_with("alice: msg 4"));
+
+        say(&mut hub, 2, "/history");
+        assert_eq!(drain(&mut bob), vec!["* no older messages in #general"]);
+    }
+
+    #[test]
+    fn test_edit_and_delete() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let mut bob = connect(&mut hub, 2);
+        say(&mut hub, 1, "/nick alice");
+        say(&mut hub, 2, "/nick bob");
+        drain(&mut alice);
+        drain(&mut bob);
+
+        say(&mut hub, 1, "helo");
+        say(&mut hub, 1, "bye");
+        let ids = [alice.try_recv(), alice.try_recv()].map(|msg| match msg {
+            Some(ServerMessage::Chat { id, .. }) => id,
+            other => panic!("expected a chat message, got {:?}", other),
+        });
+        assert!(ids[0] < ids[1]);
+        drain(&mut bob);
+
+        say(&mut hub, 2, &format!("/edit {} hijacked", ids[0]));
+        assert_eq!(
+            drain(&mut bob),
+            vec!["! you can only change your own messages"]
+        );
+        say(&mut hub, 2, "/delete 1");
+        assert_eq!(drain(&mut bob), vec!["! there is no message 1 in #general"]);
+
+        say(&mut hub, 1, &format!("/edit {} hello", ids[0]));
+        let edited = vec!["* alice edited a message in #general: hello"];
+        assert_eq!(drain(&mut alice), edited);
+        assert_eq!(drain(&mut bob), edited);
+
+        say(&mut hub, 1, "/delete last");
+        assert_eq!(
+            drain(&mut bob),
+            vec!["* alice deleted a message in #general"]
+        );
+
+        // whoever picks the nickname next sees the edit, but can't make one
+        hub.handle(Event::Disconnected {
+            id: 1,
+            reason: String::from("quit"),
+        });
+        let mut imposter = connect(&mut hub, 3);
+        let replayed = drain(&mut imposter);
+        assert!(replayed
+            .iter()
+            .any(|line| line.ends_with("alice: hello (edited)")));
+        assert!(!replayed.iter().any(|line| line.ends_with("alice: bye")));
+
+        say(&mut hub, 3, "/nick alice");
+        hub.handle(Event::Message {
+            id: 3,
+            msg: ClientMessage::Delete {
+                room: None,
+                id: ids[0],
+            },
+        });
+        assert_eq!(
+            drain(&mut imposter),
+            vec![
+                "* you are now known as alice",
+                "! you can only change your own messages"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_register_and_login() {
+        let (mut hub, mut events) = hub_with(Config {
+            guests: GuestPolicy::Deny,
+            ..Config::default()
+        });
+        let mut alice = connect(&mut hub, 1);
+        say(&mut hub, 1, "/nick alice");
+        say(&mut hub, 1, "hello?");
+        assert_eq!(
+            drain(&mut alice),
+            vec![
+                String::from("* you are now known as alice"),
+                format!("! {}", GUESTS_DENIED)
+            ]
+        );
+
+        say(&mut hub, 1, "/register hunter22");
+        pump(&mut hub, &mut events).await;
+        say(&mut hub, 1, "hello!");
+        assert_eq!(
+            drain(&mut alice),
+            vec![
+                "* registered alice, you are logged in",
+                "[#general] alice: hello!"
+            ]
+        );
+        hub.handle(Event::Disconnected {
+            id: 1,
+            reason: String::from("quit"),
+        });
+
+        let mut imposter = connect(&mut hub, 2);
+        drain(&mut imposter);
+        say(&mut hub, 2, "/nick alice");
+        say(&mut hub, 2, "/login alice hunter2");
+        pump(&mut hub, &mut events).await;
+        assert_eq!(
+            drain(&mut imposter),
+            vec![
+                "! alice is registered, use /login alice <password>",
+                "! wrong password for alice"
+            ]
+        );
+
+        let mut alice = connect(&mut hub, 3);
+        drain(&mut alice);
+        say(&mut hub, 3, "/login alice hunter22");
+        pump(&mut hub, &mut events).await;
+        say(&mut hub, 3, "/passwd hunter22 correct-horse");
+        pump(&mut hub, &mut events).await;
+        assert_eq!(
+            drain(&mut alice),
+            vec![
+                "* you are now known as alice",
+                "* logged in as alice",
+                "* password changed"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_presence() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let mut bob = connect(&mut hub, 2);
+        say(&mut hub, 1, "/nick alice");
+        drain(&mut alice);
+        assert_eq!(drain(&mut bob), vec!["* alice joined #general"]);
+
+        say(&mut hub, 2, "/nick bob");
+        drain(&mut bob);
+        assert_eq!(drain(&mut alice), vec!["* bob joined #general"]);
+
+        say(&mut hub, 1, "/join #rust");
+        drain(&mut alice);
+        assert!(drain(&mut bob).is_empty());
+        say(&mut hub, 2, "/join #rust");
+        drain(&mut bob);
+        assert_eq!(drain(&mut alice), vec!["* bob joined #rust"]);
+
+        let _carol = connect(&mut hub, 3);
+        say(&mut hub, 2, "/who #general");
+        assert_eq!(
+            drain(&mut bob),
+            ["* 2 users in #general:\n  alice (idle 0s)\n  bob (idle 0s)\n  and 1 without a nickname"]
+        );
+
+        say(&mut hub, 2, "/part #rust");
+        assert_eq!(drain(&mut alice), vec!["* bob left #rust"]);
+
+        hub.handle(Event::Disconnected {
+            id: 2,
+            reason: String::from("timed out"),
+        });
+        assert_eq!(drain(&mut alice), vec!["* bob left #general (timed out)"]);
+    }
+
+    #[test]
+    fn test_names_and_topic() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let mut bob = connect(&mut hub, 2);
+        let _carol = connect(&mut hub, 3);
+        say(&mut hub, 2, "/nick bob");
+        say(&mut hub, 1, "/nick alice");
+        drain(&mut alice);
+        drain(&mut bob);
+
+        say(&mut hub, 1, "/names");
+        assert_eq!(drain(&mut alice), vec!["* in #general: alice, bob"]);
+
+        say(&mut hub, 1, "/topic");
+        assert_eq!(drain(&mut alice), vec!["* no topic is set for #general"]);
+
+        say(&mut hub, 2, "/topic standup at ten");
+        let set = "* bob set the topic of #general to: standup at ten";
+        assert_eq!(drain(&mut alice), vec![set]);
+        assert_eq!(drain(&mut bob), vec![set]);
+
+        say(&mut hub, 1, "/topic #General");
+        assert_eq!(
+            drain(&mut alice),
+            vec!["* topic of #general: standup at ten"]
+        );
+
+        say(&mut hub, 1, "/topic #rust no one is here");
+        assert_eq!(drain(&mut alice), vec!["! you are not in #rust"]);
+        say(&mut hub, 1, "/names #rust");
+        assert_eq!(drain(&mut alice), vec!["! there is no room called #rust"]);
+    }
+
+    #[test]
+    fn test_room_operators() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let mut bob = connect(&mut hub, 2);
+        let mut carol = connect(&mut hub, 3);
+        for (id, nick) in [(1, "alice"), (2, "bob"), (3, "carol")] {
+            say(&mut hub, id, &format!("/nick {}", nick));
+            say(&mut hub, id, "/join #rust");
+        }
+        drain(&mut alice);
+        drain(&mut bob);
+        drain(&mut carol);
+
+        say(&mut hub, 2, "/kick carol");
+        assert_eq!(drain(&mut bob), vec!["! you are not an operator in #rust"]);
+        say(&mut hub, 1, "/kick bob #general");
+        assert_eq!(
+            drain(&mut alice),
+            vec!["! you are not an operator in #general"]
+        );
+
+        say(&mut hub, 1, "/mute bob 10m");
+        assert_eq!(drain(&mut alice), vec!["* muted bob in #rust for 10m"]);
+        say(&mut hub, 2, "hello?");
+        assert_eq!(
+            drain(&mut bob),
+            vec![
+                "* you were muted in #rust by alice for 10m",
+                "! you are muted in #rust"
+            ]
+        );
+        say(&mut hub, 2, "/topic still talking");
+        assert_eq!(drain(&mut bob), vec!["! you are muted in #rust"]);
+
+        // a duration too long to add to the clock is refused, not a crash
+        say(&mut hub, 1, "/mute carol 10000000000000000000s");
+        assert_eq!(
+            drain(&mut alice),
+            vec!["! durations can be at most 365d, leave it out for forever"]
+        );
+
+        say(&mut hub, 1, "/op bob");
+        say(&mut hub, 2, "/kick carol #rust spam");
+        let kicked = "* carol was kicked from #rust by bob (spam)";
+        assert_eq!(
+            drain(&mut alice),
+            vec!["* bob is now an operator in #rust", kicked]
+        );
+        assert_eq!(
+            drain(&mut bob),
+            vec!["* alice made you an operator in #rust", kicked]
+        );
+        assert_eq!(drain(&mut carol), vec![kicked]);
+
+        // and carol is back to talking in #general
+        say(&mut hub, 3, "still here");
+        assert_eq!(drain(&mut carol), vec!["[#general] carol: still here"]);
+    }
+
+    #[test]
+    fn test_mute_outlasts_reconnect() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let _bob = connect(&mut hub, 2);
+        for (id, nick) in [(1, "alice"), (2, "bob")] {
+            say(&mut hub, id, &format!("/nick {}", nick));
+            say(&mut hub, id, "/join #rust");
+        }
+        say(&mut hub, 1, "/mute bob 1h");
+        drain(&mut alice);
+
+        hub.handle(Event::Disconnected {
+            id: 2,
+            reason: String::from("quit"),
+        });
+        let mut bob = connect(&mut hub, 3);
+        say(&mut hub, 3, "/nick Bob");
+        say(&mut hub, 3, "/join #rust");
+        drain(&mut bob);
+        say(&mut hub, 3, "still here");
+        assert_eq!(drain(&mut bob), vec!["! you are muted in #rust"]);
+
+        // nor does a new nickname get out of it
+        say(&mut hub, 3, "/nick robert");
+        drain(&mut bob);
+        say(&mut hub, 3, "how about now");
+        assert_eq!(drain(&mut bob), vec!["! you are muted in #rust"]);
+    }
+
+    #[test]
+    fn test_bans() {
+        let mut hub = new_hub();
+        hub.operators = vec![String::from("root")];
+        let mut root = connect(&mut hub, 1);
+        let mut bob = connect(&mut hub, 2);
+        say(&mut hub, 1, "/nick root");
+        say(&mut hub, 2, "/nick bob");
+        drain(&mut bob);
+
+        say(&mut hub, 2, "/ban root");
+        assert_eq!(drain(&mut bob), vec!["! only server operators can do that"]);
+
+        // the nickname alone isn't enough, it takes the operator's account
+        say(&mut hub, 1, "/ban bob");
+        hub.clients.get_mut(&1).unwrap().account = true;
+        say(&mut hub, 1, "/ban 127.0.0.0/8");
+        say(&mut hub, 1, "/ban bob 1h spam");
+        assert_eq!(
+            drain(&mut root)[2..],
+            [
+                "! only server operators can do that",
+                "! banning 127.0.0.0/8 would ban you too",
+                "* banned bob for 1h",
+                "* bob left #general (banned)"
+            ]
+        );
+        assert_eq!(drain(&mut bob), vec!["! you are banned: spam"]);
+        assert!(bob.is_closed());
+
+        let mut again = connect(&mut hub, 3);
+        say(&mut hub, 3, "/nick BOB");
+        assert_eq!(drain(&mut again), vec!["! nickname BOB is banned"]);
+
+        say(&mut hub, 1, "/bans");
+        assert_eq!(
+            drain(&mut root),
+            vec!["* bans:\n  bob by root, 1h left: spam"]
+        );
+        say(&mut hub, 1, "/unban bob");
+        say(&mut hub, 3, "/nick bob");
+        assert_eq!(drain(&mut again), vec!["* you are now known as bob"]);
+    }
+
+    #[test]
+    fn test_address_bans_miss_unix_clients() {
+        let mut hub = new_hub();
+        hub.operators = vec![String::from("root")];
+        let (tx, mut root) = queue::channel(QUEUE_SIZE, SlowPolicy::DropOldest);
+        let peer = Peer::Unix {
+            uid: 1000,
+            pid: Some(4242),
+        };
+        hub.handle(Event::Connected { id: 1, peer, tx });
+        let mut bob = connect(&mut hub, 2);
+        say(&mut hub, 1, "/nick root");
+        say(&mut hub, 2, "/nick bob");
+        hub.clients.get_mut(&1).unwrap().account = true;
+        drain(&mut root);
+        drain(&mut bob);
+
+        // loopback clients are someone else as far as bans go
+        say(&mut hub, 1, "/ban 127.0.0.0/8");
+        assert_eq!(
+            drain(&mut root),
+            vec!["* banned 127.0.0.0/8", "* bob left #general (banned)"]
+        );
+        assert!(bob.is_closed());
+        assert!(!root.is_closed());
+    }
+
+    #[test]
+    fn test_file_transfer() {
+        // sha256 of "hello world"
+        let sha256 = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let offer = |to: &str| ClientMessage::Offer {
+            to: to.to_string(),
+            name: String::from("hello.txt"),
+            size: 11,
+            sha256: sha256.to_string(),
+        };
+        let chunk = |data: &[u8]| ClientMessage::Chunk {
+            id: 1,
+            data: data.to_vec(),
+        };
+
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let mut bob = connect(&mut hub, 2);
+        let mut carol = connect(&mut hub, 3);
+        for (id, nick) in [(1, "alice"), (2, "bob"), (3, "carol")] {
+            say(&mut hub, id, &format!("/nick {}", nick));
+        }
+        drain(&mut alice);
+        drain(&mut bob);
+        drain(&mut carol);
+
+        hub.handle(Event::Message {
+            id: 1,
+            msg: offer("#general"),
+        });
+        assert_eq!(
+            drain(&mut alice),
+            vec!["* offering hello.txt (11 bytes) to #general as transfer 1"]
+        );
+        let offered = "* alice offers hello.txt (11 bytes) to #general, /accept 1 or /decline 1";
+        assert_eq!(drain(&mut bob), vec![offered]);
+        assert_eq!(drain(&mut carol), vec![offered]);
+
+        hub.handle(Event::Message {
+            id: 1,
+            msg: chunk(b"hello "),
+        });
+        assert_eq!(
+            drain(&mut alice),
+            vec!["! nobody has accepted transfer 1 yet"]
+        );
+
+        hub.handle(Event::Message {
+            id: 2,
+            msg: ClientMessage::Accept { id: 1 },
+        });
+        assert_eq!(drain(&mut alice), vec!["* bob accepted transfer 1"]);
+        assert_eq!(drain(&mut bob), vec!["* bob accepted transfer 1"]);
+
+        // carol never answered, and the chunks are bob's alone
+        hub.handle(Event::Message {
+            id: 1,
+            msg: chunk(b"hello "),
+        });
+        say(&mut hub, 3, "meanwhile");
+        hub.handle(Event::Message {
+            id: 1,
+            msg: chunk(b"world"),
+        });
+        assert_eq!(
+            drain(&mut alice),
+            vec![
+                "* transfer 1: 6 of 11 bytes",
+                "[#general] carol: meanwhile",
+                "* transfer 1: 11 of 11 bytes",
+                "* transfer 1 is complete"
+            ]
+        );
+        assert_eq!(
+            drain(&mut bob),
+            vec![
+                "* 6 bytes of transfer 1",
+                "[#general] carol: meanwhile",
+                "* 5 bytes of transfer 1",
+                "* transfer 1 is complete"
+            ]
+        );
+        assert_eq!(
+            drain(&mut carol),
+            vec![
+                "! transfer 1 failed: it started without you",
+                "[#general] carol: meanwhile"
+            ]
+        );
+
+        // a file that isn't what was offered never completes
+        hub.handle(Event::Message {
+            id: 1,
+            msg: offer("bob"),
+        });
+        hub.handle(Event::Message {
+            id: 2,
+            msg: ClientMessage::Accept { id: 2 },
+        });
+        drain(&mut alice);
+        hub.handle(Event::Message {
+            id: 1,
+            msg: ClientMessage::Chunk {
+                id: 2,
+                data: b"HELLO WORLD".to_vec(),
+            },
+        });
+        assert_eq!(
+            drain(&mut alice),
+            vec!["! transfer 2 failed: checksum mismatch"]
+        );
+
+        // and one whose only recipient leaves is called off
+        hub.handle(Event::Message {
+            id: 1,
+            msg: offer("carol"),
+        });
+        hub.handle(Event::Message {
+            id: 3,
+            msg: ClientMessage::Decline { id: 3 },
+        });
+        assert_eq!(
+            drain(&mut alice),
+            vec![
+                "* offering hello.txt (11 bytes) to carol as transfer 3",
+                "* carol declined transfer 3",
+                "! transfer 3 failed: nobody is left to receive it"
+            ]
+        );
+        assert!(hub.transfers.get(3).is_none());
+    }
+
+    #[test]
+    fn test_chunk_violations() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        drain(&mut alice);
+
+        for _ in 0..DEFAULT_MAX_VIOLATIONS {
+            hub.handle(Event::Message {
+                id: 1,
+                msg: ClientMessage::Chunk {
+                    id: 7,
+                    data: vec![0; 1024],
+                },
+            });
+        }
+
+        let errors = drain(&mut alice);
+        assert_eq!(errors.len(), DEFAULT_MAX_VIOLATIONS as usize + 1);
+        assert_eq!(errors[0], "! you aren't sending transfer 7");
+        assert_eq!(
+            errors.last().unwrap(),
+            "! disconnected for too many protocol violations"
+        );
+        assert!(alice.is_closed());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(61)), "1m");
+        assert_eq!(format_duration(Duration::from_secs(3 * 3600 + 5)), "3h");
+        assert_eq!(format_duration(Duration::from_secs(2 * 86400)), "2d");
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+
+        hub.handle(Event::Shutdown {
+            reason: Some(String::from("upgrading, back soon")),
+        });
+        assert_eq!(
+            drain(&mut alice),
+            vec!["* server is shutting down: upgrading, back soon"]
+        );
+        assert!(alice.is_closed());
+    }
+
+    #[test]
+    fn test_room_routing() {
+        let mut hub = new_hub();
+        let mut alice = connect(&mut hub, 1);
+        let mut bob = connect(&mut hub, 2);
+        say(&mut hub, 1, "/nick alice");
+        say(&mut hub, 2, "/nick bob");
+
+        say(&mut hub, 1, "/join #Rust");
+        drain(&mut alice);
+        drain(&mut bob);
+
+        say(&mut hub, 1, "anyone here?");
+        assert_eq!(drain(&mut alice), vec!["[#rust] alice: anyone here?"]);
+        assert!(drain(&mut bob).is_empty());
+
+        say(&mut hub, 1, "/list");
+        assert_eq!(
+            drain(&mut alice),
+            vec!["* rooms:\n  #general (2)\n  #rust (1)"]
+        );
+
+        say(&mut hub, 1, "/part");
+        say(&mut hub, 1, "back in general");
+        assert_eq!(
+            drain(&mut alice),
+            vec!["* left #rust", "[#general] alice: back in general"]
+        );
+        assert_eq!(drain(&mut bob), vec!["[#general] alice: back in general"]);
+    }
+
+    #[test]
+    fn test_relays_only_valid_messages() {
+        let mut hub = hub_with(Config {
+            server_name: Some(String::from("berlin")),
+            links: vec![String::from("127.0.0.1:1")],
+            ..Config::default()
+        })
+        .0;
+
+        let (paris, _) = mpsc::unbounded_channel();
+        let (rome, mut relayed) = mpsc::unbounded_channel();
+        hub.handle(Event::LinkUp {
+            link: 1,
+            name: String::from("paris"),
+            tx: paris,
+        });
+        hub.handle(Event::LinkUp {
+            link: 2,
+            name: String::from("rome"),
+            tx: rome,
+        });
+        while relayed.try_recv().is_ok() {}
+
+        let chat = |id: &str, room: &str, from: &str, text: &str| Event::Link {
+            link: 1,
+            msg: LinkMessage::Chat {
+                id: id.to_string(),
+                server: String::from("paris"),
+                room: room.to_string(),
+                from: from.to_string(),
+                text: text.to_string(),
+                time: 0,
+            },
+        };
+        hub.handle(chat("paris:1", "#../../etc", "alice", "hi"));
+        hub.handle(chat("paris:2", "#general", "al ice", "hi"));
+        hub.handle(chat("paris:3", "#general", "alice", ""));
+        hub.handle(chat("paris:4", "#general", "alice", &"x".repeat(70000)));
+        assert!(relayed.try_recv().is_err());
+
+        hub.handle(chat("paris:5", "#General", "alice", "hi"));
+        assert!(matches!(
+            relayed.try_recv().unwrap(),
+            LinkMessage::Chat { id, room, .. } if id == "paris:5" && room == "#general"
+        ));
+    }
+}
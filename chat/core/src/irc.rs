@@ -0,0 +1,903 @@
+/**
+ * Enough of the IRC client protocol for irssi, weechat and friends to take
+ * part: registration with NICK, USER and optionally PASS, then JOIN, PART,
+ * PRIVMSG, NAMES, TOPIC, PING and QUIT. Everything else is passed on as a
+ * command of the same name, so `/quote history` works too.
+ *
+ * IRC lines are translated into the same messages native clients send, and
+ * the hub's messages back into IRC lines, so the hub never knows which kind
+ * of client it is talking to.
+**/
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use protocol::{
+    clock, decode_message, encode_message, ClientMessage, ErrorCode, FrameError, ServerMessage,
+};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::commands::MAX_NICK_LEN;
+use crate::config::Config;
+use crate::connection::{self, Incoming, Outgoing, Peer};
+use crate::hub::{ClientId, Event};
+use crate::metrics::{self, METRICS};
+use crate::rooms::{DEFAULT_ROOM, MAX_ROOM_LEN};
+
+/// What the server calls itself in prefixes and numerics.
+const SERVER: &str = "chat";
+
+/// Room a line needs beyond its chat text, for the command, targets and
+/// tags: a whole RFC 1459 line's worth.
+const LINE_OVERHEAD: usize = 512;
+
+/// Nicks per RPL_NAMREPLY line, which keeps them well under 512 bytes.
+const NAMES_PER_LINE: usize = 20;
+
+/// What the two halves of a connection need to know about each other.
+#[derive(Debug, Default)]
+struct Shared {
+    // the hub has given the client its first nickname
+    registered: bool,
+    // the nickname asked for last, for errors during registration
+    nick: Option<String>,
+    // registering with PASS, so with /login rather than /nick
+    login: bool,
+    // tokens of the client's pings, answered in order
+    pings: VecDeque<String>,
+}
+
+/// Turns lines from the client into messages for the hub.
+struct Inbound {
+    shared: Arc<Mutex<Shared>>,
+    // chat text is held to `max_message_size`, as for native clients
+    max_text: usize,
+    password: Option<String>,
+    nick: Option<String>,
+    user: bool,
+}
+
+impl Inbound {
+    fn new(shared: Arc<Mutex<Shared>>, max_text: usize) -> Inbound {
+        Inbound {
+            shared,
+            max_text,
+            password: None,
+            nick: None,
+            user: false,
+        }
+    }
+
+    /// The messages a line stands for, or `None` if the client quit. Chat
+    /// text longer than a native client may send is an error.
+    fn translate(&mut self, line: &str) -> Option<Result<Vec<ClientMessage>, FrameError>> {
+        let Some((command, params)) = parse(line) else {
+            return Some(Ok(vec![]));
+        };
+
+        let msgs = match (command.as_str(), &params[..]) {
+            ("PASS", [password, ..]) => {
+                self.password = Some(password.clone());
+                vec![]
+            }
+            ("NICK", [nick, ..]) => {
+                self.nick = Some(nick.clone());
+                self.register()
+            }
+            ("USER", _) => {
+                self.user = true;
+                self.register()
+            }
+            ("JOIN", [rooms, ..]) => rooms
+                .split(',')
+                // "JOIN 0" means leave everything, which isn't worth it
+                .filter(|room| *room != "0")
+                .map(|room| command_msg("join", room))
+                .collect(),
+            ("PART", [rooms, ..]) => rooms
+                .split(',')
+                .map(|room| command_msg("part", room))
+                .collect(),
+            ("PRIVMSG", [_, text, ..]) if text.len() > self.max_text => {
+                return Some(Err(FrameError::TooLarge {
+                    len: text.len(),
+                    max: self.max_text,
+                }));
+            }
+            ("PRIVMSG", [targets, text, ..]) => {
+                let Some(text) = action(text) else {
+                    return Some(Ok(vec![]));
+                };
+                targets
+                    .split(',')
+                    .map(|target| match target.starts_with('#') {
+                        true => ClientMessage::Chat {
+                            room: Some(target.to_string()),
+                            text: text.clone(),
+                        },
+                        false => command_msg("msg", &format!("{} {}", target, text)),
+                    })
+                    .collect()
+            }
+            ("PING", params) => {
+                let token = params.first().cloned().unwrap_or_default();
+                self.shared.lock().unwrap().pings.push_back(token);
+                vec![ClientMessage::Ping]
+            }
+            ("PONG", _) => vec![ClientMessage::Pong],
+            ("QUIT", _) => return None,
+            ("NAMES", []) => vec![command_msg("names", "")],
+            ("NAMES", [rooms, ..]) => rooms
+                .split(',')
+                .map(|room| command_msg("names", room))
+                .collect(),
+            ("TOPIC", [room]) => vec![command_msg("topic", room)],
+            ("TOPIC", [room, text, ..]) => {
+                vec![command_msg("topic", &format!("{} {}", room, text))]
+            }
+            // sent by clients on their own, with nothing here to answer them;
+            // NOTICEs must never be answered anyway
+            ("CAP" | "MODE" | "WHO" | "USERHOST" | "NOTICE", _) => vec![],
+            (_, params) => vec![command_msg(&command.to_lowercase(), &params.join(" "))],
+        };
+
+        Some(Ok(msgs))
+    }
+
+    /// Picks the nickname once both NICK and USER have been seen, and
+    /// renames the client on every NICK after that.
+    fn register(&mut self) -> Vec<ClientMessage> {
+        let (Some(nick), true) = (&self.nick, self.user) else {
+            return vec![];
+        };
+
+        let mut shared = self.shared.lock().unwrap();
+        shared.nick = Some(nick.clone());
+
+        match &self.password {
+            Some(password) if !shared.registered => {
+                shared.login = true;
+                vec![command_msg("login", &format!("{} {}", nick, password))]
+            }
+            _ => vec![command_msg("nick", nick)],
+        }
+    }
+}
+
+fn command_msg(name: &str, args: &str) -> ClientMessage {
+    ClientMessage::Command {
+        name: name.to_string(),
+        args: args.to_string(),
+    }
+}
+
+/// The text to send for a PRIVMSG: CTCP ACTIONs (`/me`) become `*text*` and
+/// other CTCP requests are dropped.
+fn action(text: &str) -> Option<String> {
+    let Some(ctcp) = text.strip_prefix('\x01') else {
+        return Some(text.to_string());
+    };
+
+    let ctcp = ctcp.trim_end_matches('\x01');
+    ctcp.strip_prefix("ACTION ")
+        .map(|action| format!("*{}*", action))
+}
+
+/// Splits a line into its command, uppercased, and parameters, skipping any
+/// tags and prefix. `None` for a blank line.
+fn parse(line: &str) -> Option<(String, Vec<String>)> {
+    let mut rest = line.trim_start();
+    if rest.starts_with('@') {
+        rest = rest.split_once(' ')?.1.trim_start();
+    }
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ')?.1.trim_start();
+    }
+
+    let (head, trailing) = match rest.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (rest, None),
+    };
+
+    let mut words = head.split_whitespace();
+    let command = words.next()?.to_uppercase();
+    let mut params = words.map(String::from).collect::<Vec<_>>();
+    params.extend(trailing.map(String::from));
+
+    Some((command, params))
+}
+
+/// Turns messages from the hub into lines for the client.
+struct Outbound {
+    shared: Arc<Mutex<Shared>>,
+    // for what an IRC client expects to be told unasked, like the names in
+    // a room it just joined
+    inject: UnboundedSender<ClientMessage>,
+    // set once the hub has given the client its first nickname
+    nick: Option<String>,
+    joined: BTreeSet<String>,
+    // what arrived before registration, sent once the client is welcomed
+    held: Vec<ServerMessage>,
+}
+
+impl Outbound {
+    fn new(shared: Arc<Mutex<Shared>>, inject: UnboundedSender<ClientMessage>) -> Outbound {
+        Outbound {
+            shared,
+            inject,
+            nick: None,
+            joined: BTreeSet::new(),
+            held: vec![],
+        }
+    }
+
+    fn translate(&mut self, msg: ServerMessage) -> Vec<String> {
+        let Some(nick) = self.nick.clone() else {
+            return self.unregistered(msg);
+        };
+
+        self.registered(&nick, msg)
+    }
+
+    fn unregistered(&mut self, msg: ServerMessage) -> Vec<String> {
+        match msg {
+            ServerMessage::Nick { old: None, new } => self.welcome(new),
+            ServerMessage::Error { code, text } => {
+                let shared = self.shared.lock().unwrap();
+                let nick = shared.nick.as_deref().unwrap_or("*");
+                match (code, shared.login) {
+                    (ErrorCode::NickInUse, _) => {
+                        vec![format!(":{} 433 * {} :{}", SERVER, nick, text)]
+                    }
+                    (ErrorCode::BadNick, _) => {
+                        vec![format!(":{} 432 * {} :{}", SERVER, nick, text)]
+                    }
+                    (ErrorCode::Banned, _) => vec![format!(":{} 465 {} :{}", SERVER, nick, text)],
+                    (_, true) => vec![format!(":{} 464 {} :{}", SERVER, nick, text)],
+                    (_, false) => vec![format!(":{} NOTICE {} :{}", SERVER, nick, text)],
+                }
+            }
+            ServerMessage::Ping | ServerMessage::Pong => self.registered("*", msg),
+            msg => {
+                self.held.push(msg);
+                vec![]
+            }
+        }
+    }
+
+    /// RPL_WELCOME and friends, with whatever notices the hub has sent so far
+    /// as the message of the day.
+    fn welcome(&mut self, nick: String) -> Vec<String> {
+        self.shared.lock().unwrap().registered = true;
+        self.nick = Some(nick.clone());
+
+        let mut lines = vec![
+            format!(":{} 001 {} :Welcome to {}, {}", SERVER, nick, SERVER, nick),
+            format!(":{} 002 {} :Your host is {}", SERVER, nick, SERVER),
+            format!(
+                ":{} 003 {} :This server speaks IRC as a second language",
+                SERVER, nick
+            ),
+            format!(
+                ":{} 004 {} {} {} o o",
+                SERVER,
+                nick,
+                SERVER,
+                env!("CARGO_PKG_VERSION")
+            ),
+            format!(
+                ":{} 005 {} CHANTYPES=# NICKLEN={} CHANNELLEN={} :are supported by this server",
+                SERVER, nick, MAX_NICK_LEN, MAX_ROOM_LEN
+            ),
+        ];
+
+        let held = std::mem::take(&mut self.held);
+        let (notices, rest): (Vec<_>, Vec<_>) = held
+            .into_iter()
+            .partition(|msg| matches!(msg, ServerMessage::Notice { .. }));
+
+        lines.push(format!(
+            ":{} 375 {} :- {} message of the day",
+            SERVER, nick, SERVER
+        ));
+        for notice in notices {
+            if let ServerMessage::Notice { text } = notice {
+                for line in text_lines(&text) {
+                    lines.push(format!(":{} 372 {} :- {}", SERVER, nick, line));
+                }
+            }
+        }
+        lines.push(format!(":{} 376 {} :End of /MOTD command.", SERVER, nick));
+
+        // the hub put the client in the default room as it connected
+        lines.extend(self.joined_room(&nick, DEFAULT_ROOM));
+        for msg in rest {
+            lines.extend(self.translate(msg));
+        }
+
+        lines
+    }
+
+    fn registered(&mut self, nick: &str, msg: ServerMessage) -> Vec<String> {
+        match msg {
+            // IRC clients show what they sent themselves, history aside
+            ServerMessage::Chat {
+                ref from,
+                replayed: false,
+                ..
+            } if from == nick => vec![],
+            ServerMessage::Chat {
+                room,
+                from,
+                text,
+                time,
+                replayed,
+                ..
+            } => {
+                let stamp = match replayed {
+                    true => format!("[{}] ", clock(time)),
+                    false => String::new(),
+                };
+                text_lines(&text)
+                    .map(|line| format!(":{} PRIVMSG {} :{}{}", prefix(&from), room, stamp, line))
+                    .collect()
+            }
+            ServerMessage::Edited {
+                room, from, text, ..
+            } => text_lines(&text)
+                .map(|line| {
+                    format!(
+                        ":{} NOTICE {} :{} edited a message: {}",
+                        SERVER, room, from, line
+                    )
+                })
+                .collect(),
+            ServerMessage::Deleted { room, from, .. } => vec![format!(
+                ":{} NOTICE {} :{} deleted a message",
+                SERVER, room, from
+            )],
+            ServerMessage::Private { from, to, .. } if from == nick && to != nick => vec![],
+            ServerMessage::Private { from, to, text } => text_lines(&text)
+                .map(|line| format!(":{} PRIVMSG {} :{}", prefix(&from), to, line))
+                .collect(),
+            ServerMessage::Reply {
+                command,
+                room: Some(room),
+                ..
+            } if command == "join" => self.joined_room(nick, &room),
+            ServerMessage::Reply {
+                command,
+                room: Some(room),
+                ..
+            } if command == "part" => {
+                self.joined.remove(&room);
+                vec![format!(":{} PART {}", prefix(nick), room)]
+            }
+            ServerMessage::Notice { text }
+            | ServerMessage::Reply { text, .. }
+            | ServerMessage::Error { text, .. } => text_lines(&text)
+                .map(|line| format!(":{} NOTICE {} :{}", SERVER, nick, line))
+                .collect(),
+            ServerMessage::Join { room, nick } => {
+                vec![format!(":{} JOIN {}", prefix(&nick), room)]
+            }
+            ServerMessage::Kick {
+                room,
+                nick: kicked,
+                by,
+                reason,
+            } => {
+                if kicked == nick {
+                    self.joined.remove(&room);
+                }
+                let reason = reason.unwrap_or_else(|| by.clone());
+                vec![format!(
+                    ":{} KICK {} {} :{}",
+                    prefix(&by),
+                    room,
+                    kicked,
+                    reason
+                )]
+            }
+            ServerMessage::Leave {
+                room,
+                nick,
+                reason: Some(reason),
+            } => vec![format!(":{} PART {} :{}", prefix(&nick), room, reason)],
+            ServerMessage::Leave { room, nick, .. } => {
+                vec![format!(":{} PART {}", prefix(&nick), room)]
+            }
+            ServerMessage::Nick {
+                old: Some(old),
+                new,
+            } => {
+                if old == nick {
+                    self.nick = Some(new.clone());
+                }
+                vec![format!(":{} NICK {}", prefix(&old), new)]
+            }
+            ServerMessage::Nick { old: None, .. } => vec![],
+            ServerMessage::Names { room, nicks } => {
+                let mut lines = nicks
+                    .chunks(NAMES_PER_LINE)
+                    .map(|nicks| {
+                        format!(":{} 353 {} = {} :{}", SERVER, nick, room, nicks.join(" "))
+                    })
+                    .collect::<Vec<_>>();
+                lines.push(format!(
+                    ":{} 366 {} {} :End of /NAMES list.",
+                    SERVER, nick, room
+                ));
+                lines
+            }
+            ServerMessage::Topic {
+                room,
+                topic,
+                nick: Some(setter),
+            } => vec![format!(
+                ":{} TOPIC {} :{}",
+                prefix(&setter),
+                room,
+                topic.unwrap_or_default()
+            )],
+            ServerMessage::Topic {
+                room,
+                topic: Some(topic),
+                ..
+            } => vec![format!(":{} 332 {} {} :{}", SERVER, nick, room, topic)],
+            ServerMessage::Topic { room, .. } => {
+                vec![format!(
+                    ":{} 331 {} {} :No topic is set",
+                    SERVER, nick, room
+                )]
+            }
+            ServerMessage::Offer {
+                from, name, size, ..
+            } => vec![format!(
+                ":{} NOTICE {} :{} offered you {} ({} bytes), but files can't be received over IRC",
+                SERVER, nick, from, name, size
+            )],
+            // IRC clients never take part in a transfer
+            ServerMessage::Offered { .. }
+            | ServerMessage::Accepted { .. }
+            | ServerMessage::Declined { .. }
+            | ServerMessage::Progress { .. }
+            | ServerMessage::Done { .. }
+            | ServerMessage::Failed { .. }
+            | ServerMessage::Chunk { .. } => vec![],
+            ServerMessage::Ping => vec![format!("PING :{}", SERVER)],
+            ServerMessage::Pong => {
+                let token = self.shared.lock().unwrap().pings.pop_front();
+                let token = token.unwrap_or_else(|| SERVER.to_string());
+                vec![format!(":{} PONG {} :{}", SERVER, SERVER, token)]
+            }
+        }
+    }
+
+    /// Tells the client it is in `room`, along with who else is and what the
+    /// topic is, unless it already knew.
+    fn joined_room(&mut self, nick: &str, room: &str) -> Vec<String> {
+        if !self.joined.insert(room.to_string()) {
+            return vec![];
+        }
+
+        let _ = self.inject.send(command_msg("topic", room));
+        let _ = self.inject.send(command_msg("names", room));
+        vec![format!(":{} JOIN {}", prefix(nick), room)]
+    }
+}
+
+fn prefix(nick: &str) -> String {
+    format!("{}!{}@{}", nick, nick, SERVER)
+}
+
+/// A line break in a message would end the IRC line early, and whatever
+/// followed it would be read as a command of its own.
+fn text_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split(['\r', '\n']).filter(|line| !line.is_empty())
+}
+
+/// Reads CRLF (or just LF) terminated lines. A line longer than the client
+/// may send is reported once and the rest of it thrown away.
+struct Lines<S> {
+    reader: ReadHalf<S>,
+    buff: Vec<u8>,
+    data: Vec<u8>,
+    max: usize,
+    discarding: bool,
+    inbound: Inbound,
+    pending: VecDeque<ClientMessage>,
+    inject: UnboundedReceiver<ClientMessage>,
+}
+
+impl<S> Lines<S> {
+    fn next_line(&mut self) -> Option<Result<String, FrameError>> {
+        loop {
+            let Some(end) = self.data.iter().position(|&b| b == b'\n') else {
+                if self.discarding {
+                    self.data.clear();
+                } else if self.data.len() > self.max {
+                    let len = self.data.len();
+                    self.data.clear();
+                    self.discarding = true;
+                    return Some(Err(FrameError::TooLarge { len, max: self.max }));
+                }
+                return None;
+            };
+
+            let line = self.data.drain(..=end).collect::<Vec<_>>();
+            if std::mem::take(&mut self.discarding) {
+                continue;
+            }
+            if line.len() > self.max {
+                let len = line.len();
+                return Some(Err(FrameError::TooLarge { len, max: self.max }));
+            }
+
+            let line = String::from_utf8_lossy(&line);
+            return Some(Ok(line.trim_end_matches(['\r', '\n']).to_string()));
+        }
+    }
+}
+
+impl<S: AsyncRead + Send> Incoming for Lines<S> {
+    // chat text is checked as lines come in; the messages they turn into are
+    // escaped JSON, which may well run longer
+    fn max_message_size(&self, _: &Config) -> usize {
+        usize::MAX
+    }
+
+    async fn next(&mut self) -> io::Result<Option<Result<Vec<u8>, FrameError>>> {
+        loop {
+            if let Some(msg) = self.pending.pop_front() {
+                return Ok(Some(Ok(encode_message(&msg))));
+            }
+
+            match self.next_line() {
+                Some(Ok(line)) => match self.inbound.translate(&line) {
+                    Some(Ok(msgs)) => self.pending.extend(msgs),
+                    Some(Err(err)) => return Ok(Some(Err(err))),
+                    None => return Ok(None),
+                },
+                Some(Err(err)) => return Ok(Some(Err(err))),
+                None => {
+                    tokio::select! {
+                        Some(msg) = self.inject.recv() => self.pending.push_back(msg),
+                        n = self.reader.read(&mut self.buff) => {
+                            let n = n?;
+                            if n == 0 {
+                                return Ok(None);
+                            }
+                            metrics::add(&METRICS.bytes_in, n);
+                            self.data.extend_from_slice(&self.buff[..n]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Replies<S> {
+    writer: WriteHalf<S>,
+    outbound: Outbound,
+}
+
+impl<S: AsyncWrite + Send + 'static> Outgoing for Replies<S> {
+    async fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        let Ok(msg) = decode_message::<ServerMessage>(&payload) else {
+            return Ok(());
+        };
+
+        let mut buff = String::new();
+        for line in self.outbound.translate(msg) {
+            buff.push_str(&line);
+            buff.push_str("\r\n");
+        }
+        metrics::add(&METRICS.bytes_out, buff.len());
+        self.writer.write_all(buff.as_bytes()).await
+    }
+
+    async fn close(&mut self) {
+        let _ = self.writer.shutdown().await;
+    }
+}
+
+/// Serves a client that speaks IRC.
+pub async fn handle<S>(
+    socket: S,
+    peer: Peer,
+    id: ClientId,
+    events: UnboundedSender<Event>,
+    config: &Config,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = io::split(socket);
+    let shared = Arc::new(Mutex::new(Shared::default()));
+    let (inject, inject_rx) = mpsc::unbounded_channel();
+
+    let lines = Lines {
+        reader,
+        buff: vec![0; 4096],
+        data: vec![],
+        max: config.max_message_size + LINE_OVERHEAD,
+        discarding: false,
+        inbound: Inbound::new(shared.clone(), config.max_message_size),
+        pending: VecDeque::new(),
+        inject: inject_rx,
+    };
+    let replies = Replies {
+        writer,
+        outbound: Outbound::new(shared, inject),
+    };
+
+    connection::serve(lines, replies, peer, id, events, config).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(room: &str, from: &str, text: &str, time: Option<u64>) -> ServerMessage {
+        ServerMessage::Chat {
+            id: 1,
+            room: room.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+            time: time.unwrap_or(0),
+            replayed: time.is_some(),
+            edited: false,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("@time=x :alice!a@host privmsg #rust :hi there :)"),
+            Some((
+                String::from("PRIVMSG"),
+                vec![String::from("#rust"), String::from("hi there :)")]
+            ))
+        );
+        assert_eq!(
+            parse("USER alice 0 * :Alice Liddell"),
+            Some((
+                String::from("USER"),
+                vec![
+                    String::from("alice"),
+                    String::from("0"),
+                    String::from("*"),
+                    String::from("Alice Liddell")
+                ]
+            ))
+        );
+        assert_eq!(parse("   "), None);
+    }
+
+    #[test]
+    fn test_inbound() {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let mut inbound = Inbound::new(shared.clone(), 4096);
+
+        assert_eq!(inbound.translate("CAP LS 302"), Some(Ok(vec![])));
+        assert_eq!(inbound.translate("PASS hunter22"), Some(Ok(vec![])));
+        assert_eq!(inbound.translate("NICK alice"), Some(Ok(vec![])));
+        assert_eq!(
+            inbound.translate("USER alice 0 * :Alice"),
+            Some(Ok(vec![command_msg("login", "alice hunter22")]))
+        );
+
+        shared.lock().unwrap().registered = true;
+        assert_eq!(
+            inbound.translate("NICK alicia"),
+            Some(Ok(vec![command_msg("nick", "alicia")]))
+        );
+        assert_eq!(
+            inbound.translate("JOIN #rust,#go"),
+            Some(Ok(vec![
+                command_msg("join", "#rust"),
+                command_msg("join", "#go")
+            ]))
+        );
+        assert_eq!(
+            inbound.translate("PRIVMSG #rust,bob :\x01ACTION waves\x01"),
+            Some(Ok(vec![
+                ClientMessage::Chat {
+                    room: Some(String::from("#rust")),
+                    text: String::from("*waves*"),
+                },
+                command_msg("msg", "bob *waves*"),
+            ]))
+        );
+        assert_eq!(
+            inbound.translate("PRIVMSG bob :\x01VERSION\x01"),
+            Some(Ok(vec![]))
+        );
+        assert_eq!(
+            inbound.translate("TOPIC #rust :all things rust"),
+            Some(Ok(vec![command_msg("topic", "#rust all things rust")]))
+        );
+        assert_eq!(
+            inbound.translate("history #rust"),
+            Some(Ok(vec![command_msg("history", "#rust")]))
+        );
+
+        assert_eq!(
+            inbound.translate("PING :abc"),
+            Some(Ok(vec![ClientMessage::Ping]))
+        );
+        assert_eq!(shared.lock().unwrap().pings, ["abc"]);
+        assert_eq!(inbound.translate("QUIT :bye"), None);
+    }
+
+    #[test]
+    fn test_outbound() {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let (inject, mut injected) = mpsc::unbounded_channel();
+        let mut outbound = Outbound::new(shared.clone(), inject);
+
+        shared.lock().unwrap().nick = Some(String::from("bob"));
+        assert_eq!(
+            outbound.translate(ServerMessage::Error {
+                code: ErrorCode::NickInUse,
+                text: String::from("nickname bob is already taken"),
+            }),
+            [":chat 433 * bob :nickname bob is already taken"]
+        );
+        // anything else isn't about the nickname, and says so
+        assert_eq!(
+            outbound.translate(ServerMessage::Error {
+                code: ErrorCode::Refused,
+                text: String::from("you are not in any room"),
+            }),
+            [":chat NOTICE bob :you are not in any room"]
+        );
+
+        // held until the client has a nickname
+        let notice = ServerMessage::Notice {
+            text: String::from("be nice"),
+        };
+        assert!(outbound.translate(notice).is_empty());
+        let old = chat("#general", "alice", "earlier", Some(90061));
+        assert!(outbound.translate(old).is_empty());
+
+        let lines = outbound.translate(ServerMessage::Nick {
+            old: None,
+            new: String::from("robert"),
+        });
+        assert!(lines[0].starts_with(":chat 001 robert "));
+        assert!(lines.contains(&String::from(":chat 372 robert :- be nice")));
+        assert_eq!(
+            lines[lines.len() - 2..],
+            [
+                ":robert!robert@chat JOIN #general",
+                ":alice!alice@chat PRIVMSG #general :[01:01] earlier",
+            ]
+        );
+        assert_eq!(
+            injected.try_recv().unwrap(),
+            command_msg("topic", "#general")
+        );
+        assert_eq!(
+            injected.try_recv().unwrap(),
+            command_msg("names", "#general")
+        );
+
+        // no echo of what the client said itself
+        assert!(outbound
+            .translate(chat("#general", "robert", "hi", None))
+            .is_empty());
+        assert_eq!(
+            outbound.translate(chat("#general", "alice", "one\r\nQUIT", None)),
+            [
+                ":alice!alice@chat PRIVMSG #general :one",
+                ":alice!alice@chat PRIVMSG #general :QUIT",
+            ]
+        );
+        assert_eq!(
+            outbound.translate(ServerMessage::Names {
+                room: String::from("#general"),
+                nicks: vec![String::from("alice"), String::from("robert")],
+            }),
+            [
+                ":chat 353 robert = #general :alice robert",
+                ":chat 366 robert #general :End of /NAMES list.",
+            ]
+        );
+        assert_eq!(
+            outbound.translate(ServerMessage::Nick {
+                old: Some(String::from("robert")),
+                new: String::from("bob"),
+            }),
+            [":robert!robert@chat NICK bob"]
+        );
+        assert_eq!(
+            outbound.translate(ServerMessage::Reply {
+                command: String::from("part"),
+                room: Some(String::from("#general")),
+                text: String::from("left #general"),
+            }),
+            [":bob!bob@chat PART #general"]
+        );
+
+        shared.lock().unwrap().pings.push_back(String::from("abc"));
+        assert_eq!(
+            outbound.translate(ServerMessage::Pong),
+            [":chat PONG chat :abc"]
+        );
+    }
+
+    #[test]
+    fn test_long_lines() {
+        let (_inject, inject_rx) = mpsc::unbounded_channel();
+        let (reader, _) = io::split(io::empty());
+        let mut lines = Lines {
+            reader,
+            buff: vec![],
+            data: vec![],
+            max: 8,
+            discarding: false,
+            inbound: Inbound::new(Arc::default(), 8),
+            pending: VecDeque::new(),
+            inject: inject_rx,
+        };
+
+        lines.data.extend_from_slice(b"PING a\r\nPRIVMSG #rust :");
+        assert_eq!(lines.next_line().unwrap().unwrap(), "PING a");
+        assert!(matches!(
+            lines.next_line(),
+            Some(Err(FrameError::TooLarge { len: 15, max: 8 }))
+        ));
+        lines.data.extend_from_slice(b"still going\r\nPONG\r\n");
+        assert_eq!(lines.next_line().unwrap().unwrap(), "PONG");
+        assert!(lines.next_line().is_none());
+    }
+
+    #[test]
+    fn test_text_limit() {
+        let (_inject, inject_rx) = mpsc::unbounded_channel();
+        let (reader, _) = io::split(io::empty());
+        let mut lines = Lines {
+            reader,
+            buff: vec![],
+            data: vec![],
+            max: 16 + LINE_OVERHEAD,
+            discarding: false,
+            inbound: Inbound::new(Arc::default(), 16),
+            pending: VecDeque::new(),
+            inject: inject_rx,
+        };
+
+        // escaping makes this longer than 16 bytes once it's JSON, which
+        // mustn't count against it
+        let text = "\"quoted\"\t\\tabs\\!";
+        assert_eq!(text.len(), 16);
+        let line = format!("PRIVMSG #a-long-room-name,bob :{}\r\n", text);
+        lines.data.extend_from_slice(line.as_bytes());
+        let line = lines.next_line().unwrap().unwrap();
+        assert_eq!(
+            lines.inbound.translate(&line),
+            Some(Ok(vec![
+                ClientMessage::Chat {
+                    room: Some(String::from("#a-long-room-name")),
+                    text: text.to_string(),
+                },
+                command_msg("msg", &format!("bob {}", text)),
+            ]))
+        );
+        assert!(
+            encode_message(&ClientMessage::Chat {
+                room: None,
+                text: text.to_string()
+            })
+            .len()
+                > 16
+        );
+
+        assert_eq!(
+            lines.inbound.translate(&format!("PRIVMSG #a :{}!", text)),
+            Some(Err(FrameError::TooLarge { len: 17, max: 16 }))
+        );
+    }
+}
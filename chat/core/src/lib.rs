@@ -0,0 +1,41 @@
+/**
+ * Everything the chat server and client are made of, so that both can be
+ * embedded and tested without going through the binaries. `Server` runs the
+ * hub and its listeners, `Client` speaks the protocol to one, and `Rooms` is
+ * the registry of who is in which room.
+ *
+ * The wire format itself lives in the `protocol` crate, re-exported here.
+**/
+pub use protocol;
+
+pub mod client;
+pub mod config;
+pub mod files;
+pub mod logger;
+pub mod rooms;
+pub mod server;
+pub mod tls;
+
+mod accounts;
+mod bans;
+mod commands;
+mod connection;
+mod federation;
+mod flood;
+mod history;
+mod hub;
+mod irc;
+mod links;
+mod metrics;
+mod queue;
+mod transfers;
+#[cfg(unix)]
+mod unix;
+mod websocket;
+
+pub use accounts::GuestPolicy;
+pub use client::Client;
+pub use config::Config;
+pub use hub::ClientId;
+pub use rooms::Rooms;
+pub use server::{Server, Transport};
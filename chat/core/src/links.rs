@@ -0,0 +1,244 @@
+/**
+ * Connections to other servers, for sharing rooms with them. A server dials
+ * every peer in `links`, dialling again for as long as it can't get through
+ * or the link drops, and takes links from peers on `link-port`.
+ *
+ * The dialling server says hello first, and only once its password checks
+ * out does the other answer, so the password never goes to just anyone who
+ * connects. After that the link belongs to the hub, see `federation`.
+ *
+ * Links are plain TCP even when clients are served TLS: the password and
+ * everything said in shared rooms cross the network in the clear. They are
+ * meant for networks the servers trust, or a tunnel (WireGuard, ssh -L)
+ * between them.
+**/
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use protocol::{decode_message, encode_message, LinkMessage, DEFAULT_MAX_FRAME_SIZE};
+use sha2::{Digest, Sha256};
+use tokio::io::{self, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+use crate::connection::{Frames, Incoming, Outgoing};
+use crate::hub::Event;
+use crate::log;
+
+/// How long a peer has to say hello.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Links still saying hello, beyond which more are turned away. Peers are
+/// few, so this only bites when someone else is knocking.
+const MAX_PENDING: usize = 16;
+
+/// Dialling again backs off from the first to the second.
+const MIN_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+static NEXT_LINK: AtomicU64 = AtomicU64::new(0);
+
+/// Takes links from other servers.
+pub async fn listen(server: TcpListener, events: UnboundedSender<Event>, config: Arc<Config>) {
+    let pending = Arc::new(Semaphore::new(MAX_PENDING));
+
+    loop {
+        let (socket, addr) = match server.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log!("failed to accept link: {}", err);
+                continue;
+            }
+        };
+
+        let Ok(permit) = pending.clone().try_acquire_owned() else {
+            log!("refusing link from {}: too many handshakes under way", addr);
+            continue;
+        };
+        let events = events.clone();
+        let config = config.clone();
+        tokio::spawn(async move { run(socket, addr, Some(permit), events, &config).await });
+    }
+}
+
+/// Keeps a link to `peer` up for as long as the server runs.
+pub async fn dial(peer: String, events: UnboundedSender<Event>, config: Arc<Config>) {
+    let mut retry = MIN_RETRY;
+
+    loop {
+        let started = Instant::now();
+        match TcpStream::connect(peer.as_str()).await {
+            Ok(socket) => {
+                let addr = socket
+                    .peer_addr()
+                    .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+                run(socket, addr, None, events.clone(), &config).await;
+            }
+            Err(err) => log!("failed to link to {}: {}", peer, err),
+        }
+
+        // a link that was up for a good while starts the backoff over, one
+        // refused straight away doesn't
+        if started.elapsed() > MAX_RETRY {
+            retry = MIN_RETRY;
+        }
+        time::sleep(retry).await;
+        retry = (retry * 2).min(MAX_RETRY);
+    }
+}
+
+/// Compares digests, every byte of them, so the time taken gives away
+/// neither how long the password is nor how much of it a peer got right.
+fn same_password(given: &str, password: &str) -> bool {
+    let (given, password) = (Sha256::digest(given), Sha256::digest(password));
+    given
+        .iter()
+        .zip(password.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Links that were dialled come without a `pending` permit; ones that were
+/// taken hold theirs until the peer has said hello.
+async fn run(
+    socket: TcpStream,
+    addr: SocketAddr,
+    pending: Option<OwnedSemaphorePermit>,
+    events: UnboundedSender<Event>,
+    config: &Config,
+) {
+    let (Some(name), Some(password)) = (&config.server_name, &config.link_password) else {
+        return;
+    };
+    let (reader, mut writer) = io::split(socket);
+    let mut frames = Frames::new(reader, DEFAULT_MAX_FRAME_SIZE);
+
+    // the whole exchange is timed, a peer that won't read our hello holds
+    // things up as much as one that won't send its own
+    let dialled = pending.is_none();
+    let handshake = handshake(&mut frames, &mut writer, addr, dialled, name, password);
+    let peer = match time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Some(peer)) => peer,
+        Ok(None) => return,
+        Err(_) => {
+            log!("link handshake with {} timed out", addr);
+            return;
+        }
+    };
+    drop(pending);
+
+    let link = NEXT_LINK.fetch_add(1, Ordering::Relaxed) + 1;
+    let (tx, rx) = mpsc::unbounded_channel::<LinkMessage>();
+    if events
+        .send(Event::LinkUp {
+            link,
+            name: peer,
+            tx,
+        })
+        .is_err()
+    {
+        return;
+    }
+
+    let mut writer_task = tokio::spawn(write(writer, rx, config.ping_interval));
+
+    // both ends ping, so silence for `idle_timeout` means the peer is gone
+    let reason = loop {
+        let next = tokio::select! {
+            next = time::timeout(config.idle_timeout, frames.next()) => next,
+            _ = &mut writer_task => break "closed",
+        };
+
+        match next {
+            Ok(Ok(Some(Ok(payload)))) => match decode_message(&payload) {
+                Ok(LinkMessage::Ping) => (),
+                Ok(msg) => {
+                    let _ = events.send(Event::Link { link, msg });
+                }
+                Err(err) => log!("link {}: dropping message: {}", addr, err),
+            },
+            Ok(Ok(Some(Err(err)))) => log!("link {}: dropping message: {}", addr, err),
+            Ok(Ok(None)) => break "closed by peer",
+            Ok(Err(_)) => break "connection lost",
+            Err(_) => break "timed out",
+        }
+    };
+
+    writer_task.abort();
+    let _ = events.send(Event::LinkDown {
+        link,
+        reason: reason.to_string(),
+    });
+}
+
+/// Swaps hellos, the dialling server first, returning the peer's name once
+/// its password checks out.
+async fn handshake(
+    frames: &mut Frames<TcpStream>,
+    writer: &mut WriteHalf<TcpStream>,
+    addr: SocketAddr,
+    dialled: bool,
+    name: &str,
+    password: &str,
+) -> Option<String> {
+    let hello = encode_message(&LinkMessage::Hello {
+        name: name.to_string(),
+        password: password.to_string(),
+    });
+
+    if dialled && writer.send(hello.clone()).await.is_err() {
+        return None;
+    }
+    let peer = match frames.next().await {
+        Ok(Some(Ok(payload))) => decode_message(&payload).ok(),
+        _ => None,
+    };
+    let peer = match peer {
+        Some(LinkMessage::Hello {
+            name,
+            password: given,
+        }) if same_password(&given, password) => name,
+        Some(LinkMessage::Hello { name, .. }) => {
+            log!("refusing link from {} ({}): wrong password", name, addr);
+            return None;
+        }
+        _ => {
+            log!("link handshake with {} failed", addr);
+            return None;
+        }
+    };
+    if !dialled && writer.send(hello).await.is_err() {
+        return None;
+    }
+    Some(peer)
+}
+
+/// Writes what the hub sends, until the hub lets go of the link.
+async fn write(
+    mut writer: WriteHalf<TcpStream>,
+    mut rx: mpsc::UnboundedReceiver<LinkMessage>,
+    ping_interval: Duration,
+) {
+    let mut pings = time::interval_at(Instant::now() + ping_interval, ping_interval);
+
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = pings.tick() => LinkMessage::Ping,
+        };
+
+        if writer.send(encode_message(&msg)).await.is_err() {
+            return;
+        }
+    }
+
+    writer.close().await;
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Like `println!`, but also appends a timestamped copy of the line to the
+/// log file when one is configured.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::logger::write(format_args!($($arg)*))
+    };
+}
+
+pub fn init(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *LOG_FILE.lock().unwrap_or_else(|err| err.into_inner()) = Some(file);
+
+    Ok(())
+}
+
+pub fn write(args: fmt::Arguments) {
+    println!("{}", args);
+
+    let mut file = LOG_FILE.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(file) = file.as_mut() {
+        let _ = writeln!(file, "{} {}", timestamp(), args);
+    }
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ` for the current time.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+/// Days since 1970-01-01 to a (year, month, day) date, from Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19723), (2024, 1, 1));
+    }
+}
@@ -0,0 +1,271 @@
+/**
+ * Counters for Prometheus to scrape over plain HTTP, at `/metrics` on the
+ * metrics port. Like the log they are global to the process, so anything
+ * can count without being handed a registry.
+ *
+ * Rates, such as messages a second, are left to Prometheus: everything that
+ * only ever goes up is a `_total` counter to take the `rate()` of.
+**/
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use crate::log;
+use crate::queue;
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// A request that hasn't arrived whole by then is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 8192;
+
+pub struct Metrics {
+    pub connections: AtomicU64,
+    pub turned_away_full: AtomicU64,
+    pub turned_away_banned: AtomicU64,
+    pub clients: AtomicU64,
+    pub rooms: AtomicU64,
+    pub messages_received: AtomicU64,
+    pub messages_sent: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub failed_writes: AtomicU64,
+    pub queue_dropped: AtomicU64,
+    pub slow_disconnects: AtomicU64,
+    // command name -> times used
+    commands: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            connections: AtomicU64::new(0),
+            turned_away_full: AtomicU64::new(0),
+            turned_away_banned: AtomicU64::new(0),
+            clients: AtomicU64::new(0),
+            rooms: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            failed_writes: AtomicU64::new(0),
+            queue_dropped: AtomicU64::new(0),
+            slow_disconnects: AtomicU64::new(0),
+            commands: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Only called for commands that parsed, so clients can't make up new
+    /// label values.
+    pub fn command(&self, name: &str) {
+        let mut commands = self.commands.lock().unwrap_or_else(|err| err.into_inner());
+        *commands.entry(name.to_string()).or_default() += 1;
+    }
+
+    /// The Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in values {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        metric(
+            "chat_connections_total",
+            "counter",
+            "Connections accepted.",
+            &[("", get(&self.connections))],
+        );
+        metric(
+            "chat_connections_turned_away_total",
+            "counter",
+            "Connections refused before being served.",
+            &[
+                ("{reason=\"full\"}", get(&self.turned_away_full)),
+                ("{reason=\"banned\"}", get(&self.turned_away_banned)),
+            ],
+        );
+        metric(
+            "chat_clients",
+            "gauge",
+            "Clients connected.",
+            &[("", get(&self.clients))],
+        );
+        metric("chat_rooms", "gauge", "Rooms.", &[("", get(&self.rooms))]);
+        metric(
+            "chat_messages_received_total",
+            "counter",
+            "Messages received from clients, commands included.",
+            &[("", get(&self.messages_received))],
+        );
+        metric(
+            "chat_messages_sent_total",
+            "counter",
+            "Messages queued for clients, counting each recipient.",
+            &[("", get(&self.messages_sent))],
+        );
+        metric(
+            "chat_bytes_received_total",
+            "counter",
+            "Bytes read from clients.",
+            &[("", get(&self.bytes_in))],
+        );
+        metric(
+            "chat_bytes_sent_total",
+            "counter",
+            "Bytes written to clients.",
+            &[("", get(&self.bytes_out))],
+        );
+        metric(
+            "chat_failed_writes_total",
+            "counter",
+            "Messages that could not be delivered to a client.",
+            &[("", get(&self.failed_writes))],
+        );
+
+        // read off the queues themselves rather than kept up to date on
+        // every send
+        let (queued, deepest) = queue::depths();
+        metric(
+            "chat_queued_messages",
+            "gauge",
+            "Messages waiting in client queues to be written.",
+            &[("", queued as u64)],
+        );
+        metric(
+            "chat_queue_depth_max",
+            "gauge",
+            "Messages waiting in the fullest client queue.",
+            &[("", deepest as u64)],
+        );
+        metric(
+            "chat_queue_dropped_total",
+            "counter",
+            "Messages dropped from full client queues.",
+            &[("", get(&self.queue_dropped))],
+        );
+        metric(
+            "chat_slow_disconnects_total",
+            "counter",
+            "Clients disconnected for letting their queue fill up.",
+            &[("", get(&self.slow_disconnects))],
+        );
+
+        let commands = self
+            .commands
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .map(|(name, &count)| (format!("{{command=\"{}\"}}", name), count))
+            .collect::<Vec<_>>();
+        let commands = commands
+            .iter()
+            .map(|(labels, count)| (labels.as_str(), *count))
+            .collect::<Vec<_>>();
+        metric(
+            "chat_commands_total",
+            "counter",
+            "Commands used, by name.",
+            &commands,
+        );
+
+        out
+    }
+}
+
+pub fn add(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+pub fn set(gauge: &AtomicU64, n: usize) {
+    gauge.store(n as u64, Ordering::Relaxed);
+}
+
+pub async fn listen(server: TcpListener) {
+    loop {
+        match server.accept().await {
+            Ok((socket, _)) => {
+                tokio::spawn(respond(socket));
+            }
+            Err(err) => log!("failed to accept metrics connection: {}", err),
+        }
+    }
+}
+
+/// Answers a single request, then closes the connection.
+async fn respond(mut socket: TcpStream) {
+    let mut request = vec![];
+    let mut buff = [0; 1024];
+
+    let read = time::timeout(REQUEST_TIMEOUT, async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = socket.read(&mut buff).await?;
+            if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+                return Err(std::io::ErrorKind::InvalidData.into());
+            }
+            request.extend_from_slice(&buff[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await;
+    if !matches!(read, Ok(Ok(()))) {
+        return;
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let path = match (words.next(), words.next()) {
+        (Some("GET"), Some(path)) => path.split('?').next().unwrap_or(path),
+        _ => "",
+    };
+
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", METRICS.render()),
+        _ => ("404 Not Found", "text/plain", String::from("not found\n")),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+
+    let _ = socket.write_all(response.as_bytes()).await;
+    let _ = socket.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        add(&metrics.messages_received, 3);
+        set(&metrics.clients, 2);
+        metrics.command("join");
+        metrics.command("join");
+        metrics.command("nick");
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE chat_clients gauge\nchat_clients 2\n"));
+        assert!(text.contains("chat_messages_received_total 3\n"));
+        assert!(text.contains("chat_connections_turned_away_total{reason=\"full\"} 0\n"));
+        assert!(text.contains(
+            "# TYPE chat_commands_total counter\n\
+             chat_commands_total{command=\"join\"} 2\n\
+             chat_commands_total{command=\"nick\"} 1\n"
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+/**
+ * Each client's outbound messages wait in a queue of their own, which the
+ * hub fills and the client's writer task drains at whatever pace its socket
+ * allows. The hub never waits on a socket, so one slow reader holds up
+ * nobody else.
+ *
+ * A queue holds at most `queue-size` messages. A client that falls that far
+ * behind either loses its oldest messages or is disconnected, depending on
+ * `slow-clients`.
+**/
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use protocol::ServerMessage;
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+use crate::metrics::{self, METRICS};
+
+/// Every live queue, for the depth metrics.
+static QUEUES: Mutex<Vec<Weak<Shared>>> = Mutex::new(Vec::new());
+
+/// What happens to a client whose queue is full.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowPolicy {
+    /// Make room by throwing away the oldest queued message.
+    DropOldest,
+    Disconnect,
+}
+
+impl SlowPolicy {
+    pub fn parse(s: &str) -> Option<SlowPolicy> {
+        match s {
+            "drop-oldest" => Some(SlowPolicy::DropOldest),
+            "disconnect" => Some(SlowPolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum SendError {
+    /// The writer task is gone.
+    Closed,
+    /// The queue was full and the client is to be disconnected.
+    TooSlow,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    messages: VecDeque<ServerMessage>,
+    sender_gone: bool,
+    receiver_gone: bool,
+    too_slow: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    // wakes the receiver for a new message or a closed queue
+    ready: Notify,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+pub fn channel(capacity: usize, policy: SlowPolicy) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared::default());
+
+    let mut queues = QUEUES.lock().unwrap_or_else(|err| err.into_inner());
+    queues.retain(|queue| queue.strong_count() > 0);
+    queues.push(Arc::downgrade(&shared));
+    drop(queues);
+
+    let sender = Sender {
+        shared: shared.clone(),
+        capacity: capacity.max(1),
+        policy,
+    };
+    (sender, Receiver { shared })
+}
+
+/// The hub's end. Dropping it lets the writer send what's left and close.
+#[derive(Debug)]
+pub struct Sender {
+    shared: Arc<Shared>,
+    capacity: usize,
+    policy: SlowPolicy,
+}
+
+impl Sender {
+    pub fn send(&self, msg: ServerMessage) -> Result<(), SendError> {
+        let mut state = self.shared.lock();
+        if state.too_slow {
+            return Err(SendError::TooSlow);
+        }
+        if state.receiver_gone {
+            return Err(SendError::Closed);
+        }
+
+        if state.messages.len() >= self.capacity {
+            match self.policy {
+                SlowPolicy::DropOldest => {
+                    state.messages.pop_front();
+                    metrics::add(&METRICS.queue_dropped, 1);
+                }
+                SlowPolicy::Disconnect => {
+                    state.too_slow = true;
+                    state.messages.clear();
+                    metrics::add(&METRICS.slow_disconnects, 1);
+                    drop(state);
+                    self.shared.ready.notify_one();
+                    return Err(SendError::TooSlow);
+                }
+            }
+        }
+
+        state.messages.push_back(msg);
+        drop(state);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.shared.lock().sender_gone = true;
+        self.shared.ready.notify_one();
+    }
+}
+
+/// The writer task's end.
+#[derive(Debug)]
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    /// The next message, or `None` once the hub has let go of the client and
+    /// everything queued has been taken. Cancel safe.
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        loop {
+            if let Some(msg) = self.try_recv() {
+                return Some(msg);
+            }
+            if self.is_closed() {
+                return None;
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<ServerMessage> {
+        let mut state = self.shared.lock();
+        if state.too_slow {
+            return None;
+        }
+        state.messages.pop_front()
+    }
+
+    /// Whether nothing more will come, because the hub has let go of the
+    /// client or given up on it.
+    pub fn is_closed(&self) -> bool {
+        let state = self.shared.lock();
+        state.sender_gone || state.too_slow
+    }
+
+    /// Whether the queue overflowed under `SlowPolicy::Disconnect`.
+    pub fn too_slow(&self) -> bool {
+        self.shared.lock().too_slow
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_gone = true;
+    }
+}
+
+/// (messages queued for all clients together, the deepest single queue).
+pub fn depths() -> (usize, usize) {
+    let queues = QUEUES.lock().unwrap_or_else(|err| err.into_inner());
+
+    queues
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|queue| queue.lock().messages.len())
+        .fold((0, 0), |(total, max), len| (total + len, max.max(len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice(text: &str) -> ServerMessage {
+        ServerMessage::Notice {
+            text: text.to_string(),
+        }
+    }
+
+    fn texts(rx: &mut Receiver) -> Vec<String> {
+        let mut texts = vec![];
+        while let Some(msg) = rx.try_recv() {
+            texts.push(msg.to_string());
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let (tx, mut rx) = channel(2, SlowPolicy::DropOldest);
+        for text in ["a", "b", "c"] {
+            assert_eq!(tx.send(notice(text)), Ok(()));
+        }
+        assert_eq!(texts(&mut rx), vec!["* b", "* c"]);
+
+        // what's queued still goes out after the hub lets go
+        tx.send(notice("d")).unwrap();
+        drop(tx);
+        assert!(rx.is_closed());
+        assert_eq!(rx.recv().await, Some(notice("d")));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        let (tx, mut rx) = channel(2, SlowPolicy::Disconnect);
+        tx.send(notice("a")).unwrap();
+        tx.send(notice("b")).unwrap();
+        assert_eq!(tx.send(notice("c")), Err(SendError::TooSlow));
+        assert!(rx.too_slow());
+        assert_eq!(tx.send(notice("d")), Err(SendError::TooSlow));
+
+        // nothing more is written to a client that has been given up on
+        assert_eq!(rx.recv().await, None);
+
+        let (tx, rx) = channel(2, SlowPolicy::Disconnect);
+        drop(rx);
+        assert_eq!(tx.send(notice("a")), Err(SendError::Closed));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Instant;
+
+use crate::hub::ClientId;
+
+pub const DEFAULT_ROOM: &str = "#general";
+pub const MAX_ROOM_LEN: usize = 32;
+
+/// Room name -> members. A room exists for as long as it has at least one
+/// member, so joining an unknown room creates it. Its topic, operators and
+/// mutes go with it. Operators are the clients made one, while mutes are by
+/// nickname and so outlast the connection they were made against.
+#[derive(Debug, Default)]
+pub struct Rooms {
+    rooms: BTreeMap<String, BTreeSet<ClientId>>,
+    topics: BTreeMap<String, String>,
+    ops: BTreeMap<String, BTreeSet<ClientId>>,
+    // lowercased nickname -> when the mute runs out, if it does. By
+    // nickname rather than client, so reconnecting doesn't end it
+    mutes: BTreeMap<String, BTreeMap<String, Option<Instant>>>,
+}
+
+impl Rooms {
+    /// Returns false if the client was already a member.
+    pub fn join(&mut self, room: &str, id: ClientId) -> bool {
+        self.rooms.entry(room.to_string()).or_default().insert(id)
+    }
+
+    /// Returns false if the client was not a member.
+    pub fn part(&mut self, room: &str, id: ClientId) -> bool {
+        let Some(members) = self.rooms.get_mut(room) else {
+            return false;
+        };
+
+        let removed = members.remove(&id);
+        if members.is_empty() {
+            self.rooms.remove(room);
+            self.topics.remove(room);
+            self.ops.remove(room);
+            self.mutes.remove(room);
+        } else if let Some(ops) = self.ops.get_mut(room) {
+            // leaving costs operator status, but not a mute
+            ops.remove(&id);
+        }
+
+        removed
+    }
+
+    /// Removes the client from every room, returning the rooms it was in.
+    pub fn part_all(&mut self, id: ClientId) -> Vec<String> {
+        let rooms = self.rooms_of(id);
+        for room in &rooms {
+            self.part(room, id);
+        }
+
+        rooms
+    }
+
+    pub fn members(&self, room: &str) -> Vec<ClientId> {
+        self.rooms
+            .get(room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn rooms_of(&self, id: ClientId) -> Vec<String> {
+        self.rooms
+            .iter()
+            .filter(|(_, members)| members.contains(&id))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    /// Everyone sharing at least one room with the client, the client included.
+    pub fn peers(&self, id: ClientId) -> BTreeSet<ClientId> {
+        let mut peers = BTreeSet::from([id]);
+        for members in self.rooms.values().filter(|members| members.contains(&id)) {
+            peers.extend(members);
+        }
+
+        peers
+    }
+
+    pub fn topic(&self, room: &str) -> Option<&str> {
+        self.topics.get(room).map(String::as_str)
+    }
+
+    /// Does nothing if the room doesn't exist.
+    pub fn set_topic(&mut self, room: &str, topic: &str) {
+        if self.rooms.contains_key(room) {
+            self.topics.insert(room.to_string(), topic.to_string());
+        }
+    }
+
+    pub fn is_op(&self, room: &str, id: ClientId) -> bool {
+        self.ops
+            .get(room)
+            .map(|ops| ops.contains(&id))
+            .unwrap_or(false)
+    }
+
+    /// Only members can be operators. Returns false if nothing changed.
+    pub fn set_op(&mut self, room: &str, id: ClientId, op: bool) -> bool {
+        if !self.members(room).contains(&id) {
+            return false;
+        }
+
+        let ops = self.ops.entry(room.to_string()).or_default();
+        match op {
+            true => ops.insert(id),
+            false => ops.remove(&id),
+        }
+    }
+
+    /// Mutes `nick` until `until`, or until unmuted if that's `None`.
+    pub fn mute(&mut self, room: &str, nick: &str, until: Option<Instant>) {
+        if self.rooms.contains_key(room) {
+            self.mutes
+                .entry(room.to_string())
+                .or_default()
+                .insert(nick.to_lowercase(), until);
+        }
+    }
+
+    /// Returns false if `nick` wasn't muted.
+    pub fn unmute(&mut self, room: &str, nick: &str) -> bool {
+        self.mutes
+            .get_mut(room)
+            .map(|mutes| mutes.remove(&nick.to_lowercase()).is_some())
+            .unwrap_or(false)
+    }
+
+    pub fn is_muted(&self, room: &str, nick: &str, now: Instant) -> bool {
+        let mute = self
+            .mutes
+            .get(room)
+            .and_then(|mutes| mutes.get(&nick.to_lowercase()));
+        match mute {
+            Some(Some(until)) => *until > now,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Has a muted user's mutes follow them to a new nickname, while still
+    /// holding for the old one, so neither a rename nor renaming back gets
+    /// out of them.
+    pub fn rename(&mut self, old: &str, new: &str) {
+        let (old, new) = (old.to_lowercase(), new.to_lowercase());
+        for mutes in self.mutes.values_mut() {
+            if let Some(&until) = mutes.get(&old) {
+                mutes.insert(new.clone(), until);
+            }
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.rooms.len()
+    }
+
+    /// (room, member count) for every room, sorted by name.
+    pub fn list(&self) -> Vec<(String, usize)> {
+        self.rooms
+            .iter()
+            .map(|(room, members)| (room.clone(), members.len()))
+            .collect()
+    }
+}
+
+/// Room names are case-insensitive, so they are stored lowercased.
+pub fn normalize_room(room: &str) -> Result<String, String> {
+    let Some(name) = room.strip_prefix('#') else {
+        return Err(format!("room names must start with '#', try #{}", room));
+    };
+
+    if name.is_empty() || room.chars().count() > MAX_ROOM_LEN {
+        return Err(format!(
+            "room names must be between 2 and {} characters",
+            MAX_ROOM_LEN
+        ));
+    }
+
+    if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(String::from(
+            "room names may only contain letters, digits, '_' and '-'",
+        ));
+    }
+
+    Ok(room.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_and_part() {
+        let mut rooms = Rooms::default();
+
+        assert!(rooms.join("#rust", 1));
+        assert!(!rooms.join("#rust", 1));
+        assert!(rooms.join("#rust", 2));
+        assert!(rooms.join("#go", 2));
+
+        assert_eq!(rooms.members("#rust"), vec![1, 2]);
+        assert_eq!(rooms.rooms_of(2), vec!["#go", "#rust"]);
+        assert_eq!(
+            rooms.list(),
+            vec![(String::from("#go"), 1), (String::from("#rust"), 2)]
+        );
+
+        assert!(rooms.part("#go", 2));
+        assert!(!rooms.part("#go", 2));
+        assert_eq!(rooms.list(), vec![(String::from("#rust"), 2)]);
+    }
+
+    #[test]
+    fn test_part_all_and_peers() {
+        let mut rooms = Rooms::default();
+        rooms.join("#a", 1);
+        rooms.join("#a", 2);
+        rooms.join("#b", 1);
+        rooms.join("#b", 3);
+        rooms.join("#c", 4);
+
+        assert_eq!(rooms.peers(1), BTreeSet::from([1, 2, 3]));
+        assert_eq!(rooms.part_all(1), vec!["#a", "#b"]);
+        assert!(rooms.rooms_of(1).is_empty());
+        assert_eq!(rooms.peers(1), BTreeSet::from([1]));
+    }
+
+    #[test]
+    fn test_topic_goes_with_room() {
+        let mut rooms = Rooms::default();
+        rooms.set_topic("#rust", "nobody here");
+        assert_eq!(rooms.topic("#rust"), None);
+
+        rooms.join("#rust", 1);
+        rooms.set_topic("#rust", "borrowck support group");
+        assert_eq!(rooms.topic("#rust"), Some("borrowck support group"));
+
+        rooms.part("#rust", 1);
+        rooms.join("#rust", 1);
+        assert_eq!(rooms.topic("#rust"), None);
+    }
+
+    #[test]
+    fn test_ops_and_mutes() {
+        let mut rooms = Rooms::default();
+        rooms.join("#rust", 1);
+        rooms.join("#rust", 2);
+
+        assert!(rooms.set_op("#rust", 1, true));
+        assert!(!rooms.set_op("#rust", 3, true));
+        assert!(rooms.is_op("#rust", 1));
+        assert!(!rooms.is_op("#rust", 2));
+
+        let now = Instant::now();
+        rooms.mute("#rust", "Bob", None);
+        assert!(rooms.is_muted("#rust", "bob", now));
+        rooms.part("#rust", 2);
+        rooms.join("#rust", 2);
+        assert!(rooms.is_muted("#rust", "bob", now));
+        rooms.part("#rust", 1);
+        rooms.join("#rust", 1);
+        assert!(!rooms.is_op("#rust", 1));
+
+        rooms.rename("bob", "robert");
+        assert!(rooms.is_muted("#rust", "robert", now));
+        assert!(rooms.is_muted("#rust", "bob", now));
+        assert!(rooms.unmute("#rust", "robert"));
+        assert!(!rooms.unmute("#rust", "robert"));
+        assert!(rooms.unmute("#rust", "bob"));
+        rooms.mute("#rust", "bob", Some(now));
+        assert!(!rooms.is_muted("#rust", "bob", now));
+
+        // until the room goes away
+        rooms.mute("#rust", "bob", None);
+        rooms.part("#rust", 1);
+        rooms.part("#rust", 2);
+        rooms.join("#rust", 2);
+        assert!(!rooms.is_muted("#rust", "bob", now));
+    }
+
+    #[test]
+    fn test_normalize_room() {
+        assert_eq!(normalize_room("#Rust"), Ok(String::from("#rust")));
+        assert!(normalize_room("rust").is_err());
+        assert!(normalize_room("#").is_err());
+        assert!(normalize_room("#no spaces").is_err());
+    }
+}
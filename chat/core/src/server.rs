@@ -0,0 +1,438 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use protocol::{encode_frame, encode_message, ErrorCode, ServerMessage, DEFAULT_MAX_FRAME_SIZE};
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+
+use crate::accounts::Accounts;
+use crate::bans::{unix_now, Bans};
+use crate::config::{Config, Tls};
+use crate::connection::Peer;
+use crate::history::History;
+use crate::hub::{self, ClientId, Event, Hub};
+use crate::log;
+use crate::metrics::{self, METRICS};
+#[cfg(unix)]
+use crate::unix;
+use crate::{connection, irc, links, tls, websocket};
+
+/// What clients on a listener speak.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    Tcp,
+    WebSocket,
+    Irc,
+    /// Other servers, see `links`.
+    Link,
+}
+
+/// How long a client that is turned away has to hear why.
+const TURN_AWAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State shared by every listener's accept loop.
+struct Listeners {
+    config: Arc<Config>,
+    acceptor: Option<TlsAcceptor>,
+    bans: Arc<Mutex<Bans>>,
+    events: UnboundedSender<Event>,
+    next_id: AtomicU64,
+    // one permit per connected client, to enforce max-clients
+    slots: Arc<Semaphore>,
+}
+
+/// A chat server with every listener bound, ready to `run`. Binding to port 0
+/// picks a free port, which `local_addrs` then tells.
+pub struct Server {
+    hub: Hub,
+    events: UnboundedReceiver<Event>,
+    listeners: Arc<Listeners>,
+    servers: Vec<(TcpListener, Transport)>,
+    #[cfg(unix)]
+    unix: Option<UnixListener>,
+    metrics: Vec<TcpListener>,
+}
+
+impl Server {
+    /// Loads everything the config points at and binds its addresses. Has to
+    /// be called from within a Tokio runtime.
+    pub fn bind(config: Config) -> io::Result<Server> {
+        let acceptor = match &config.tls {
+            Tls::Off => None,
+            Tls::Files { cert, key } => {
+                Some(tls::acceptor(cert, key).map_err(context("failed to load TLS certificate"))?)
+            }
+            Tls::Dev { dir } => Some(
+                tls::dev_acceptor(dir)
+                    .map_err(context("failed to set up development TLS certificate"))?,
+            ),
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel::<Event>();
+        let history =
+            History::open(&config.history_dir).map_err(context("failed to open history"))?;
+        let accounts =
+            Accounts::open(&config.accounts).map_err(context("failed to open accounts"))?;
+        let bans = Bans::open(&config.bans).map_err(context("failed to open bans"))?;
+        let bans = Arc::new(Mutex::new(bans));
+        let hub = Hub::new(&config, history, accounts, bans.clone(), tx.clone());
+
+        let addrs = config
+            .bind
+            .iter()
+            .map(|&addr| (addr, Transport::Tcp))
+            .chain(
+                config
+                    .ws_bind
+                    .iter()
+                    .map(|&addr| (addr, Transport::WebSocket)),
+            )
+            .chain(config.irc_bind.iter().map(|&addr| (addr, Transport::Irc)))
+            .chain(config.link_bind.iter().map(|&addr| (addr, Transport::Link)));
+
+        let mut servers = vec![];
+        for (addr, transport) in addrs {
+            let server = bind(addr).map_err(context(&format!("failed to bind {}", addr)))?;
+            servers.push((server, transport));
+        }
+
+        #[cfg(unix)]
+        let unix = match &config.unix_socket {
+            Some(path) => Some(
+                unix::bind(path, config.unix_socket_mode)
+                    .map_err(context(&format!("failed to bind {}", path.display())))?,
+            ),
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if config.unix_socket.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets aren't supported on this platform",
+            ));
+        }
+
+        let mut metrics = vec![];
+        for &addr in &config.metrics_bind {
+            metrics.push(bind(addr).map_err(context(&format!("failed to bind {}", addr)))?);
+        }
+
+        let listeners = Arc::new(Listeners {
+            slots: Arc::new(Semaphore::new(config.max_clients)),
+            config: Arc::new(config),
+            acceptor,
+            bans,
+            events: tx,
+            next_id: AtomicU64::new(0),
+        });
+
+        Ok(Server {
+            hub,
+            events: rx,
+            listeners,
+            servers,
+            #[cfg(unix)]
+            unix,
+            metrics,
+        })
+    }
+
+    /// Where each listener ended up, metrics aside.
+    pub fn local_addrs(&self) -> Vec<(Transport, SocketAddr)> {
+        self.servers
+            .iter()
+            .filter_map(|(server, transport)| Some((*transport, server.local_addr().ok()?)))
+            .collect()
+    }
+
+    /// Serves clients until `shutdown` completes, then tells them and waits
+    /// up to `shutdown-timeout` for them to go.
+    pub async fn run(self, shutdown: impl Future<Output = ()>) {
+        let Server {
+            hub,
+            events,
+            listeners,
+            servers,
+            #[cfg(unix)]
+            unix,
+            metrics,
+        } = self;
+        let hub_task = tokio::spawn(hub::run(events, hub));
+
+        let mut tasks = vec![];
+        for (server, transport) in servers {
+            if let Ok(addr) = server.local_addr() {
+                match transport {
+                    Transport::Tcp => log!("listening on {}", addr),
+                    Transport::WebSocket => log!("listening for WebSocket clients on {}", addr),
+                    Transport::Irc => log!("listening for IRC clients on {}", addr),
+                    Transport::Link => log!("listening for links from servers on {}", addr),
+                }
+            }
+
+            let task = match transport {
+                Transport::Link => tokio::spawn(links::listen(
+                    server,
+                    listeners.events.clone(),
+                    listeners.config.clone(),
+                )),
+                _ => tokio::spawn(accept_loop(server, transport, listeners.clone())),
+            };
+            tasks.push(task);
+        }
+
+        for peer in &listeners.config.links {
+            tasks.push(tokio::spawn(links::dial(
+                peer.clone(),
+                listeners.events.clone(),
+                listeners.config.clone(),
+            )));
+        }
+
+        #[cfg(unix)]
+        if let Some(server) = unix {
+            if let Some(path) = &listeners.config.unix_socket {
+                log!("listening on {}", path.display());
+            }
+            tasks.push(tokio::spawn(accept_unix(server, listeners.clone())));
+        }
+
+        // plain HTTP even with TLS on, it's meant for a scraper on the inside
+        for server in metrics {
+            if let Ok(addr) = server.local_addr() {
+                log!("serving metrics on http://{}/metrics", addr);
+            }
+            tasks.push(tokio::spawn(metrics::listen(server)));
+        }
+
+        shutdown.await;
+        log!("shutting down");
+
+        for task in tasks {
+            task.abort();
+        }
+
+        let config = &listeners.config;
+        let _ = listeners.events.send(Event::Shutdown {
+            reason: config.shutdown_reason.clone(),
+        });
+
+        // every connection hands its slot back once it has closed
+        let all_slots = u32::try_from(config.max_clients).unwrap_or(u32::MAX);
+        let drained = time::timeout(config.shutdown_timeout, async {
+            let _ = hub_task.await;
+            let _ = listeners.slots.acquire_many(all_slots).await;
+        })
+        .await;
+
+        if drained.is_err() {
+            log!("gave up waiting for clients to disconnect");
+        }
+
+        if let Some(path) = &config.unix_socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn context(what: &str) -> impl Fn(io::Error) -> io::Error + '_ {
+    move |err| io::Error::new(err.kind(), format!("{}: {}", what, err))
+}
+
+/// IPv6 sockets are made v6-only so `0.0.0.0` and `::` can share a port.
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+async fn accept_loop(server: TcpListener, transport: Transport, listeners: Arc<Listeners>) {
+    loop {
+        let (socket, peer) = match server.accept().await {
+            Ok((socket, addr)) => (socket, Peer::Net(addr)),
+            Err(err) => {
+                log!("failed to accept connection: {}", err);
+                continue;
+            }
+        };
+
+        let slot = match admit(&listeners, peer) {
+            Ok(slot) => slot,
+            Err((code, text)) => {
+                turn_away(socket, listeners.acceptor.clone(), transport, code, text);
+                continue;
+            }
+        };
+        let id = listeners.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let listeners = listeners.clone();
+
+        // the handshake runs in the client's own task so a slow client can't
+        // hold up the accept loop
+        tokio::spawn(async move {
+            let events = listeners.events.clone();
+            let config = &listeners.config;
+
+            // a peer that never finishes the handshake would keep its slot
+            // for good, so it gets as long as an idle client does
+            match &listeners.acceptor {
+                Some(acceptor) => {
+                    match time::timeout(config.idle_timeout, acceptor.accept(socket)).await {
+                        Ok(Ok(stream)) => serve(transport, stream, peer, id, events, config).await,
+                        Ok(Err(err)) => log!("TLS handshake with {} failed: {}", peer, err),
+                        Err(_) => log!("TLS handshake with {} timed out", peer),
+                    }
+                }
+                None => serve(transport, socket, peer, id, events, config).await,
+            }
+
+            drop(slot);
+        });
+    }
+}
+
+/// Clients on the Unix socket are on this host, and go by the user the
+/// system says they are. Who may connect at all is up to the socket's
+/// permissions, so address bans don't reach them; nickname bans still do.
+#[cfg(unix)]
+async fn accept_unix(server: UnixListener, listeners: Arc<Listeners>) {
+    loop {
+        let (socket, peer) = match server.accept().await {
+            Ok((socket, _)) => match socket.peer_cred() {
+                Ok(cred) => (
+                    socket,
+                    Peer::Unix {
+                        uid: cred.uid(),
+                        pid: cred.pid(),
+                    },
+                ),
+                Err(err) => {
+                    log!("failed to identify a Unix socket client: {}", err);
+                    continue;
+                }
+            },
+            Err(err) => {
+                log!("failed to accept connection: {}", err);
+                continue;
+            }
+        };
+
+        let slot = match admit(&listeners, peer) {
+            Ok(slot) => slot,
+            Err((code, text)) => {
+                turn_away(socket, None, Transport::Tcp, code, text);
+                continue;
+            }
+        };
+        let id = listeners.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let listeners = listeners.clone();
+
+        tokio::spawn(async move {
+            let events = listeners.events.clone();
+            connection::handle(socket, peer, id, events, &listeners.config).await;
+            drop(slot);
+        });
+    }
+}
+
+/// A slot for a new client, unless it is banned or the server is full.
+fn admit(listeners: &Listeners, peer: Peer) -> Result<OwnedSemaphorePermit, (ErrorCode, String)> {
+    let banned = peer.ip().and_then(|ip| {
+        listeners
+            .bans
+            .lock()
+            .unwrap()
+            .address(ip, unix_now())
+            .map(|ban| ban.message())
+    });
+    if let Some(text) = banned {
+        log!("turning away {}: banned", peer);
+        metrics::add(&METRICS.turned_away_banned, 1);
+        return Err((ErrorCode::Banned, text));
+    }
+
+    let Ok(slot) = listeners.slots.clone().try_acquire_owned() else {
+        log!("turning away {}: server is full", peer);
+        metrics::add(&METRICS.turned_away_full, 1);
+        return Err((ErrorCode::Full, String::from("server is full")));
+    };
+
+    log!("Client {} connected", peer);
+    metrics::add(&METRICS.connections, 1);
+    Ok(slot)
+}
+
+/// Tells a client it won't be served, as far as that is possible before any
+/// handshake, and closes the connection. Over TLS that takes a handshake of
+/// its own, or the client would only see garbage.
+fn turn_away<S>(
+    mut socket: S,
+    acceptor: Option<TlsAcceptor>,
+    transport: Transport,
+    code: ErrorCode,
+    text: String,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // a WebSocket client would need a handshake first, it just gets the
+    // connection closed
+    let buff = match transport {
+        Transport::Tcp => {
+            let msg = encode_message(&ServerMessage::Error { code, text });
+            encode_frame(&msg, DEFAULT_MAX_FRAME_SIZE).unwrap_or_default()
+        }
+        Transport::Irc => format!("ERROR :{}\r\n", text).into_bytes(),
+        Transport::WebSocket | Transport::Link => return,
+    };
+
+    // these hold no slot, so they get a short while rather than the idle
+    // timeout a client that was let in gets
+    tokio::spawn(time::timeout(TURN_AWAY_TIMEOUT, async move {
+        match acceptor {
+            Some(acceptor) => {
+                if let Ok(mut stream) = acceptor.accept(socket).await {
+                    let _ = stream.write_all(&buff).await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+            None => {
+                let _ = socket.write_all(&buff).await;
+            }
+        }
+    }));
+}
+
+async fn serve<S>(
+    transport: Transport,
+    stream: S,
+    peer: Peer,
+    id: ClientId,
+    events: UnboundedSender<Event>,
+    config: &Config,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match transport {
+        Transport::Tcp => connection::handle(stream, peer, id, events, config).await,
+        Transport::WebSocket => websocket::handle(stream, peer, id, events, config).await,
+        Transport::Irc => irc::handle(stream, peer, id, events, config).await,
+        Transport::Link => (),
+    }
+}
@@ -0,0 +1,253 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair, KeyUsagePurpose,
+};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, Error, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::log;
+
+pub const DEV_CA: &str = "dev-ca.pem";
+pub const DEV_CERT: &str = "dev-cert.pem";
+pub const DEV_KEY: &str = "dev-key.pem";
+
+fn invalid(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Builds an acceptor from a PEM certificate chain and a PEM private key.
+pub fn acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_slice_iter(&fs::read(cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_slice(&fs::read(key)?).map_err(invalid)?;
+
+    if let Some(leaf) = certs.first() {
+        log!("TLS certificate fingerprint: {}", fingerprint(leaf));
+    }
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Local development mode: the first run creates a throwaway CA plus a
+/// certificate for localhost signed by it in `dir`, later runs reuse them.
+/// Clients can trust the CA with `--ca` or pin the printed fingerprint.
+pub fn dev_acceptor(dir: &Path) -> io::Result<TlsAcceptor> {
+    let cert = dir.join(DEV_CERT);
+    let key = dir.join(DEV_KEY);
+
+    if !cert.exists() || !key.exists() {
+        generate_dev_cert(dir).map_err(invalid)?;
+        log!(
+            "generated a self-signed development CA at {}",
+            dir.join(DEV_CA).display()
+        );
+    }
+
+    acceptor(&cert, &key)
+}
+
+fn generate_dev_cert(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+
+    let mut ca_params = CertificateParams::new(vec![])?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "chat development CA");
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate()?)?;
+
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![
+        String::from("localhost"),
+        String::from("127.0.0.1"),
+        String::from("::1"),
+    ])?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "chat development server");
+    let cert = params.signed_by(&key, &ca)?;
+
+    fs::write(dir.join(DEV_CA), ca.pem())?;
+    fs::write(dir.join(DEV_CERT), cert.pem())?;
+    write_private(&dir.join(DEV_KEY), key.serialize_pem().as_bytes())?;
+
+    Ok(())
+}
+
+/// Writes a file only its owner can read, from the moment it exists.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(contents)
+}
+
+/// Without a custom CA the server has to present a certificate from one of
+/// the usual public roots. With a pin the server's certificate must also hash
+/// to it; a pin on its own is enough to trust a self-signed certificate.
+pub fn connector(ca: Option<&Path>, pin: Option<&str>) -> io::Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in CertificateDer::pem_slice_iter(&fs::read(ca)?) {
+                roots.add(cert.map_err(invalid)?).map_err(invalid)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let builder = ClientConfig::builder();
+    let config = match pin {
+        Some(pin) => {
+            let chain = match ca {
+                Some(_) => Some(
+                    WebPkiServerVerifier::builder(Arc::new(roots))
+                        .build()
+                        .map_err(invalid)?,
+                ),
+                None => None,
+            };
+            let verifier = PinnedCert {
+                pin: pin.to_lowercase().replace(':', ""),
+                chain,
+                provider: CryptoProvider::get_default()
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(crypto::ring::default_provider())),
+            };
+
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        }
+        None => builder.with_root_certificates(roots).with_no_client_auth(),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Hex SHA-256 of a DER certificate, the value clients pin with `--pin`.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Debug)]
+struct PinnedCert {
+    pin: String,
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if let Some(chain) = &self.chain {
+            chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+
+        if fingerprint(end_entity) != self.pin {
+            return Err(Error::General(String::from(
+                "server certificate does not match the pinned fingerprint",
+            )));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::temp_dir;
+
+    #[test]
+    fn test_dev_cert_is_reused() {
+        let dir = temp_dir("tls");
+
+        dev_acceptor(&dir).unwrap();
+        let first = fs::read(dir.join(DEV_CERT)).unwrap();
+        dev_acceptor(&dir).unwrap();
+        assert_eq!(fs::read(dir.join(DEV_CERT)).unwrap(), first);
+        assert!(dir.join(DEV_CA).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join(DEV_KEY))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use sha2::{Digest, Sha256};
+
+use crate::hub::ClientId;
+
+pub const MAX_NAME_LEN: usize = 255;
+
+/// How many offers a client may have open at once.
+pub const MAX_TRANSFERS: usize = 4;
+
+/// A file on its way from one client to others. The hub only passes chunks
+/// along, but it counts and hashes them as they go so that a sender can't
+/// deliver more than it offered or something other than what it described.
+pub struct Transfer {
+    pub from: ClientId,
+    pub name: String,
+    pub size: u64,
+    sha256: String,
+    /// Offered to but yet to answer.
+    pub pending: BTreeSet<ClientId>,
+    pub accepted: BTreeSet<ClientId>,
+    pub received: u64,
+    hasher: Sha256,
+}
+
+impl Transfer {
+    /// `sha256` must already have been checked with `validate_offer`.
+    pub fn new(
+        from: ClientId,
+        name: String,
+        size: u64,
+        sha256: &str,
+        to: BTreeSet<ClientId>,
+    ) -> Transfer {
+        Transfer {
+            from,
+            name,
+            size,
+            sha256: sha256.to_lowercase(),
+            pending: to,
+            accepted: BTreeSet::new(),
+            received: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Everyone who has yet to answer or is receiving the file.
+    pub fn recipients(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.pending.iter().chain(&self.accepted).copied()
+    }
+
+    pub fn started(&self) -> bool {
+        self.received > 0
+    }
+
+    /// Counts a chunk in. Returns true once the whole file has arrived and
+    /// its checksum matched, or why the transfer has to be abandoned.
+    pub fn receive(&mut self, data: &[u8]) -> Result<bool, String> {
+        if data.is_empty() {
+            return Err(String::from("empty chunk"));
+        }
+
+        let received = self.received + data.len() as u64;
+        if received > self.size {
+            return Err(format!("more than the {} bytes offered", self.size));
+        }
+        self.hasher.update(data);
+        self.received = received;
+
+        if received < self.size {
+            return Ok(false);
+        }
+
+        let digest = self
+            .hasher
+            .finalize_reset()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        match digest == self.sha256 {
+            true => Ok(true),
+            false => Err(String::from("checksum mismatch")),
+        }
+    }
+}
+
+pub fn validate_offer(name: &str, size: u64, sha256: &str, max_size: u64) -> Result<(), String> {
+    // the name is a suggestion for where to save the file, so nothing that
+    // could point anywhere but a plain file in the current directory
+    if name.is_empty()
+        || name.chars().count() > MAX_NAME_LEN
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\'])
+        || name.chars().any(char::is_control)
+    {
+        return Err(format!(
+            "file names must be between 1 and {} characters, without slashes",
+            MAX_NAME_LEN
+        ));
+    }
+
+    if size == 0 {
+        return Err(String::from("empty files can't be sent"));
+    }
+    if size > max_size {
+        return Err(format!("files may be at most {} bytes", max_size));
+    }
+
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(String::from("sha256 must be 64 hex digits"));
+    }
+
+    Ok(())
+}
+
+/// Transfer id -> transfer. Ids are never reused, so a late chunk or answer
+/// can't end up in someone else's transfer.
+#[derive(Default)]
+pub struct Transfers {
+    transfers: BTreeMap<u64, Transfer>,
+    last_id: u64,
+}
+
+impl Transfers {
+    pub fn add(&mut self, transfer: Transfer) -> u64 {
+        self.last_id += 1;
+        self.transfers.insert(self.last_id, transfer);
+        self.last_id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Transfer> {
+        self.transfers.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Transfer> {
+        self.transfers.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Transfer> {
+        self.transfers.remove(&id)
+    }
+
+    pub fn sent_by(&self, client: ClientId) -> Vec<u64> {
+        self.transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.from == client)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    pub fn sent_to(&self, client: ClientId) -> Vec<u64> {
+        self.transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.recipients().any(|id| id == client))
+            .map(|(&id, _)| id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sha256 of "hello world"
+    const HELLO: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    #[test]
+    fn test_receive() {
+        let mut transfer = Transfer::new(1, String::from("hello.txt"), 11, HELLO, BTreeSet::new());
+        assert_eq!(transfer.receive(b"hello "), Ok(false));
+        assert!(transfer.started());
+        assert_eq!(transfer.receive(b"world"), Ok(true));
+
+        let mut transfer = Transfer::new(1, String::from("hello.txt"), 11, HELLO, BTreeSet::new());
+        assert_eq!(
+            transfer.receive(b"hello world!"),
+            Err(String::from("more than the 11 bytes offered"))
+        );
+        assert_eq!(
+            transfer.receive(b"HELLO WORLD"),
+            Err(String::from("checksum mismatch"))
+        );
+    }
+
+    #[test]
+    fn test_validate_offer() {
+        assert!(validate_offer("notes.txt", 11, HELLO, 100).is_ok());
+        assert!(validate_offer("notes.txt", 11, &HELLO.to_uppercase(), 100).is_ok());
+        assert!(validate_offer("../etc/passwd", 11, HELLO, 100).is_err());
+        assert!(validate_offer("..", 11, HELLO, 100).is_err());
+        assert!(validate_offer("notes.txt", 0, HELLO, 100).is_err());
+        assert!(validate_offer("notes.txt", 101, HELLO, 100).is_err());
+        assert!(validate_offer("notes.txt", 11, "abc", 100).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+/**
+ * A Unix socket for clients on the same host, such as bots and local tools,
+ * so they needn't go through a network port. It speaks the same frames as
+ * the TCP port, never with TLS, and who may connect is down to the socket
+ * file's permissions.
+**/
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+use tokio::net::UnixListener;
+
+/// Creates the socket at `path` with permissions `mode`. A socket file left
+/// behind by a server that didn't shut down cleanly is replaced, but not one
+/// something still answers on, nor anything that isn't a socket.
+///
+/// The socket is made in a directory only this user can get into and moved
+/// into place once it has its permissions, so there is never a moment when
+/// the umask lets someone else connect.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a file that isn't a socket is in the way",
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another server is listening on it",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let dir = path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(format!(".chat-{}", std::process::id()));
+    // left over if a server with the same pid died halfway through
+    let _ = fs::remove_dir_all(&dir);
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let staged = dir.join("sock");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&dir);
+
+    listener
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::temp_dir;
+
+    #[tokio::test]
+    async fn test_bind() {
+        let dir = temp_dir("unix");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chat.sock");
+
+        let listener = bind(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // nothing is left of where it was made
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+        assert!(bind(&path, 0o600).is_err());
+
+        // a stale socket from a server that's gone is taken over
+        drop(listener);
+        bind(&path, 0o660).unwrap();
+
+        let file = path.with_file_name("not.sock");
+        fs::write(&file, "").unwrap();
+        assert!(bind(&file, 0o600).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
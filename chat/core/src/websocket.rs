@@ -0,0 +1,108 @@
+use std::io;
+
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use protocol::{is_chunk, FrameError};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::config::Config;
+use crate::connection::{self, Incoming, Outgoing, Peer};
+use crate::hub::{ClientId, Event};
+use crate::log;
+use crate::metrics::{self, METRICS};
+
+/// Each WebSocket message carries exactly what a frame does on a plain
+/// socket, so browsers get the same JSON messages as every other client.
+/// File chunks go out as binary messages, everything else as text.
+struct Messages<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    max: usize,
+}
+
+impl<S> Incoming for Messages<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn next(&mut self) -> io::Result<Option<Result<Vec<u8>, FrameError>>> {
+        loop {
+            let payload = match self.stream.next().await {
+                Some(Ok(Message::Text(text))) => Vec::from(text.as_bytes()),
+                Some(Ok(Message::Binary(data))) => data.to_vec(),
+                // tungstenite answers pings itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => return Ok(None),
+                Some(Err(err)) => return Err(into_io(err)),
+            };
+
+            metrics::add(&METRICS.bytes_in, payload.len());
+            if payload.len() > self.max {
+                let (len, max) = (payload.len(), self.max);
+                return Ok(Some(Err(FrameError::TooLarge { len, max })));
+            }
+
+            return Ok(Some(Ok(payload)));
+        }
+    }
+}
+
+impl<S> Outgoing for SplitSink<WebSocketStream<S>, Message>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        metrics::add(&METRICS.bytes_out, payload.len());
+        let msg = match is_chunk(&payload) {
+            true => Message::binary(payload),
+            false => Message::text(String::from_utf8(payload).map_err(io::Error::other)?),
+        };
+        SinkExt::send(self, msg).await.map_err(into_io)
+    }
+
+    async fn close(&mut self) {
+        let _ = SinkExt::close(self).await;
+    }
+}
+
+fn into_io(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// Serves a client that connected with a WebSocket upgrade request.
+pub async fn handle<S>(
+    socket: S,
+    peer: Peer,
+    id: ClientId,
+    events: UnboundedSender<Event>,
+    config: &Config,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // messages somewhat over the limit are still read in, so that they are
+    // answered the same way as on a plain socket, while ones far over it
+    // close the connection before they take up much memory
+    let max = connection::max_payload(config);
+    let ws_config = WebSocketConfig::default()
+        .max_message_size(Some(max * 2))
+        .max_frame_size(Some(max * 2));
+
+    // a client that never finishes the upgrade would keep its slot for good
+    let upgrade = tokio_tungstenite::accept_async_with_config(socket, Some(ws_config));
+    let stream = match time::timeout(config.idle_timeout, upgrade).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => return log!("WebSocket handshake with {} failed: {}", peer, err),
+        Err(_) => return log!("WebSocket handshake with {} timed out", peer),
+    };
+
+    let (sink, stream) = stream.split();
+    let messages = Messages { stream, max };
+
+    connection::serve(messages, sink, peer, id, events, config).await
+}
@@ -0,0 +1,520 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+
+use chat_core::config::Tls;
+use chat_core::protocol::{
+    decode_message, encode_message, ClientMessage, ErrorCode, ServerMessage,
+};
+use chat_core::tls::{self, DEV_CA};
+use chat_core::{Client, Config, Server, Transport};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines,
+};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::time;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_tungstenite::tungstenite::Message;
+
+/// How long a test waits for any one message before failing.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct TestServer {
+    addr: SocketAddr,
+    ws_addr: Option<SocketAddr>,
+    irc_addr: Option<SocketAddr>,
+    link_addr: Option<SocketAddr>,
+    dir: PathBuf,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Starts a server on a free port, with its files in a fresh directory.
+    fn start(config: Config) -> TestServer {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "chat-it-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let config = Config {
+            bind: vec!["127.0.0.1:0".parse().unwrap()],
+            history_dir: dir.join("history"),
+            accounts: dir.join("accounts"),
+            bans: dir.join("bans"),
+            shutdown_timeout: Duration::from_secs(1),
+            ..config
+        };
+        let server = Server::bind(config).unwrap();
+        let addrs = server.local_addrs();
+        let addr = find(&addrs, Transport::Tcp).unwrap();
+        let ws_addr = find(&addrs, Transport::WebSocket);
+        let irc_addr = find(&addrs, Transport::Irc);
+        let link_addr = find(&addrs, Transport::Link);
+
+        let (shutdown, rx) = oneshot::channel::<()>();
+        let task = tokio::spawn(server.run(async {
+            let _ = rx.await;
+        }));
+
+        TestServer {
+            addr,
+            ws_addr,
+            irc_addr,
+            link_addr,
+            dir,
+            shutdown: Some(shutdown),
+            task,
+        }
+    }
+
+    async fn connect(&self, nick: &str) -> Client<TcpStream> {
+        let mut client = Client::connect(self.addr).await.unwrap();
+        client
+            .send(&ClientMessage::from_line(&format!("/nick {}", nick)))
+            .await
+            .unwrap();
+        expect(&mut client, &format!("* you are now known as {}", nick)).await;
+        client
+    }
+
+    async fn stop(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        time::timeout(TIMEOUT, &mut self.task)
+            .await
+            .unwrap()
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn find(addrs: &[(Transport, SocketAddr)], transport: Transport) -> Option<SocketAddr> {
+    addrs
+        .iter()
+        .find(|(other, _)| *other == transport)
+        .map(|(_, addr)| *addr)
+}
+
+/// A server called `name` that links to `links`, and takes links on
+/// `listen` if given, port 0 for any.
+fn linked(name: &str, listen: Option<u16>, links: &[&TestServer]) -> Config {
+    Config {
+        link_bind: listen
+            .map(|port| vec![SocketAddr::from(([127, 0, 0, 1], port))])
+            .unwrap_or_default(),
+        links: links
+            .iter()
+            .map(|server| server.link_addr.unwrap().to_string())
+            .collect(),
+        server_name: Some(name.to_string()),
+        link_password: Some(String::from("secret")),
+        ..Config::default()
+    }
+}
+
+async fn say<S: AsyncRead + AsyncWrite>(client: &mut Client<S>, line: &str) {
+    client.send(&ClientMessage::from_line(line)).await.unwrap();
+}
+
+/// The next message, failing the test if none comes.
+async fn next<S: AsyncRead + AsyncWrite>(client: &mut Client<S>) -> Option<ServerMessage> {
+    time::timeout(TIMEOUT, client.recv())
+        .await
+        .expect("timed out waiting for the server")
+        .unwrap()
+        .map(|msg| msg.unwrap())
+}
+
+/// Skips ahead to the message that renders as `text`.
+async fn expect<S: AsyncRead + AsyncWrite>(client: &mut Client<S>, text: &str) -> ServerMessage {
+    loop {
+        match next(client).await {
+            Some(msg) if msg.to_string() == text => return msg,
+            Some(_) => continue,
+            None => panic!("connection closed waiting for {:?}", text),
+        }
+    }
+}
+
+/// Skips ahead to the IRC line that starts with `text`.
+async fn expect_line<R: AsyncBufRead + Unpin>(lines: &mut Lines<R>, text: &str) {
+    let found = time::timeout(TIMEOUT, async {
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if line.starts_with(text) {
+                return;
+            }
+        }
+        panic!("connection closed waiting for {:?}", text);
+    })
+    .await;
+
+    if found.is_err() {
+        panic!("timed out waiting for {:?}", text);
+    }
+}
+
+/// Asks for the names in #general until they are `names`, as links come up
+/// in their own time.
+async fn wait_for_names(client: &mut Client<TcpStream>, names: &str) {
+    let expected = format!("* in #general: {}", names);
+    let waited = time::timeout(TIMEOUT, async {
+        loop {
+            say(client, "/names").await;
+            loop {
+                match next(client).await {
+                    Some(msg @ ServerMessage::Names { .. }) if msg.to_string() == expected => {
+                        return;
+                    }
+                    Some(ServerMessage::Names { .. }) => break,
+                    Some(_) => continue,
+                    None => panic!("connection closed waiting for {:?}", expected),
+                }
+            }
+            // slow enough to stay clear of the flood limits
+            time::sleep(Duration::from_millis(500)).await;
+        }
+    })
+    .await;
+
+    if waited.is_err() {
+        panic!("timed out waiting for {:?}", expected);
+    }
+}
+
+/// What arrives within a short while, for checking that nothing else does.
+async fn quiet(client: &mut Client<TcpStream>) -> Vec<String> {
+    let mut msgs = vec![];
+    while let Ok(msg) = time::timeout(Duration::from_millis(200), client.recv()).await {
+        match msg.unwrap() {
+            Some(msg) => msgs.push(msg.unwrap().to_string()),
+            None => break,
+        }
+    }
+    msgs
+}
+
+#[tokio::test]
+async fn test_rooms_and_private_messages() {
+    let server = TestServer::start(Config::default());
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    let mut carol = server.connect("carol").await;
+
+    say(&mut alice, "/join #rust").await;
+    expect(&mut alice, "* joined #rust").await;
+    say(&mut bob, "/join #rust").await;
+    expect(&mut alice, "* bob joined #rust").await;
+    expect(&mut bob, "* joined #rust").await;
+    quiet(&mut carol).await;
+
+    say(&mut alice, "borrowck says no").await;
+    expect(&mut alice, "[#rust] alice: borrowck says no").await;
+    expect(&mut bob, "[#rust] alice: borrowck says no").await;
+    assert!(quiet(&mut carol).await.is_empty());
+
+    say(&mut carol, "/msg bob psst").await;
+    expect(&mut bob, "[pm] carol -> bob: psst").await;
+    assert!(quiet(&mut alice).await.is_empty());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_file_transfer() {
+    let server = TestServer::start(Config::default());
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    alice
+        .send(&ClientMessage::Offer {
+            to: String::from("bob"),
+            name: String::from("hello.txt"),
+            size: 11,
+            sha256: String::from(
+                "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+            ),
+        })
+        .await
+        .unwrap();
+    expect(
+        &mut bob,
+        "* alice offers hello.txt (11 bytes) to bob, /accept 1 or /decline 1",
+    )
+    .await;
+    bob.send(&ClientMessage::Accept { id: 1 }).await.unwrap();
+    expect(&mut alice, "* bob accepted transfer 1").await;
+
+    for data in [&b"hello "[..], &b"world"[..]] {
+        let chunk = ClientMessage::Chunk {
+            id: 1,
+            data: data.to_vec(),
+        };
+        alice.send(&chunk).await.unwrap();
+    }
+
+    let mut received = vec![];
+    loop {
+        match next(&mut bob).await {
+            Some(ServerMessage::Chunk { id: 1, data }) => received.extend(data),
+            Some(ServerMessage::Done { id: 1 }) => break,
+            Some(_) => continue,
+            None => panic!("connection closed mid transfer"),
+        }
+    }
+    assert_eq!(received, b"hello world");
+    expect(&mut alice, "* transfer 1 is complete").await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_oversized_message() {
+    let config = Config {
+        max_message_size: 64,
+        ..Config::default()
+    };
+    let server = TestServer::start(config);
+    let mut alice = server.connect("alice").await;
+
+    say(&mut alice, &"a".repeat(100)).await;
+    match next(&mut alice).await {
+        Some(ServerMessage::Error { code, .. }) => assert_eq!(code, ErrorCode::TooLarge),
+        msg => panic!("expected an error, got {:?}", msg),
+    }
+
+    // and the connection is still good for what fits
+    say(&mut alice, "short").await;
+    expect(&mut alice, "[#general] alice: short").await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    let config = Config {
+        shutdown_reason: Some(String::from("back soon")),
+        ..Config::default()
+    };
+    let server = TestServer::start(config);
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    server.stop().await;
+    for client in [&mut alice, &mut bob] {
+        expect(client, "* server is shutting down: back soon").await;
+        assert!(next(client).await.is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_linked_servers() {
+    // a triangle, so everything has two ways round
+    let a = TestServer::start(linked("a", Some(0), &[]));
+    let b = TestServer::start(linked("b", Some(0), &[&a]));
+    let c = TestServer::start(linked("c", None, &[&a, &b]));
+    let mut alice = a.connect("alice").await;
+    let mut bob = b.connect("bob").await;
+    let mut carol = c.connect("carol").await;
+
+    wait_for_names(&mut alice, "alice, bob@b, carol@c").await;
+    wait_for_names(&mut carol, "alice@a, bob@b, carol").await;
+
+    say(&mut alice, "hello from a").await;
+    expect(&mut alice, "[#general] alice: hello from a").await;
+    expect(&mut bob, "[#general] alice@a: hello from a").await;
+    expect(&mut carol, "[#general] alice@a: hello from a").await;
+
+    // only once, however many ways it came
+    assert!(quiet(&mut bob).await.is_empty());
+    assert!(quiet(&mut carol).await.is_empty());
+
+    // edits and deletions follow the message round, once each
+    say(&mut alice, "/edit last hello from server a").await;
+    expect(
+        &mut bob,
+        "* alice@a edited a message in #general: hello from server a",
+    )
+    .await;
+    expect(
+        &mut carol,
+        "* alice@a edited a message in #general: hello from server a",
+    )
+    .await;
+    say(&mut alice, "/delete last").await;
+    expect(&mut bob, "* alice@a deleted a message in #general").await;
+    expect(&mut carol, "* alice@a deleted a message in #general").await;
+    assert!(quiet(&mut carol).await.is_empty());
+
+    say(&mut carol, "/join #rust").await;
+    say(&mut bob, "/join #rust").await;
+    expect(&mut carol, "* bob@b joined #rust").await;
+
+    for server in [a, b, c] {
+        server.stop().await;
+    }
+}
+
+#[tokio::test]
+async fn test_link_recovery() {
+    let a = TestServer::start(linked("a", Some(0), &[]));
+    let port = a.link_addr.unwrap().port();
+    let b = TestServer::start(linked("b", None, &[&a]));
+    let alice = a.connect("alice").await;
+    let mut bob = b.connect("bob").await;
+    wait_for_names(&mut bob, "alice@a, bob").await;
+
+    drop(alice);
+    a.stop().await;
+    expect(&mut bob, "* alice@a left #general (link lost)").await;
+
+    // the same server back on the same port
+    let a = TestServer::start(linked("a", Some(port), &[]));
+    let mut alice = a.connect("alice").await;
+    wait_for_names(&mut bob, "alice@a, bob").await;
+
+    say(&mut alice, "back again").await;
+    expect(&mut bob, "[#general] alice@a: back again").await;
+
+    a.stop().await;
+    b.stop().await;
+}
+
+#[tokio::test]
+async fn test_websocket() {
+    let config = Config {
+        ws_bind: vec!["127.0.0.1:0".parse().unwrap()],
+        ..Config::default()
+    };
+    let server = TestServer::start(config);
+    let mut alice = server.connect("alice").await;
+
+    let addr = server.ws_addr.unwrap();
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), socket)
+        .await
+        .unwrap();
+    for line in ["/nick wendy", "hello from the web"] {
+        let msg = encode_message(&ClientMessage::from_line(line));
+        let msg = Message::text(String::from_utf8(msg).unwrap());
+        ws.send(msg).await.unwrap();
+    }
+    expect(&mut alice, "[#general] wendy: hello from the web").await;
+
+    say(&mut alice, "hello from here").await;
+    let found = time::timeout(TIMEOUT, async {
+        while let Some(msg) = ws.next().await {
+            if let Message::Text(text) = msg.unwrap() {
+                let msg = decode_message::<ServerMessage>(text.as_bytes()).unwrap();
+                if msg.to_string() == "[#general] alice: hello from here" {
+                    return;
+                }
+            }
+        }
+        panic!("connection closed waiting for alice");
+    })
+    .await;
+    if found.is_err() {
+        panic!("timed out waiting for alice");
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_unix_socket() {
+    let path = std::env::temp_dir().join(format!("chat-it-{}.sock", std::process::id()));
+    let config = Config {
+        unix_socket: Some(path.clone()),
+        ..Config::default()
+    };
+    let server = TestServer::start(config);
+    let mut alice = server.connect("alice").await;
+
+    let mut bot = Client::connect_unix(&path).await.unwrap();
+    say(&mut bot, "/nick bot").await;
+    say(&mut bot, "beep").await;
+    expect(&mut alice, "[#general] bot: beep").await;
+
+    server.stop().await;
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_tls_turn_away() {
+    let dir = std::env::temp_dir().join(format!("chat-it-{}-tls", std::process::id()));
+    let config = Config {
+        tls: Tls::Dev { dir: dir.clone() },
+        max_clients: 1,
+        ..Config::default()
+    };
+    let server = TestServer::start(config);
+
+    let connect = || async {
+        let connector = tls::connector(Some(&dir.join(DEV_CA)), None).unwrap();
+        let socket = TcpStream::connect(server.addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        Client::<TlsStream<TcpStream>>::new(connector.connect(name, socket).await.unwrap())
+    };
+    let mut alice = connect().await;
+    say(&mut alice, "/nick alice").await;
+    expect(&mut alice, "* you are now known as alice").await;
+
+    // told why over TLS, rather than in plaintext the client can't read
+    let mut bob = connect().await;
+    assert!(matches!(
+        next(&mut bob).await,
+        Some(ServerMessage::Error {
+            code: ErrorCode::Full,
+            ..
+        })
+    ));
+
+    server.stop().await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_irc_gateway() {
+    let config = Config {
+        irc_bind: vec!["127.0.0.1:0".parse().unwrap()],
+        ..Config::default()
+    };
+    let server = TestServer::start(config);
+    let mut alice = server.connect("alice").await;
+
+    let socket = TcpStream::connect(server.irc_addr.unwrap()).await.unwrap();
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    writer
+        .write_all(b"NICK bob\r\nUSER bob 0 * :Bob\r\nJOIN #general\r\n")
+        .await
+        .unwrap();
+    expect_line(&mut lines, ":chat 001 bob ").await;
+    expect_line(&mut lines, ":bob!bob@chat JOIN #general").await;
+
+    writer
+        .write_all(b"PRIVMSG #general :hello from irc\r\n")
+        .await
+        .unwrap();
+    expect(&mut alice, "[#general] bob: hello from irc").await;
+
+    say(&mut alice, "hello from here").await;
+    expect_line(
+        &mut lines,
+        ":alice!alice@chat PRIVMSG #general :hello from here",
+    )
+    .await;
+
+    server.stop().await;
+}
@@ -0,0 +1,62 @@
+/**
+ * File data travels in binary frames of its own rather than inside JSON, so
+ * it costs no more than its size. A chunk frame's payload is a zero byte,
+ * which no JSON message starts with, the transfer id and the data.
+ *
+ *   +----------+----------------+---------------------------+
+ *   | tag: 0u8 | id: u64 (BE)   | data: up to 16 KiB        |
+ *   +----------+----------------+---------------------------+
+ *
+ * The transfer itself is negotiated with `offer`, `accept` and friends in
+ * ordinary messages; chunk frames only carry the bytes.
+**/
+pub const CHUNK_TAG: u8 = 0;
+pub const CHUNK_HEADER_SIZE: usize = 9;
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+pub fn is_chunk(payload: &[u8]) -> bool {
+    payload.first() == Some(&CHUNK_TAG)
+}
+
+pub fn encode_chunk(id: u64, data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(CHUNK_HEADER_SIZE + data.len());
+    payload.push(CHUNK_TAG);
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(data);
+
+    payload
+}
+
+/// The transfer id and data of a chunk frame, or `None` if the payload isn't
+/// one.
+pub fn decode_chunk(payload: &[u8]) -> Option<(u64, &[u8])> {
+    if !is_chunk(payload) || payload.len() < CHUNK_HEADER_SIZE {
+        return None;
+    }
+
+    let id = u64::from_be_bytes(payload[1..CHUNK_HEADER_SIZE].try_into().ok()?);
+    Some((id, &payload[CHUNK_HEADER_SIZE..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_message;
+    use crate::message::ClientMessage;
+
+    #[test]
+    fn test_round_trip() {
+        let payload = encode_chunk(7, b"\x00\xffdata");
+        assert_eq!(payload.len(), CHUNK_HEADER_SIZE + 6);
+        assert_eq!(decode_chunk(&payload), Some((7, &b"\x00\xffdata"[..])));
+
+        assert_eq!(decode_chunk(&encode_chunk(1, b"")), Some((1, &b""[..])));
+        assert_eq!(decode_chunk(&[CHUNK_TAG, 1, 2]), None);
+    }
+
+    #[test]
+    fn test_never_json() {
+        assert!(!is_chunk(&encode_message(&ClientMessage::Ping)));
+        assert!(decode_chunk(br#"{"v":1,"kind":"ping"}"#).is_none());
+    }
+}
@@ -0,0 +1,167 @@
+/**
+ * Every message on the wire is a frame: a 4 byte big-endian length header
+ * followed by exactly that many payload bytes.
+ *
+ *   +----------------+---------------------------+
+ *   | len: u32 (BE)  | payload: [u8; len]        |
+ *   +----------------+---------------------------+
+ *
+ * Frames whose header announces more than the configured maximum are
+ * rejected. The decoder still consumes (and throws away) their payload so the
+ * next header is read from the right place.
+**/
+use std::fmt;
+
+pub const HEADER_SIZE: usize = 4;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum FrameError {
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds maximum of {} bytes", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+pub fn encode_frame(payload: &[u8], max: usize) -> Result<Vec<u8>, FrameError> {
+    if payload.len() > max || payload.len() > u32::MAX as usize {
+        return Err(FrameError::TooLarge {
+            len: payload.len(),
+            max,
+        });
+    }
+
+    let mut buff = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buff.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buff.extend_from_slice(payload);
+
+    Ok(buff)
+}
+
+/// Incremental decoder: feed it whatever the socket returned and pull
+/// complete frames back out.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buff: Vec<u8>,
+    max: usize,
+    skip: usize,
+}
+
+impl FrameDecoder {
+    pub fn new(max: usize) -> FrameDecoder {
+        FrameDecoder {
+            buff: vec![],
+            max,
+            skip: 0,
+        }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buff.extend_from_slice(data);
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.skip > 0 {
+            let n = self.skip.min(self.buff.len());
+            self.buff.drain(..n);
+            self.skip -= n;
+
+            if self.skip > 0 {
+                return Ok(None);
+            }
+        }
+
+        if self.buff.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&self.buff[..HEADER_SIZE]);
+        let len = u32::from_be_bytes(header) as usize;
+
+        if len > self.max {
+            self.buff.drain(..HEADER_SIZE);
+            self.skip = len;
+            return Err(FrameError::TooLarge { len, max: self.max });
+        }
+
+        if self.buff.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+
+        let frame = self.buff[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+        self.buff.drain(..HEADER_SIZE + len);
+
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE);
+        decoder.extend(&encode_frame(b"hello", DEFAULT_MAX_FRAME_SIZE).unwrap());
+        decoder.extend(&encode_frame(b"a\0b", DEFAULT_MAX_FRAME_SIZE).unwrap());
+        decoder.extend(&encode_frame(b"", DEFAULT_MAX_FRAME_SIZE).unwrap());
+
+        assert_eq!(decoder.next_frame(), Ok(Some(b"hello".to_vec())));
+        assert_eq!(decoder.next_frame(), Ok(Some(b"a\0b".to_vec())));
+        assert_eq!(decoder.next_frame(), Ok(Some(vec![])));
+        assert_eq!(decoder.next_frame(), Ok(None));
+    }
+
+    #[test]
+    fn test_partial_frame() {
+        let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE);
+        let frame = encode_frame(b"a longer message than 32 bytes, for sure", 1024).unwrap();
+
+        for byte in &frame[..frame.len() - 1] {
+            decoder.extend(&[*byte]);
+            assert_eq!(decoder.next_frame(), Ok(None));
+        }
+
+        decoder.extend(&frame[frame.len() - 1..]);
+        assert_eq!(
+            decoder.next_frame(),
+            Ok(Some(b"a longer message than 32 bytes, for sure".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_encode_too_large() {
+        assert_eq!(
+            encode_frame(b"12345", 4),
+            Err(FrameError::TooLarge { len: 5, max: 4 })
+        );
+    }
+
+    #[test]
+    fn test_oversized_frame_is_skipped() {
+        let mut decoder = FrameDecoder::new(4);
+        let big = encode_frame(b"way too big", 1024).unwrap();
+        let (first, rest) = big.split_at(6);
+
+        decoder.extend(first);
+        assert_eq!(
+            decoder.next_frame(),
+            Err(FrameError::TooLarge { len: 11, max: 4 })
+        );
+        assert_eq!(decoder.next_frame(), Ok(None));
+
+        decoder.extend(rest);
+        decoder.extend(&encode_frame(b"ok", 4).unwrap());
+        assert_eq!(decoder.next_frame(), Ok(Some(b"ok".to_vec())));
+    }
+}
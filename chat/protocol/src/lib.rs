@@ -0,0 +1,12 @@
+pub mod chunk;
+pub mod frame;
+pub mod link;
+pub mod message;
+
+pub use chunk::{decode_chunk, encode_chunk, is_chunk, MAX_CHUNK_SIZE};
+pub use frame::{encode_frame, FrameDecoder, FrameError, DEFAULT_MAX_FRAME_SIZE};
+pub use link::LinkMessage;
+pub use message::{
+    clock, decode_message, encode_message, ClientMessage, DecodeError, Envelope, ErrorCode,
+    ServerMessage, PROTOCOL_VERSION,
+};
@@ -0,0 +1,98 @@
+/**
+ * What linked servers say to each other. Links use the same frames and
+ * envelope as clients, with a `kind` of their own:
+ *
+ *   {"v":1,"kind":"members","server":"berlin","seq":7,"room":"#general","nicks":["alice"]}
+ *
+ * Everything but `hello`, `sync` and `ping` is passed on to the other links
+ * of a server, so it reaches servers that aren't linked to its origin.
+**/
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LinkMessage {
+    /// The first thing sent either way. The server that accepted the
+    /// connection only answers once the password checks out.
+    Hello { name: String, password: String },
+    /// Who from `server` is in `room`. `seq` only ever grows on each server,
+    /// and a list replaces any older one for the same server and room.
+    Members {
+        server: String,
+        seq: u64,
+        room: String,
+        nicks: Vec<String>,
+    },
+    /// Chat text from `from` on `server`. `id` is unique across all servers.
+    Chat {
+        id: String,
+        server: String,
+        room: String,
+        from: String,
+        text: String,
+        time: u64,
+    },
+    /// New text for the chat message relayed as `message`, from its author.
+    /// `id` is the edit's own, like a chat message's.
+    Edit {
+        id: String,
+        server: String,
+        room: String,
+        message: String,
+        text: String,
+    },
+    /// The chat message relayed as `message` was deleted by its author.
+    Delete {
+        id: String,
+        server: String,
+        room: String,
+        message: String,
+    },
+    /// `server` can no longer be reached through the sender.
+    Lost { server: String },
+    /// Asks for every member list the peer knows, its own and relayed ones.
+    Sync,
+    /// Keeps a quiet link from timing out.
+    Ping,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_message, encode_message};
+
+    #[test]
+    fn test_round_trip() {
+        let msgs = [
+            LinkMessage::Hello {
+                name: String::from("berlin"),
+                password: String::from("secret"),
+            },
+            LinkMessage::Members {
+                server: String::from("berlin"),
+                seq: 7,
+                room: String::from("#general"),
+                nicks: vec![String::from("alice")],
+            },
+            LinkMessage::Edit {
+                id: String::from("berlin:9"),
+                server: String::from("berlin"),
+                room: String::from("#general"),
+                message: String::from("berlin:8"),
+                text: String::from("hello"),
+            },
+            LinkMessage::Lost {
+                server: String::from("paris"),
+            },
+            LinkMessage::Sync,
+        ];
+
+        for msg in msgs {
+            let decoded: LinkMessage = decode_message(&encode_message(&msg)).unwrap();
+            assert_eq!(decoded, msg);
+        }
+
+        let json = String::from_utf8(encode_message(&LinkMessage::Sync)).unwrap();
+        assert_eq!(json, r#"{"v":1,"kind":"sync"}"#);
+    }
+}
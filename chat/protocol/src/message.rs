@@ -0,0 +1,577 @@
+/**
+ * Every frame carries one JSON object: the protocol version `v`, the message
+ * `kind` and the fields belonging to that kind.
+ *
+ *   {"v":1,"kind":"chat","room":"#general","text":"hi"}
+ *
+ * Clients send `ClientMessage`s and the server answers with `ServerMessage`s.
+ * A peer speaking another version is told so up front instead of having its
+ * messages half understood.
+**/
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Envelope<T> {
+    pub v: u32,
+    #[serde(flatten)]
+    pub msg: T,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Chat text for `room`, or for the sender's active room if there is none.
+    Chat {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        text: String,
+    },
+    /// `name` is the command without its slash, `args` everything after it.
+    Command {
+        name: String,
+        #[serde(default)]
+        args: String,
+    },
+    /// Offers a file to a user or, with `to` a `#room`, to everyone in it.
+    /// `sha256` is the hex digest of the whole file.
+    Offer {
+        to: String,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    Accept {
+        id: u64,
+    },
+    /// Turns an offer down, or drops out of a transfer under way.
+    Decline {
+        id: u64,
+    },
+    /// Calls off a transfer the client offered.
+    Cancel {
+        id: u64,
+    },
+    /// Replaces the text of one of the sender's own messages, in `room` or
+    /// the active room.
+    Edit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        id: u64,
+        text: String,
+    },
+    /// Takes back one of the sender's own messages.
+    Delete {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        id: u64,
+    },
+    /// A piece of a file, only ever sent as a chunk frame.
+    #[serde(skip)]
+    Chunk {
+        id: u64,
+        data: Vec<u8>,
+    },
+    Ping,
+    Pong,
+}
+
+impl ClientMessage {
+    /// A line as typed by a user: `/name args` is a command, anything else is
+    /// chat text for the active room.
+    pub fn from_line(line: &str) -> ClientMessage {
+        match line.strip_prefix('/') {
+            Some(cmd) => {
+                let (name, args) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+                ClientMessage::Command {
+                    name: name.to_string(),
+                    args: args.trim().to_string(),
+                }
+            }
+            None => ClientMessage::Chat {
+                room: None,
+                text: line.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Said in a room. `id` is unique on the server and larger for every
+    /// later message, and `time` is in seconds since the Unix epoch.
+    /// `replayed` marks messages sent from history, and `edited` those whose
+    /// text has been changed since.
+    Chat {
+        id: u64,
+        room: String,
+        from: String,
+        text: String,
+        time: u64,
+        #[serde(default, skip_serializing_if = "is_false")]
+        replayed: bool,
+        #[serde(default, skip_serializing_if = "is_false")]
+        edited: bool,
+    },
+    /// The new text of message `id`, from its author.
+    Edited {
+        room: String,
+        id: u64,
+        from: String,
+        text: String,
+    },
+    /// Message `id` was deleted by its author.
+    Deleted {
+        room: String,
+        id: u64,
+        from: String,
+    },
+    /// Sent to the recipient, with a copy back to the sender.
+    Private {
+        from: String,
+        to: String,
+        text: String,
+    },
+    /// News from the server that isn't the answer to a command.
+    Notice {
+        text: String,
+    },
+    /// The outcome of a command that went through, with the room it was
+    /// about if there was one.
+    Reply {
+        command: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        text: String,
+    },
+    Error {
+        #[serde(default)]
+        code: ErrorCode,
+        text: String,
+    },
+    Join {
+        room: String,
+        nick: String,
+    },
+    Leave {
+        room: String,
+        nick: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A change of nickname. Only the client picking its first nickname is
+    /// sent one without `old`.
+    Nick {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        old: Option<String>,
+        new: String,
+    },
+    /// `nick` was made to leave `room` by operator `by`.
+    Kick {
+        room: String,
+        nick: String,
+        by: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Who is in a room, in answer to `names`.
+    Names {
+        room: String,
+        nicks: Vec<String>,
+    },
+    /// A room's topic, set by `nick` or, without one, in answer to `topic`.
+    Topic {
+        room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nick: Option<String>,
+    },
+    /// A file on offer from `from`, to be accepted or declined by `id`.
+    Offer {
+        id: u64,
+        from: String,
+        to: String,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// Tells the sender of an offer the id to send its chunks under.
+    Offered {
+        id: u64,
+        to: String,
+        name: String,
+        size: u64,
+    },
+    Accepted {
+        id: u64,
+        nick: String,
+    },
+    Declined {
+        id: u64,
+        nick: String,
+    },
+    /// Sent to the sender as each chunk is passed on, and its cue to send
+    /// another.
+    Progress {
+        id: u64,
+        received: u64,
+        size: u64,
+    },
+    /// Every byte arrived and matched the checksum.
+    Done {
+        id: u64,
+    },
+    Failed {
+        id: u64,
+        reason: String,
+    },
+    /// A piece of a file, only ever sent as a chunk frame.
+    #[serde(skip)]
+    Chunk {
+        id: u64,
+        data: Vec<u8>,
+    },
+    Ping,
+    Pong,
+}
+
+/// What an `Error` is about, for clients that want to do more than show it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The server wouldn't do what was asked, the text says why.
+    #[default]
+    Refused,
+    /// A frame that didn't hold a valid message.
+    Malformed,
+    /// A message in a protocol version the server doesn't speak.
+    Version,
+    /// A frame larger than the server accepts.
+    TooLarge,
+    Flooding,
+    /// The server has no room for another client.
+    Full,
+    /// The client, its nickname or its address is banned.
+    Banned,
+    /// The nickname asked for is someone else's, or a registered account's.
+    NickInUse,
+    /// The nickname asked for isn't a valid one, or is banned.
+    BadNick,
+}
+
+/// The plain text rendering used by the terminal client.
+impl fmt::Display for ServerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerMessage::Chat {
+                room,
+                from,
+                text,
+                time,
+                replayed,
+                edited,
+                ..
+            } => {
+                write!(f, "[{}] ", room)?;
+                if *replayed {
+                    write!(f, "{} ", clock(*time))?;
+                }
+                write!(f, "{}: {}", from, text)?;
+                if *edited {
+                    write!(f, " (edited)")?;
+                }
+                Ok(())
+            }
+            ServerMessage::Edited {
+                room, from, text, ..
+            } => {
+                write!(f, "* {} edited a message in {}: {}", from, room, text)
+            }
+            ServerMessage::Deleted { room, from, .. } => {
+                write!(f, "* {} deleted a message in {}", from, room)
+            }
+            ServerMessage::Private { from, to, text } => {
+                write!(f, "[pm] {} -> {}: {}", from, to, text)
+            }
+            ServerMessage::Notice { text } | ServerMessage::Reply { text, .. } => {
+                write!(f, "* {}", text)
+            }
+            ServerMessage::Error { text, .. } => write!(f, "! {}", text),
+            ServerMessage::Join { room, nick } => write!(f, "* {} joined {}", nick, room),
+            ServerMessage::Leave {
+                room,
+                nick,
+                reason: Some(reason),
+            } => write!(f, "* {} left {} ({})", nick, room, reason),
+            ServerMessage::Leave { room, nick, .. } => write!(f, "* {} left {}", nick, room),
+            ServerMessage::Nick { old: None, new } => write!(f, "* you are now known as {}", new),
+            ServerMessage::Nick {
+                old: Some(old),
+                new,
+            } => write!(f, "* {} is now known as {}", old, new),
+            ServerMessage::Kick {
+                room,
+                nick,
+                by,
+                reason: Some(reason),
+            } => write!(
+                f,
+                "* {} was kicked from {} by {} ({})",
+                nick, room, by, reason
+            ),
+            ServerMessage::Kick { room, nick, by, .. } => {
+                write!(f, "* {} was kicked from {} by {}", nick, room, by)
+            }
+            ServerMessage::Names { room, nicks } => {
+                write!(f, "* in {}: {}", room, nicks.join(", "))
+            }
+            ServerMessage::Topic {
+                room,
+                topic,
+                nick: Some(nick),
+            } => match topic {
+                Some(topic) => write!(f, "* {} set the topic of {} to: {}", nick, room, topic),
+                None => write!(f, "* {} cleared the topic of {}", nick, room),
+            },
+            ServerMessage::Topic { room, topic, .. } => match topic {
+                Some(topic) => write!(f, "* topic of {}: {}", room, topic),
+                None => write!(f, "* no topic is set for {}", room),
+            },
+            ServerMessage::Offer {
+                id,
+                from,
+                to,
+                name,
+                size,
+                ..
+            } => write!(
+                f,
+                "* {} offers {} ({} bytes) to {}, /accept {} or /decline {}",
+                from, name, size, to, id, id
+            ),
+            ServerMessage::Offered { id, to, name, size } => write!(
+                f,
+                "* offering {} ({} bytes) to {} as transfer {}",
+                name, size, to, id
+            ),
+            ServerMessage::Accepted { id, nick } => {
+                write!(f, "* {} accepted transfer {}", nick, id)
+            }
+            ServerMessage::Declined { id, nick } => {
+                write!(f, "* {} declined transfer {}", nick, id)
+            }
+            ServerMessage::Progress { id, received, size } => {
+                write!(f, "* transfer {}: {} of {} bytes", id, received, size)
+            }
+            ServerMessage::Done { id } => write!(f, "* transfer {} is complete", id),
+            ServerMessage::Failed { id, reason } => {
+                write!(f, "! transfer {} failed: {}", id, reason)
+            }
+            ServerMessage::Chunk { id, data } => {
+                write!(f, "* {} bytes of transfer {}", data.len(), id)
+            }
+            ServerMessage::Ping => write!(f, "* ping"),
+            ServerMessage::Pong => write!(f, "* pong"),
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+/// `HH:MM` in UTC, which is all a chat window needs.
+pub fn clock(secs: u64) -> String {
+    let minutes = secs / 60;
+    format!("{:02}:{:02}", (minutes / 60) % 24, minutes % 60)
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Version { got: u32 },
+    Invalid(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Version { got } => write!(
+                f,
+                "protocol version {} is not supported, expected {}",
+                got, PROTOCOL_VERSION
+            ),
+            DecodeError::Invalid(err) => write!(f, "malformed message: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl DecodeError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            DecodeError::Version { .. } => ErrorCode::Version,
+            DecodeError::Invalid(_) => ErrorCode::Malformed,
+        }
+    }
+}
+
+pub fn encode_message<T: Serialize>(msg: &T) -> Vec<u8> {
+    let envelope = Envelope {
+        v: PROTOCOL_VERSION,
+        msg,
+    };
+    serde_json::to_vec(&envelope).expect("messages always serialize")
+}
+
+pub fn decode_message<T: DeserializeOwned>(payload: &[u8]) -> Result<T, DecodeError> {
+    #[derive(Deserialize)]
+    struct Version {
+        v: u32,
+    }
+
+    // checked first so a newer peer gets a clear answer rather than a
+    // complaint about some field it moved
+    let invalid = |err: serde_json::Error| DecodeError::Invalid(err.to_string());
+    let Version { v } = serde_json::from_slice(payload).map_err(invalid)?;
+    if v != PROTOCOL_VERSION {
+        return Err(DecodeError::Version { got: v });
+    }
+
+    let envelope: Envelope<T> = serde_json::from_slice(payload).map_err(invalid)?;
+    Ok(envelope.msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let msgs = vec![
+            ServerMessage::Chat {
+                id: 90061000000,
+                room: String::from("#general"),
+                from: String::from("alice"),
+                text: String::from("hi"),
+                time: 90061,
+                replayed: true,
+                edited: true,
+            },
+            ServerMessage::Edited {
+                room: String::from("#general"),
+                id: 90061000000,
+                from: String::from("alice"),
+                text: String::from("hello"),
+            },
+            ServerMessage::Leave {
+                room: String::from("#rust"),
+                nick: String::from("bob"),
+                reason: Some(String::from("timed out")),
+            },
+            ServerMessage::Ping,
+        ];
+
+        for msg in msgs {
+            let decoded: ServerMessage = decode_message(&encode_message(&msg)).unwrap();
+            assert_eq!(decoded, msg);
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let msg = ServerMessage::Chat {
+            id: 90061000000,
+            room: String::from("#general"),
+            from: String::from("alice"),
+            text: String::from("hi"),
+            time: 90061,
+            replayed: false,
+            edited: false,
+        };
+        assert_eq!(
+            String::from_utf8(encode_message(&msg)).unwrap(),
+            r##"{"v":1,"kind":"chat","id":90061000000,"room":"#general","from":"alice","text":"hi","time":90061}"##
+        );
+
+        let ping: ClientMessage = decode_message(br#"{"v":1,"kind":"ping"}"#).unwrap();
+        assert_eq!(ping, ClientMessage::Ping);
+
+        let error = ServerMessage::Error {
+            code: ErrorCode::TooLarge,
+            text: String::from("too big"),
+        };
+        assert_eq!(
+            String::from_utf8(encode_message(&error)).unwrap(),
+            r#"{"v":1,"kind":"error","code":"too_large","text":"too big"}"#
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_messages() {
+        let newer = decode_message::<ClientMessage>(br#"{"v":2,"kind":"ping"}"#);
+        assert!(matches!(newer, Err(DecodeError::Version { got: 2 })));
+
+        for payload in [
+            &br#"{"kind":"ping"}"#[..],
+            br#"{"v":1,"kind":"dance"}"#,
+            br#"{"v":1,"kind":"chat"}"#,
+            b"\xff not json",
+        ] {
+            let result = decode_message::<ClientMessage>(payload);
+            assert!(matches!(result, Err(DecodeError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn test_from_line() {
+        assert_eq!(
+            ClientMessage::from_line("/join  #rust "),
+            ClientMessage::Command {
+                name: String::from("join"),
+                args: String::from("#rust"),
+            }
+        );
+        assert_eq!(
+            ClientMessage::from_line("hello /nick"),
+            ClientMessage::Chat {
+                room: None,
+                text: String::from("hello /nick"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let mut chat = ServerMessage::Chat {
+            id: 90061000000,
+            room: String::from("#general"),
+            from: String::from("alice"),
+            text: String::from("hi"),
+            time: 90061,
+            replayed: false,
+            edited: false,
+        };
+        assert_eq!(chat.to_string(), "[#general] alice: hi");
+        if let ServerMessage::Chat {
+            replayed, edited, ..
+        } = &mut chat
+        {
+            *replayed = true;
+            *edited = true;
+        }
+        assert_eq!(chat.to_string(), "[#general] 01:01 alice: hi (edited)");
+
+        let error = ServerMessage::Error {
+            code: ErrorCode::Refused,
+            text: String::from("no such user bob"),
+        };
+        assert_eq!(error.to_string(), "! no such user bob");
+    }
+}
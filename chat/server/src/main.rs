@@ -1,65 +1,33 @@
-use std::io::{ErrorKind, Read, Write};
-use std::net::TcpListener;
-use std::sync::mpsc;
-use std::thread;
+use std::process;
 
-const LOCAL: &str = "127.0.0.1:6000";
-const MSG_SIZE: usize = 32;
+use chat_core::config::Config;
+use chat_core::{logger, Server};
+use tokio::signal::unix::{signal, SignalKind};
 
-fn sleep() {
-    thread::sleep(::std::time::Duration::from_millis(100));
-}
-
-fn main() {
-    let server = TcpListener::bind(LOCAL).expect("Listener failed to bind");
-    server
-        .set_nonblocking(true)
-        .expect("failed to initialize non-blocking");
-
-    let mut clients = vec![];
-    let (tx, rx) = mpsc::channel::<String>();
-
-    loop {
-        if let Ok((mut socket, addr)) = server.accept() {
-            println!("Client {} connected", addr);
-
-            let tx = tx.clone();
-            clients.push(socket.try_clone().expect("failed to clone client"));
+#[tokio::main]
+async fn main() {
+    let config = Config::load().unwrap_or_else(|err| {
+        eprintln!("invalid configuration: {}", err);
+        process::exit(1);
+    });
 
-            thread::spawn(move || loop {
-                let mut buff = vec![0; MSG_SIZE];
-
-                match socket.read_exact(&mut buff) {
-                    Ok(_) => {
-                        let msg = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
-                        let msg = String::from_utf8(msg).expect("Invalid utf8 message");
-
-                        println!("{}: {:?}", addr, msg);
-                        tx.send(msg).expect("failed to send message to rx");
-                    }
-                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-                    Err(_) => {
-                        println!("closing connection with: {}", addr);
-                        break;
-                    }
-                }
+    if let Some(path) = &config.log {
+        logger::init(path).expect("failed to open log file");
+    }
 
-                sleep();
-            });
-        }
+    let server = Server::bind(config).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
 
-        if let Ok(msg) = rx.try_recv() {
-            clients = clients
-                .into_iter()
-                .filter_map(|mut client| {
-                    let mut buff = msg.clone().into_bytes();
-                    buff.resize(MSG_SIZE, 0);
+    server.run(shutdown_signal()).await
+}
 
-                    client.write_all(&buff).map(|_| client).ok()
-                })
-                .collect::<Vec<_>>();
-        }
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
 
-        sleep();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
     }
 }